@@ -0,0 +1,192 @@
+//! Modules loaded from the EFI System Partition at boot, to be started once the kernel is up.
+//!
+//! `\sisyphos\modules.cfg` lists one module per line, as its path and an optional command line:
+//!
+//! ```text
+//! # Comment
+//! init.so --verbose
+//! \sisyphos\drivers\serial.so
+//! ```
+//!
+//! Relative paths are relative to `\sisyphos`. A module is named after the last component of its path.
+//!
+//! Files are read while boot services are still around, so the list lives in a static instead of on the heap.
+//! The configuration and the modules stay in `LOADER_DATA` pages, which the kernel never reuses.
+
+use core::fmt;
+use core::slice;
+use core::str;
+
+use spin;
+use x86_64::PhysicalAddress;
+
+use memory::paging;
+use uefi;
+
+const MODULE_DIRECTORY: &'static str = "\\sisyphos";
+const CONFIG_FILE: &'static str = "modules.cfg";
+
+pub const MAX_BOOT_MODULES: usize = 32;
+
+#[derive(Copy, Clone)]
+struct Entry {
+    // Offsets and lengths in the configuration text.
+    name: (usize, usize),
+    cmdline: (usize, usize),
+    // Physical address and size of the file.
+    data: u64,
+    size: usize,
+}
+
+const EMPTY_ENTRY: Entry = Entry { name: (0, 0), cmdline: (0, 0), data: 0, size: 0 };
+
+struct BootModules {
+    config: u64,
+    config_size: usize,
+    count: usize,
+    entries: [Entry; MAX_BOOT_MODULES],
+}
+
+static BOOT_MODULES: spin::Mutex<BootModules> =
+    spin::Mutex::new(BootModules { config: 0, config_size: 0, count: 0, entries: [EMPTY_ENTRY; MAX_BOOT_MODULES] });
+
+/// A module file loaded at boot.
+#[derive(Copy, Clone)]
+pub struct BootModule {
+    pub name: &'static str,
+    pub cmdline: &'static str,
+    pub data: &'static [u8],
+}
+
+/// Splits a configuration line into the module path and its command line.
+/// Returns `None` for blank lines and comments.
+fn parse_line(line: &str) -> Option<(&str, &str)> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    match line.find(char::is_whitespace) {
+        Some(end) => Some((&line[..end], line[end..].trim_left())),
+        // Empty, but still within the line, see `span()`.
+        None => Some((line, &line[line.len()..])),
+    }
+}
+
+fn module_name(path: &str) -> &str {
+    match path.rfind(|c| c == '\\' || c == '/') {
+        Some(separator) => &path[separator + 1..],
+        None => path,
+    }
+}
+
+// Offset and length of `part`, which is a slice of `text`.
+fn span(text: &str, part: &str) -> (usize, usize) {
+    (part.as_ptr() as usize - text.as_ptr() as usize, part.len())
+}
+
+/// Reads the configuration and every module it lists from the volume the kernel was loaded from.
+/// Has to run before boot services are terminated. Having no configuration means having no modules,
+/// modules that can't be read are reported to `out` and left out. Returns the number of modules loaded.
+pub unsafe fn load(system_table: *const uefi::SystemTable, image_handle: uefi::Handle, out: &mut fmt::Write) -> Result<usize, uefi::Status> {
+    let root = try!(uefi::open_boot_volume(system_table, image_handle));
+    let result = match uefi::open_file(root, MODULE_DIRECTORY) {
+        Ok(directory) => {
+            let result = load_from(system_table, directory, out);
+            uefi::close_file(directory);
+            result
+        },
+        Err(uefi::NOT_FOUND) => Ok(0),
+        Err(status) => Err(status),
+    };
+    uefi::close_file(root);
+    result
+}
+
+unsafe fn load_from(system_table: *const uefi::SystemTable, directory: *mut uefi::FileProtocol, out: &mut fmt::Write) -> Result<usize, uefi::Status> {
+    let (config, config_size) = match uefi::read_file(system_table, directory, CONFIG_FILE) {
+        Ok(file) => file,
+        Err(uefi::NOT_FOUND) => return Ok(0),
+        Err(status) => return Err(status),
+    };
+
+    // Boot services memory is identity mapped.
+    let text = match str::from_utf8(slice::from_raw_parts(config as usize as *const u8, config_size)) {
+        Ok(text) => text,
+        Err(_) => {
+            let _ = write!(out, "{}\\{} is not valid UTF-8.\n", MODULE_DIRECTORY, CONFIG_FILE);
+            return Err(uefi::LOAD_ERROR);
+        },
+    };
+
+    let mut modules = BOOT_MODULES.lock();
+    modules.config = config;
+    modules.config_size = config_size;
+    modules.count = 0;
+
+    for (path, cmdline) in text.lines().filter_map(parse_line) {
+        if modules.count == MAX_BOOT_MODULES {
+            let _ = write!(out, "More than {} boot modules, ignoring the rest.\n", MAX_BOOT_MODULES);
+            break;
+        }
+
+        match uefi::read_file(system_table, directory, path) {
+            Ok((data, size)) => {
+                let index = modules.count;
+                modules.entries[index] = Entry {
+                    name: span(text, module_name(path)),
+                    cmdline: span(text, cmdline),
+                    data: data,
+                    size: size,
+                };
+                modules.count += 1;
+            },
+            Err(status) => {
+                let _ = write!(out, "Failed to read boot module {} (status {:x}).\n", path, status);
+            },
+        }
+    }
+
+    Ok(modules.count)
+}
+
+pub fn count() -> usize {
+    BOOT_MODULES.lock().count
+}
+
+/// Boot module `index`, in the order of the configuration.
+pub fn get(index: usize) -> Option<BootModule> {
+    let modules = BOOT_MODULES.lock();
+    if index >= modules.count {
+        return None;
+    }
+
+    let entry = modules.entries[index];
+    unsafe {
+        let config = paging::phys_to_virt(PhysicalAddress(modules.config)) as *const u8;
+        let text = |(offset, len): (usize, usize)| {
+            assert!(offset + len <= modules.config_size);
+            str::from_utf8_unchecked(slice::from_raw_parts(config.offset(offset as isize), len))
+        };
+        let data = paging::phys_to_virt(PhysicalAddress(entry.data)) as *const u8;
+
+        Some(BootModule {
+            name: text(entry.name),
+            cmdline: text(entry.cmdline),
+            data: slice::from_raw_parts(data, entry.size),
+        })
+    }
+}
+
+#[test]
+fn test_boot_modules_config() {
+    assert_eq!(parse_line("  # comment"), None);
+    assert_eq!(parse_line("\t"), None);
+    assert_eq!(parse_line("init.so"), Some(("init.so", "")));
+    assert_eq!(parse_line(" drivers\\serial.so  --port 1 "), Some(("drivers\\serial.so", "--port 1")));
+
+    assert_eq!(module_name("\\sisyphos\\drivers\\serial.so"), "serial.so");
+    assert_eq!(module_name("init.so"), "init.so");
+
+    let text = "init.so arg";
+    assert_eq!(span(text, &text[8..]), (8, 3));
+}
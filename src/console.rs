@@ -0,0 +1,86 @@
+//! The kernel console. While boot services are around, that's the UEFI text console.
+//! The firmware's console doesn't survive `ExitBootServices()`, nor the switch to the kernel's page tables,
+//! so from then on output goes to the first serial port.
+
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use efi_app;
+use platform;
+
+const COM1: u16 = 0x3f8;
+
+// Register offsets from the base port. With DLAB set, the first two hold the divisor.
+const DATA: u16 = 0;
+const INTERRUPT_ENABLE: u16 = 1;
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+
+const LINE_CONTROL_DLAB: u8 = 0x80;
+const LINE_CONTROL_8N1: u8 = 0x03;
+// Enabled, both FIFOs cleared, interrupt at 14 bytes.
+const FIFO_ENABLE: u8 = 0xc7;
+const MODEM_DTR_RTS: u8 = 0x03;
+const LINE_STATUS_THR_EMPTY: u8 = 0x20;
+
+// 115200 baud.
+const DIVISOR: u16 = 1;
+
+static SERIAL_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+// Holds no state, so handing out several references to it is harmless.
+static mut SERIAL: SerialPort = SerialPort { base: COM1 };
+
+/// A 16550 UART, polled. Doesn't lock anything, so that it can be used from the panic and fault handlers.
+pub struct SerialPort {
+    base: u16,
+}
+
+impl SerialPort {
+    unsafe fn init(&self) {
+        platform::outb(self.base + INTERRUPT_ENABLE, 0);
+        platform::outb(self.base + LINE_CONTROL, LINE_CONTROL_DLAB);
+        platform::outb(self.base + DATA, DIVISOR as u8);
+        platform::outb(self.base + INTERRUPT_ENABLE, (DIVISOR >> 8) as u8);
+        platform::outb(self.base + LINE_CONTROL, LINE_CONTROL_8N1);
+        platform::outb(self.base + FIFO_CONTROL, FIFO_ENABLE);
+        platform::outb(self.base + MODEM_CONTROL, MODEM_DTR_RTS);
+    }
+
+    fn write_byte(&self, byte: u8) {
+        unsafe {
+            // Without a UART, the status reads as all ones, and this doesn't wait.
+            while platform::inb(self.base + LINE_STATUS) & LINE_STATUS_THR_EMPTY == 0 {}
+            platform::outb(self.base + DATA, byte);
+        }
+    }
+}
+
+impl fmt::Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            if byte == b'\n' {
+                self.write_byte(b'\r');
+            }
+            self.write_byte(byte);
+        }
+        Ok(())
+    }
+}
+
+/// Sends all further output to the serial port. To be called as soon as boot services are terminated.
+pub unsafe fn switch_to_serial() {
+    SERIAL.init();
+    SERIAL_ACTIVE.store(true, Ordering::SeqCst);
+}
+
+/// The console that currently works.
+pub unsafe fn out() -> &'static mut fmt::Write {
+    if SERIAL_ACTIVE.load(Ordering::SeqCst) {
+        &mut SERIAL
+    } else {
+        efi_app::__fixme_temporary_out()
+    }
+}
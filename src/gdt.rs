@@ -0,0 +1,125 @@
+//! The kernel's own GDT and TSS. The ones UEFI set up live in boot services memory, which we reuse.
+
+use core::mem::size_of;
+use spin;
+
+use x86_64::{PrivilegeLevel, VirtualAddress};
+use x86_64::structures::gdt::SegmentSelector;
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::instructions::segmentation::set_cs;
+use x86_64::instructions::tables::{lgdt, load_tss, DescriptorTablePointer};
+
+/// Interrupt stack table slot of the double fault handler's stack.
+pub const DOUBLE_FAULT_IST_INDEX: usize = 0;
+
+const GDT_ENTRIES: usize = 8;
+
+bitflags! {
+    struct DescriptorFlags: u64 {
+        const WRITABLE =     1 << 41;
+        const EXECUTABLE =   1 << 43;
+        const USER_SEGMENT = 1 << 44;
+        const PRESENT =      1 << 47;
+        const LONG_MODE =    1 << 53;
+    }
+}
+
+enum Descriptor {
+    UserSegment(u64),
+    SystemSegment(u64, u64),
+}
+
+impl Descriptor {
+    fn kernel_code_segment() -> Descriptor {
+        Descriptor::UserSegment((USER_SEGMENT | PRESENT | EXECUTABLE | LONG_MODE).bits())
+    }
+
+    fn kernel_data_segment() -> Descriptor {
+        Descriptor::UserSegment((USER_SEGMENT | PRESENT | WRITABLE).bits())
+    }
+
+    fn tss_segment(tss: &'static TaskStateSegment) -> Descriptor {
+        let base = tss as *const TaskStateSegment as u64;
+        let limit = (size_of::<TaskStateSegment>() - 1) as u64;
+
+        // Available 64-bit TSS.
+        let low = PRESENT.bits() | (0b1001 << 40) | limit
+                | ((base & 0xff_ffff) << 16) | (((base >> 24) & 0xff) << 56);
+        Descriptor::SystemSegment(low, base >> 32)
+    }
+}
+
+struct Gdt {
+    table: [u64; GDT_ENTRIES],
+    next_free: usize,
+}
+
+impl Gdt {
+    fn new() -> Gdt {
+        // Entry 0 is the mandatory null descriptor.
+        Gdt { table: [0; GDT_ENTRIES], next_free: 1 }
+    }
+
+    fn add_entry(&mut self, entry: Descriptor) -> SegmentSelector {
+        let index = match entry {
+            Descriptor::UserSegment(value) => self.push(value),
+            Descriptor::SystemSegment(low, high) => {
+                let index = self.push(low);
+                self.push(high);
+                index
+            }
+        };
+        SegmentSelector::new(index as u16, PrivilegeLevel::Ring0)
+    }
+
+    fn push(&mut self, value: u64) -> usize {
+        assert!(self.next_free < GDT_ENTRIES, "GDT full.");
+        let index = self.next_free;
+        self.table[index] = value;
+        self.next_free += 1;
+        index
+    }
+
+    unsafe fn load(&'static self) {
+        let pointer = DescriptorTablePointer {
+            base: self.table.as_ptr() as u64,
+            limit: (self.table.len() * size_of::<u64>() - 1) as u16,
+        };
+        lgdt(&pointer);
+    }
+}
+
+struct Selectors {
+    code: SegmentSelector,
+    data: SegmentSelector,
+    tss: SegmentSelector,
+}
+
+static TSS: spin::Once<TaskStateSegment> = spin::Once::new();
+static GDT: spin::Once<(Gdt, Selectors)> = spin::Once::new();
+
+/// Loads the GDT and the TSS of the boot CPU, with `double_fault_stack` as the top of the double fault stack.
+pub unsafe fn init(double_fault_stack: usize) {
+    let tss = TSS.call_once(|| {
+        let mut tss = TaskStateSegment::new();
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX] = VirtualAddress(double_fault_stack);
+        tss
+    });
+
+    let &(ref gdt, ref selectors) = GDT.call_once(|| {
+        let mut gdt = Gdt::new();
+        let code = gdt.add_entry(Descriptor::kernel_code_segment());
+        let data = gdt.add_entry(Descriptor::kernel_data_segment());
+        let tss = gdt.add_entry(Descriptor::tss_segment(tss));
+        (gdt, Selectors { code: code, data: data, tss: tss })
+    });
+
+    gdt.load();
+    set_cs(selectors.code);
+    // The selectors UEFI left in the data segment registers are meaningless in our GDT.
+    asm!("mov $0, %ds
+          mov $0, %es
+          mov $0, %ss"
+         :: "r"(selectors.data.0) :: "volatile");
+    load_tss(selectors.tss);
+}
@@ -0,0 +1,58 @@
+use spin;
+
+use x86_64::VirtualAddress;
+use x86_64::registers::control_regs;
+use x86_64::structures::idt::{Idt, ExceptionStackFrame, PageFaultErrorCode};
+
+use console;
+use gdt;
+use memory::kernel_stack::{self, KernelStack};
+
+const DOUBLE_FAULT_STACK_PAGES: usize = 4;
+
+static DOUBLE_FAULT_STACK: spin::Once<KernelStack> = spin::Once::new();
+static IDT: spin::Once<Idt> = spin::Once::new();
+
+/// Sets up exception handling on the boot CPU. The heap must be usable.
+pub unsafe fn init() {
+    let stack = DOUBLE_FAULT_STACK.call_once(|| {
+        KernelStack::new(DOUBLE_FAULT_STACK_PAGES, "double fault handler").expect("Out of memory for the double fault stack.")
+    });
+    gdt::init(stack.top());
+
+    let idt = IDT.call_once(|| {
+        let mut idt = Idt::new();
+        idt.page_fault.set_handler_fn(page_fault_handler);
+        idt.double_fault.set_handler_fn(double_fault_handler).set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX as u16);
+        idt
+    });
+    idt.load();
+}
+
+fn check_stack_overflow(address: VirtualAddress) {
+    if let Some(owner) = kernel_stack::guard_owner(address) {
+        // Not a panic: the panic handler walks the heap and the page tables, which is a lot to ask
+        // of the double fault stack, and a fault in there would end in a triple fault without any output.
+        let out = unsafe { console::out() };
+        let _ = write!(out, "Kernel stack overflow in {} (access to {:#x}).\n", owner, address.0);
+        loop {
+            unsafe { asm!("cli; hlt" :::: "volatile"); }
+        }
+    }
+}
+
+extern "x86-interrupt" fn page_fault_handler(stack_frame: &mut ExceptionStackFrame, error_code: PageFaultErrorCode) {
+    // Only reachable for overflows that skip past the stack pointer, e.g. with a large stack frame.
+    let address = control_regs::cr2();
+    check_stack_overflow(address);
+
+    panic!("Page fault at {:#x} ({:?}).\n{:#?}", address.0, error_code, stack_frame);
+}
+
+extern "x86-interrupt" fn double_fault_handler(stack_frame: &mut ExceptionStackFrame, _error_code: u64) {
+    // A page fault on the stack can't push its own frame, and escalates to here.
+    // We run on a separate stack, and CR2 still holds the address of the original fault.
+    check_stack_overflow(control_regs::cr2());
+
+    panic!("Double fault.\n{:#?}", stack_frame);
+}
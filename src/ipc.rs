@@ -0,0 +1,115 @@
+//! Message channels. Each channel is a queue of messages, copied in on send and out on receive.
+//! Nothing blocks: there is no scheduler to wait with yet.
+
+use spin;
+
+use alloc::btree_map::BTreeMap;
+use alloc::vec::Vec;
+use alloc::vec_deque::VecDeque;
+
+pub type ChannelId = u64;
+
+// Messages queued on one channel at most, so that a sender nobody listens to can't eat the heap.
+const MAX_QUEUED: usize = 256;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum IpcError {
+    NoSuchChannel,
+    /// Nothing to receive.
+    Empty,
+    /// The channel has `MAX_QUEUED` messages waiting.
+    Full,
+    /// The next message needs a buffer of this size. It stays queued.
+    BufferTooSmall(usize),
+}
+
+struct Channels {
+    next_id: ChannelId,
+    queues: BTreeMap<ChannelId, VecDeque<Vec<u8>>>,
+}
+
+static CHANNELS: spin::Mutex<Option<Channels>> = spin::Mutex::new(None);
+
+// The registry lives on the heap, so it's only created on first use.
+fn with_channels<T, F>(func: F) -> T
+    where F: FnOnce(&mut Channels) -> T
+{
+    let mut channels = CHANNELS.lock();
+    if channels.is_none() {
+        *channels = Some(Channels { next_id: 1, queues: BTreeMap::new() });
+    }
+
+    match *channels {
+        Some(ref mut channels) => func(channels),
+        None => unreachable!(),
+    }
+}
+
+pub fn create() -> ChannelId {
+    with_channels(|channels| {
+        let id = channels.next_id;
+        channels.next_id += 1;
+        channels.queues.insert(id, VecDeque::new());
+        id
+    })
+}
+
+/// Destroys the channel, along with any messages still queued on it.
+pub fn destroy(id: ChannelId) -> Result<(), IpcError> {
+    with_channels(|channels| match channels.queues.remove(&id) {
+        Some(_) => Ok(()),
+        None => Err(IpcError::NoSuchChannel),
+    })
+}
+
+pub fn send(id: ChannelId, message: &[u8]) -> Result<(), IpcError> {
+    let mut copy = Vec::with_capacity(message.len());
+    copy.extend_from_slice(message);
+
+    with_channels(|channels| {
+        let queue = match channels.queues.get_mut(&id) {
+            Some(queue) => queue,
+            None => return Err(IpcError::NoSuchChannel),
+        };
+        if queue.len() >= MAX_QUEUED {
+            return Err(IpcError::Full);
+        }
+        queue.push_back(copy);
+        Ok(())
+    })
+}
+
+/// Takes the oldest message off the channel, copies it into `buffer`, and returns its size.
+pub fn receive(id: ChannelId, buffer: &mut [u8]) -> Result<usize, IpcError> {
+    let message = try!(with_channels(|channels| {
+        let queue = match channels.queues.get_mut(&id) {
+            Some(queue) => queue,
+            None => return Err(IpcError::NoSuchChannel),
+        };
+        match queue.front().map(|message| message.len()) {
+            None => return Err(IpcError::Empty),
+            Some(size) if size > buffer.len() => return Err(IpcError::BufferTooSmall(size)),
+            Some(_) => {},
+        }
+        Ok(queue.pop_front().unwrap())
+    }));
+
+    buffer[..message.len()].copy_from_slice(&message);
+    Ok(message.len())
+}
+
+#[test]
+fn test_ipc_channels() {
+    let channel = create();
+    let mut buffer = [0; 8];
+    assert_eq!(receive(channel, &mut buffer), Err(IpcError::Empty));
+
+    send(channel, b"hello").unwrap();
+    send(channel, b"longer message").unwrap();
+    assert_eq!(receive(channel, &mut buffer), Ok(5));
+    assert_eq!(&buffer[..5], b"hello");
+    assert_eq!(receive(channel, &mut buffer), Err(IpcError::BufferTooSmall(14)));
+
+    destroy(channel).unwrap();
+    assert_eq!(send(channel, b"gone"), Err(IpcError::NoSuchChannel));
+}
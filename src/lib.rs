@@ -10,6 +10,9 @@
 #![feature(repr_align)]
 #![feature(attr_literals)]
 #![feature(const_fn)]
+#![feature(asm)]
+#![feature(abi_x86_interrupt)]
+#![cfg_attr(test, feature(test))]
 
 // FIXME: remove
 #![allow(unreachable_code)]
@@ -26,54 +29,94 @@ extern crate spin;
 #[macro_use]
 extern crate bitflags;
 
+#[cfg(test)]
+extern crate test;
+#[cfg(test)]
+#[macro_use]
+extern crate std;
+
 mod relocate;
+mod uefi;
+mod console;
+mod boot_modules;
 mod memory;
+mod platform;
+mod percpu;
+mod gdt;
+mod interrupts;
+mod ipc;
+mod module;
 pub mod panic;
 pub mod rt_stubs;
 
-use alloc::boxed::Box;
+use core::mem;
 
 
-#[global_allocator]
+// Host tests run on std, with its allocator and panic handler.
+#[cfg_attr(not(test), global_allocator)]
 static ALLOCATOR: memory::heap::HeapAllocator = memory::heap::HeapAllocator::new();
 
 #[no_mangle]
-pub extern "C" fn efi_main(ldbase: u64, dyn: *const u8, arg1: efi_app::Arg1, arg2: efi_app::Arg2) -> efi_app::Status
+pub extern "C" fn efi_main(ldbase: u64, dyn: *const u8, image_handle: uefi::Handle, system_table: *const uefi::SystemTable) -> efi_app::Status
 {
     // First, relocate to identity-mapped region, so asserts etc work.
-    unsafe { relocate::relocate(ldbase, dyn); }
+    // Failures can only be reported once the console is up.
+    let image_size = memory::kernel_space::image_range().1 as u64;
+    let relocated = unsafe { relocate::relocate(ldbase, image_size, dyn) };
+
+    uefi::set_boot_system_table(system_table);
+
+    // The kernel's page tables need frames before we own the memory.
+    if let Err(status) = unsafe { memory::physical::init_early(system_table) } {
+        panic!("Failed to reserve early boot memory (status {:x}).", status);
+    }
 
     // FIXME: this implicitly initializes globals in efi_app, which is weird.
-    let mut ctx = unsafe { efi_app::BootContext::new(arg1, arg2) };
-
-    // Copy the flat memory mapping into the upper half.
-    // This is done simply by taking the top-level page table from UEFI
-    // and copying existing pointers from bottom half to top half.
-    //
-    // The upper half is verified to be empty, in order to avoid unknowingly
-    // clobbering something important. If the upper half is mapped by UEFI,
-    // we just print an error message and make it halt and catch fire. Such a
-    // thing happening should be highly unlikely.
-    unsafe { memory::paging::L4Table::initialize_high_mapping(); }
-    
-    // TODO: This change to mapping needs to be reverted if we return without calling ExitBootServices().
-    // The remapping we do is currently infringing on UEFI's turf (we don't own the memory map yet,
-    // so we shouldn't touch it). It should be fine if we end up calling `ExitBootServices()`, since UEFI itself
-    // should only have identity mapping, so the top half should be empty. If we don't ExitBootServices(), though,
-    // we have to return things into original state.
-    
-    let b = Box::new(5);
-    // TODO: Relocate everything into high memory, including current RIP and RSP.
+    let mut ctx = unsafe { efi_app::BootContext::new(mem::transmute::<_, efi_app::Arg1>(image_handle),
+                                                     mem::transmute::<_, efi_app::Arg2>(system_table)) };
 
     let o = ctx.console_out();
     o.clear_screen();
 
-    //o.write_fmt(format_args!("{:?}", unsafe { memory::paging::L4Table::current() }));
-
     o.output_string("Hello, EFI world!\n");
 
-    loop{}
+    let out = unsafe { console::out() };
+    if let Err(err) = relocated {
+        let _ = write!(out, "Relocation failed: {}.\n", err);
+    }
+
+    // Files can only be read as long as we have boot services.
+    match unsafe { boot_modules::load(system_table, image_handle, out) } {
+        Ok(0) => {},
+        Ok(count) => { let _ = write!(out, "Loaded {} boot modules.\n", count); },
+        Err(status) => { let _ = write!(out, "Failed to load boot modules (status {:x}).\n", status); },
+    }
+
+    // From here on, the memory belongs to us, the UEFI console is gone and output goes to the serial port,
+    // and we run on our own page tables. The heap is only usable after this.
+    if let Err(status) = unsafe { memory::physical::take_over_memory(system_table, image_handle) } {
+        panic!("Failed to take over memory from UEFI (status {:x}).", status);
+    }
+
+    // Continue with the image, RIP and RSP in the upper half.
+    unsafe { memory::kernel_space::enter_higher_half(ldbase, dyn, kernel_main) }
     //return efi_app::Status::success();
 }
 
+/// Continuation of `efi_main()`, running in the upper half on the kernel's own stack.
+extern "C" fn kernel_main() -> ! {
+    unsafe {
+        memory::kernel_space::drop_identity_mapping();
+        interrupts::init();
+        // Only now nothing uses UEFI's stack, GDT and IDT anymore.
+        memory::physical::release_boot_services_memory();
+    }
+
+    unsafe { percpu::init_boot_cpu(); }
+
+    module::start_boot_modules();
+
+    loop{}
+}
+
 
@@ -0,0 +1,220 @@
+//! Address spaces with a private lower half and the kernel's upper half.
+
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin;
+
+use alloc::arc::Arc;
+use alloc::vec::Vec;
+
+use x86_64::{PhysicalAddress, VirtualAddress};
+use x86_64::registers::control_regs;
+
+use memory::frame::FrameAllocator;
+use memory::kernel_space;
+use memory::paging::{phys_to_virt, L4Table, Mapper, MapError, Page, EntryFlags, ENTRY_COUNT, PAGE_SIZE};
+use memory::physical::SystemFrames;
+use platform;
+
+// End of the lower half, the part of an address space that's private to it.
+const USER_END: usize = 0x0000_8000_0000_0000;
+
+// Set in CR3 to keep the TLB entries of the PCID being switched to.
+const CR3_NO_FLUSH: u64 = 1 << 63;
+
+const PCID_COUNT: usize = 4096;
+
+// One bit per PCID. PCID 0 belongs to the kernel's own tables, and to spaces that didn't get one.
+static PCIDS: spin::Mutex<[u64; PCID_COUNT / 64]> = spin::Mutex::new([0; PCID_COUNT / 64]);
+
+fn alloc_pcid() -> u16 {
+    if !platform::pcid_enabled() {
+        return 0;
+    }
+
+    let mut pcids = PCIDS.lock();
+    for (i, word) in pcids.iter_mut().enumerate() {
+        let used = if i == 0 { *word | 1 } else { *word };
+        if used != !0 {
+            let bit = (!used).trailing_zeros() as usize;
+            *word |= 1 << bit;
+            return (i * 64 + bit) as u16;
+        }
+    }
+    0
+}
+
+fn free_pcid(pcid: u16) {
+    if pcid != 0 {
+        PCIDS.lock()[pcid as usize / 64] &= !(1 << (pcid % 64));
+    }
+}
+
+/// Frames that can be mapped into several address spaces at once.
+/// They are freed when the last space mapping them goes away.
+pub struct SharedRegion {
+    frames: Vec<PhysicalAddress>,
+}
+
+impl SharedRegion {
+    /// Allocates `pages` fresh, zeroed frames, so that nothing of their previous use leaks to whoever maps them.
+    pub fn new(pages: usize) -> Result<Arc<SharedRegion>, MapError> {
+        let mut region = SharedRegion { frames: Vec::with_capacity(pages) };
+        for _ in 0..pages {
+            match SystemFrames.alloc_frames(0) {
+                Some(frame) => {
+                    unsafe { ptr::write_bytes(phys_to_virt(frame) as *mut u8, 0, PAGE_SIZE); }
+                    region.frames.push(frame);
+                },
+                // Dropping the region frees what was allocated so far.
+                None => return Err(MapError::OutOfFrames),
+            }
+        }
+        Ok(Arc::new(region))
+    }
+
+    #[inline]
+    pub fn pages(&self) -> usize {
+        self.frames.len()
+    }
+}
+
+impl Drop for SharedRegion {
+    fn drop(&mut self) {
+        for &frame in self.frames.iter() {
+            SystemFrames.free_frames(frame, 0);
+        }
+    }
+}
+
+/// An L4 table of its own, whose lower half is private, and whose upper half entries
+/// point to the same L3 tables as the kernel's master table. The kernel creates every upper half
+/// L3 table at boot, so kernel mappings show up in all address spaces without any synchronization.
+///
+/// Dropping the space frees the tables of the lower half, but not the frames mapped by them,
+/// except for shared regions nobody else maps anymore.
+pub struct AddressSpace {
+    l4: PhysicalAddress,
+    pcid: u16,
+    // The TLB may hold stale entries tagged with our PCID.
+    needs_flush: AtomicBool,
+    shared: Vec<(VirtualAddress, Arc<SharedRegion>)>,
+}
+
+impl AddressSpace {
+    pub fn new() -> Result<AddressSpace, MapError> {
+        let (frame, l4) = match L4Table::create(&mut SystemFrames) {
+            Some(table) => table,
+            None => return Err(MapError::OutOfFrames),
+        };
+
+        let kernel = unsafe { L4Table::from_frame(kernel_space::kernel_l4()) };
+        for i in ENTRY_COUNT / 2..ENTRY_COUNT {
+            l4[i] = kernel[i];
+        }
+
+        // A recycled PCID may still have entries of its previous owner in the TLB.
+        Ok(AddressSpace { l4: frame, pcid: alloc_pcid(), needs_flush: AtomicBool::new(true), shared: Vec::new() })
+    }
+
+    #[inline]
+    pub fn l4_frame(&self) -> PhysicalAddress {
+        self.l4
+    }
+
+    /// Mapper for the lower half. Only the lower half may be changed through it,
+    /// the upper half belongs to the kernel.
+    pub unsafe fn mapper(&mut self) -> Mapper {
+        // Changes made while another space is active can't invalidate our TLB entries.
+        if !self.is_active() {
+            self.needs_flush.store(true, Ordering::Relaxed);
+        }
+        Mapper::new(L4Table::from_frame(self.l4))
+    }
+
+    pub fn is_active(&self) -> bool {
+        control_regs::cr3().0 & !0xfff == self.l4.0
+    }
+
+    /// Switches CR3 to this address space. With PCIDs, TLB entries of the space survive
+    /// switching away and back, unless the space was changed meanwhile.
+    pub unsafe fn activate(&self) {
+        let mut cr3 = self.l4.0 | self.pcid as u64;
+        if self.pcid != 0 && !self.needs_flush.swap(false, Ordering::Relaxed) {
+            cr3 |= CR3_NO_FLUSH;
+        }
+        control_regs::cr3_write(PhysicalAddress(cr3));
+    }
+
+    /// Maps all of `region` at `address`, in the lower half.
+    pub unsafe fn map_shared(&mut self, address: VirtualAddress, region: &Arc<SharedRegion>, flags: EntryFlags) -> Result<(), MapError> {
+        assert!(address.0 % PAGE_SIZE == 0 && address.0 + region.pages() * PAGE_SIZE <= USER_END);
+
+        {
+            let mut mapper = self.mapper();
+            for (i, &frame) in region.frames.iter().enumerate() {
+                let page = Page::containing_address(VirtualAddress(address.0 + i * PAGE_SIZE));
+                if let Err(err) = mapper.map_to(page, frame, flags, &mut SystemFrames) {
+                    for j in 0..i {
+                        mapper.unmap(Page::containing_address(VirtualAddress(address.0 + j * PAGE_SIZE)));
+                    }
+                    return Err(err);
+                }
+            }
+        }
+
+        self.shared.push((address, region.clone()));
+        Ok(())
+    }
+
+    /// Unmaps a shared region mapped at `address`, and drops this space's reference to it.
+    pub unsafe fn unmap_shared(&mut self, address: VirtualAddress) {
+        let index = match self.shared.iter().position(|&(start, _)| start.0 == address.0) {
+            Some(index) => index,
+            None => panic!("No shared region is mapped at {:#x}.", address.0),
+        };
+
+        let (_, region) = self.shared.swap_remove(index);
+        let mut mapper = self.mapper();
+        for i in 0..region.pages() {
+            mapper.unmap(Page::containing_address(VirtualAddress(address.0 + i * PAGE_SIZE)));
+        }
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        assert!(!self.is_active(), "Destroying the active address space.");
+
+        unsafe {
+            let l4 = L4Table::from_frame(self.l4);
+            for i4 in 0..ENTRY_COUNT / 2 {
+                free_tables(l4, i4);
+            }
+        }
+
+        SystemFrames.free_frames(self.l4, 0);
+        free_pcid(self.pcid);
+        // The shared regions are released together with the vector.
+    }
+}
+
+// Frees the table behind the entry of `l4`, and all tables below it. Huge pages are not tables.
+unsafe fn free_tables(l4: &L4Table, i4: usize) {
+    let l3 = match l4.next_table(i4) {
+        Some(l3) => l3,
+        None => return,
+    };
+
+    for i3 in 0..ENTRY_COUNT {
+        if let Some(l2) = l3.next_table(i3) {
+            for i2 in 0..ENTRY_COUNT {
+                if l2.next_table(i2).is_some() {
+                    SystemFrames.free_frames(l2[i2].frame_address().unwrap(), 0);
+                }
+            }
+            SystemFrames.free_frames(l3[i3].frame_address().unwrap(), 0);
+        }
+    }
+    SystemFrames.free_frames(l4[i4].frame_address().unwrap(), 0);
+}
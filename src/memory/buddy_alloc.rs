@@ -1,7 +1,25 @@
-
-// TODO
-#![cfg(false)]
-
+use core::cmp;
+
+use x86_64::PhysicalAddress;
+
+use memory::frame::FrameAllocator;
+
+/// Binary buddy allocator for physical frames.
+///
+/// Addresses and sizes are expressed in allocation units (frames), not bytes.
+/// The lowest `BITMAP_RANK` levels are represented by a plain bitmap with one bit per frame,
+/// grouped into 512-frame segments of eight words. Everything above that is a binary tree
+/// stored in `tree_buffer` as an implicit heap (node `i` has children `2i` and `2i+1`).
+///
+/// Every tree node holds a summary: a bitmask of the ranks that can still be allocated
+/// inside the node's subtree. A node that is completely free holds exactly `1 << rank`,
+/// a completely allocated node holds 0 (and its children may hold garbage).
+///
+/// To keep the common case cheap, the allocator remembers the last visited node (the pivot).
+/// Nodes on the path from the root to the pivot don't hold their own summary, but instead
+/// a summary of everything outside the pivot's subtree, so that the availability
+/// of every rank in the whole memory is always just `tree[pivot] | tree[pivot/2]`.
+/// Index 0 is the imaginary parent of the root and is always 0.
 #[derive(Debug)]
 pub struct BuddyAllocator<'a> {
     // Only useful for debug info.
@@ -16,523 +34,836 @@ pub struct BuddyAllocator<'a> {
     tree_buffer: &'a mut [u64],
 }
 
+const BITMAP_RANK: usize = 9;
+const SEGMENT_WORDS: usize = 8;
+const SEGMENT_FRAMES: usize = 1 << BITMAP_RANK;
+
+#[inline]
+fn align_up(val: usize, align: usize) -> usize {
+    debug_assert!(align.is_power_of_two());
+    (val + align - 1) & !(align - 1)
+}
+
+#[inline]
+fn log2i(val: usize) -> usize {
+    debug_assert!(val != 0);
+    63 - val.leading_zeros() as usize
+}
+
+#[inline]
+fn align_to_pow2(val: usize) -> usize {
+    val.next_power_of_two()
+}
+
+#[inline]
+fn find_first_set(val: u64) -> usize {
+    debug_assert!(val != 0);
+    val.trailing_zeros() as usize
+}
+
+#[inline]
+fn alignment_rank(offset: usize) -> usize {
+    if offset == 0 { 63 } else { offset.trailing_zeros() as usize }
+}
+
+fn root_rank(address_limit: usize) -> usize {
+    cmp::max(log2i(align_to_pow2(cmp::max(address_limit, 1))), BITMAP_RANK)
+}
+
 impl<'a> BuddyAllocator<'a> {
-    const bitmap_rank = 9;
+    /// Number of `u64` words `bitmap_buffer` must have for the given limit (in frames).
+    pub fn bitmap_size(address_limit: usize) -> usize {
+        // One cache line of bitmap per segment.
+        align_up(address_limit, SEGMENT_FRAMES) / 64
+    }
 
-	const fn bitmap_size(address_limit: usize) -> usize {
-		const CACHE_LINE_SIZE: usize = 64;
-		return align_up(address_limit, CACHE_LINE_SIZE * 8) / 8;
-	}
+    /// Number of `u64` words `tree_buffer` must have for the given limit (in frames).
+    pub fn tree_size(address_limit: usize) -> usize {
+        let bitmap_segments = (1 << root_rank(address_limit)) / SEGMENT_FRAMES;
+        bitmap_segments * 2
+    }
 
-	const fn tree_size(address_limit: usize) -> usize {
-		let address_limit = align_to_pow2(address_limit);
-		let bitmap_segments = address_limit / 512;
-		return bitmap_segments * mem::size_of<u64>() * 2;
-	}
+    /// Creates an allocator managing frames `0..address_limit`.
+    /// Initially, everything is allocated. Use `dealloc()` to hand usable memory over.
+    pub fn new(address_limit: usize, bitmap_buffer: &'a mut [u64], tree_buffer: &'a mut [u64], alloc_unit: usize) -> Self {
+        assert!(bitmap_buffer.len() >= Self::bitmap_size(address_limit));
+        assert!(tree_buffer.len() >= Self::tree_size(address_limit));
 
-	pub fn new(address_limit: usize, bitmap_buffer: &'a mut [u64], tree_buffer: &'a mut [u64], alloc_unit: usize) -> Self {
-	    let rrank = log2i(align_to_pow2(address_limit));
+        let rrank = root_rank(address_limit);
         tree_buffer[0] = 0;
         tree_buffer[1] = 0;
 
-	    BuddyAllocator{
-	        alloc_unit: alloc_unit,
-	        limit: address_limit,
-	        pivot: 1,
-	        pivot_rank: rrank,
-	        root_rank: rrank,
-	        bitmap_buffer: bitmap_buffer,
-	        tree_buffer: tree_buffer,
-	    }
-	}
+        BuddyAllocator {
+            alloc_unit: alloc_unit,
+            limit: address_limit,
+            pivot: 1,
+            pivot_rank: rrank,
+            root_rank: rrank,
+            bitmap_buffer: bitmap_buffer,
+            tree_buffer: tree_buffer,
+        }
+    }
 
-	pub fn alloc(&mut self, size: usize) -> usize {
-	    if (is_power_of_two(size)) {
-		    return self.alloc_frame(log2i(size));
-	    }
+    #[inline]
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
 
-	    let rank = log2i(size)+1;
-	    let frame = self.alloc_frame(rank);
-	    // debug("Buddy allocator %x -- Allocated region %x of size %d, region size %d.", this, frame * _alloc_unit, size * _alloc_unit, (1<<rank) * _alloc_unit);
-	    self.dealloc(frame + size, (1<<rank)-size);
-	    frame
+    #[inline]
+    pub fn alloc_unit(&self) -> usize {
+        self.alloc_unit
     }
 
-    pub fn dealloc(offset: usize, size: usize) {
-	    // debug("Buddy allocator %x -- Releasing region, offset %x, size %d.", this, offset * _alloc_unit, size * _alloc_unit);
+    /// Allocates `size` contiguous frames, aligned to the next power of two.
+    /// The unused tail of the power-of-two block is returned to the allocator immediately.
+    pub fn alloc(&mut self, size: usize) -> Option<usize> {
+        assert!(size > 0);
 
-	    while (size > 0) {
-		    int rank = min(alignment_rank(offset), log2i(size));
-		    free_frame(rank, offset);
+        if size.is_power_of_two() {
+            return self.alloc_frame(log2i(size));
+        }
 
-		    offset += (1 << rank);
-		    size -= (1 << rank);
-	    }
+        let rank = log2i(size) + 1;
+        let frame = self.alloc_frame(rank);
+        if let Some(frame) = frame {
+            self.dealloc(frame + size, (1 << rank) - size);
+        }
+        frame
     }
 
-    // FIXME: Move somewhere else
-    fn set_all(s: &mut [u64], val: u64) {
-        // TODO: platform-optimized versions?
-        for &mut x in s {
-            *x = val;
+    /// Releases an arbitrary range of frames. The range doesn't need to match
+    /// a previous allocation, it is split into maximal aligned blocks.
+    pub fn dealloc(&mut self, offset: usize, size: usize) {
+        assert!(offset + size <= self.limit);
+
+        let mut offset = offset;
+        let mut size = size;
+
+        while size > 0 {
+            let rank = cmp::min(alignment_rank(offset), log2i(size));
+            self.free_frame(rank, offset);
+
+            offset += 1 << rank;
+            size -= 1 << rank;
         }
     }
 
-    fn alloc_rank_0(bitmap: &mut [u64; 8]) -> usize {
-       	for i in 0..8 {
-		    if (bitmap[i] == 0) {
-			    continue;
-		    }
-
-		    let offset = find_first_set(bitmap[i]);
-		    bitmap[i] ^= (1 << offset);
-		    return i*64 + offset;
-	    }
+    /// Returns a bitmask of ranks that can currently be allocated.
+    #[inline]
+    pub fn available_ranks(&self) -> u64 {
+        self.tree_buffer[self.pivot] | self.tree_buffer[self.pivot / 2]
+    }
 
-	    unreachable!();
+    #[inline]
+    fn pivot_address(&self) -> usize {
+        let depth = self.root_rank - self.pivot_rank;
+        (self.pivot - (1 << depth)) << self.pivot_rank
     }
 
-    fn alloc_rank_1(bitmap: &mut [u64; 8]) -> usize {
-	    for i in 0..8 {
-		    let free_rank_1 = (bitmap[i] & (bitmap[i] >> 1) & 0x5555555555555555_u64);
+    #[inline]
+    fn bitmap(&mut self, address: usize) -> &mut [u64] {
+        let start = (address >> BITMAP_RANK) * SEGMENT_WORDS;
+        &mut self.bitmap_buffer[start..start + SEGMENT_WORDS]
+    }
 
-		    if (free_rank_1 == 0) {
-			    continue;
-		    }
+    fn alloc_rank_0(bitmap: &mut [u64]) -> usize {
+        for i in 0..8 {
+            if bitmap[i] == 0 {
+                continue;
+            }
 
-		    let offset = find_first_set(free_rank_1);
-		    bitmap[i] ^= (0x3 << offset);
-		    debug_assert!((bitmap[i] & (0x3 << offset)) == 0);
-		    return i*64 + offset;
-	    }
+            let offset = find_first_set(bitmap[i]);
+            bitmap[i] ^= 1 << offset;
+            return i*64 + offset;
+        }
 
-	    unreachable!();
+        unreachable!();
     }
 
-    fn alloc_rank_2(bitmap: &mut [u64; 8]) -> usize {
-	    for i in 0..8 {
-		    let free_rank_1 = (bitmap[i] & (bitmap[i] >> 1) & 0x5555555555555555_u64);
-		    let free_rank_2 = (free_rank_1 & (free_rank_1 >> 2) & 0x1111111111111111_u64);
+    fn alloc_rank_1(bitmap: &mut [u64]) -> usize {
+        for i in 0..8 {
+            let free_rank_1 = bitmap[i] & (bitmap[i] >> 1) & 0x5555555555555555_u64;
 
-		    if (free_rank_2 == 0) {
-			    continue;
-		    }
+            if free_rank_1 == 0 {
+                continue;
+            }
 
-		    let offset = find_first_set(free_rank_2);
-		    bitmap[i] ^= (0xf << offset);
-		    debug_assert!((bitmap[i] & (0xf << offset)) == 0);
-		    return i*64 + offset;
-	    }
+            let offset = find_first_set(free_rank_1);
+            bitmap[i] ^= 0x3 << offset;
+            debug_assert!((bitmap[i] & (0x3 << offset)) == 0);
+            return i*64 + offset;
+        }
 
-	    unreachable!();
+        unreachable!();
     }
 
-    fn alloc_rank_3(bitmap: &mut [u64; 8]) -> usize {
-	    for i in 0..8 {
-	        let free_rank_1 = (bitmap[i] & (bitmap[i] >> 1) & 0x5555555555555555_u64);
-		    let free_rank_2 = (free_rank_1 & (free_rank_1 >> 2) & 0x1111111111111111_u64);
-		    let free_rank_3 = (free_rank_2 & (free_rank_2 >> 4) & 0x0101010101010101_u64);
+    fn alloc_rank_2(bitmap: &mut [u64]) -> usize {
+        for i in 0..8 {
+            let free_rank_1 = bitmap[i] & (bitmap[i] >> 1) & 0x5555555555555555_u64;
+            let free_rank_2 = free_rank_1 & (free_rank_1 >> 2) & 0x1111111111111111_u64;
 
-            if (free_rank_3 == 0) {
-			    continue;
-		    }
+            if free_rank_2 == 0 {
+                continue;
+            }
 
-		    let offset = find_first_set(free_rank_3);
-		    bitmap[i] ^= (0xff << offset);
-		    debug_assert!((bitmap[i] & (0xff << offset)) == 0);
-		    return i*64 + offset;
-	    }
+            let offset = find_first_set(free_rank_2);
+            bitmap[i] ^= 0xf << offset;
+            debug_assert!((bitmap[i] & (0xf << offset)) == 0);
+            return i*64 + offset;
+        }
 
-	    unreachable!();
+        unreachable!();
     }
 
-    fn alloc_rank_4(bitmap: &mut [u64; 8]) -> usize {
-	    for i in 0..8 {
-	        let free_rank_1 = (bitmap[i] & (bitmap[i] >> 1) & 0x5555555555555555_u64);
-		    let free_rank_2 = (free_rank_1 & (free_rank_1 >> 2) & 0x1111111111111111_u64);
-		    let free_rank_3 = (free_rank_2 & (free_rank_2 >> 4) & 0x0101010101010101_u64);
-            let free_rank_4 = (free_rank_3 & (free_rank_3 >> 8) & 0x0001000100010001_u64);
+    fn alloc_rank_3(bitmap: &mut [u64]) -> usize {
+        for i in 0..8 {
+            let free_rank_1 = bitmap[i] & (bitmap[i] >> 1) & 0x5555555555555555_u64;
+            let free_rank_2 = free_rank_1 & (free_rank_1 >> 2) & 0x1111111111111111_u64;
+            let free_rank_3 = free_rank_2 & (free_rank_2 >> 4) & 0x0101010101010101_u64;
 
-            if (free_rank_4 == 0) {
-			    continue;
-		    }
+            if free_rank_3 == 0 {
+                continue;
+            }
 
-		    let offset = find_first_set(free_rank_4);
-		    bitmap[i] ^= (0xffff << offset);
-		    debug_assert!((bitmap[i] & (0xffff << offset)) == 0);
-		    return i*64 + offset;
-	    }
+            let offset = find_first_set(free_rank_3);
+            bitmap[i] ^= 0xff << offset;
+            debug_assert!((bitmap[i] & (0xff << offset)) == 0);
+            return i*64 + offset;
+        }
 
-	    unreachable!();
+        unreachable!();
     }
 
-    fn alloc_rank_5(bitmap: &mut [u64; 8]) -> usize {
-	    for i in 0..8 {
-	        if (bitmap[i] & 0xffffffff) == 0xffffffff {
-	            bitmap[i] ^= 0xffffffff;
-	            return i*64;
-	        }
-	        if (bitmap[i] & 0xffffffff00000000) == 0xffffffff00000000 {
-	            bitmap[i] ^= 0xffffffff00000000;
-	            return i*64 + 32;
-	        }
-	    }
+    fn alloc_rank_4(bitmap: &mut [u64]) -> usize {
+        for i in 0..8 {
+            let free_rank_1 = bitmap[i] & (bitmap[i] >> 1) & 0x5555555555555555_u64;
+            let free_rank_2 = free_rank_1 & (free_rank_1 >> 2) & 0x1111111111111111_u64;
+            let free_rank_3 = free_rank_2 & (free_rank_2 >> 4) & 0x0101010101010101_u64;
+            let free_rank_4 = free_rank_3 & (free_rank_3 >> 8) & 0x0001000100010001_u64;
+
+            if free_rank_4 == 0 {
+                continue;
+            }
+
+            let offset = find_first_set(free_rank_4);
+            bitmap[i] ^= 0xffff << offset;
+            debug_assert!((bitmap[i] & (0xffff << offset)) == 0);
+            return i*64 + offset;
+        }
 
-	    unreachable!();
+        unreachable!();
     }
 
-    fn alloc_rank_6(bitmap: &mut [u64; 8]) -> usize {
-	    for i in 0..8 {
-		    if (bitmap[i] == 0xffffffffffffffff_u64) {
-			    bitmap[i] = 0;
-			    return i*64;
-		    }
-	    }
+    fn alloc_rank_5(bitmap: &mut [u64]) -> usize {
+        for i in 0..8 {
+            if (bitmap[i] & 0xffffffff) == 0xffffffff {
+                bitmap[i] ^= 0xffffffff;
+                return i*64;
+            }
+            if (bitmap[i] & 0xffffffff00000000) == 0xffffffff00000000 {
+                bitmap[i] ^= 0xffffffff00000000;
+                return i*64 + 32;
+            }
+        }
 
-	    unreachable!();
+        unreachable!();
     }
 
-    fn alloc_rank_7(bitmap: &mut [u64; 8]) -> usize {
-	    for (int i = 0; i < 8; i+=2) {
-		    if ((bitmap[i] & bitmap[i+1]) == 0xffffffffffffffff_u64) {
-			    bitmap[i] = 0;
-			    bitmap[i+1] = 0;
-			    return i*64;
-		    }
-	    }
+    fn alloc_rank_6(bitmap: &mut [u64]) -> usize {
+        for i in 0..8 {
+            if bitmap[i] == 0xffffffffffffffff_u64 {
+                bitmap[i] = 0;
+                return i*64;
+            }
+        }
 
-	    unreachable!();
+        unreachable!();
     }
 
-    fn alloc_rank_8(bitmap: &mut [u64; 8]) -> usize {
-	    for (int i = 0; i < 8; i+=4) {
-		    if ((bitmap[i] & bitmap[i+1] & bitmap[i+2] & bitmap[i+3]) != 0xffffffffffffffff_u64) {
-			    continue;
-		    }
+    fn alloc_rank_7(bitmap: &mut [u64]) -> usize {
+        for i in (0..8).filter(|i| i % 2 == 0) {
+            if (bitmap[i] & bitmap[i+1]) == 0xffffffffffffffff_u64 {
+                bitmap[i] = 0;
+                bitmap[i+1] = 0;
+                return i*64;
+            }
+        }
+
+        unreachable!();
+    }
 
-		    bitmap[i] = 0;
-		    bitmap[i+1] = 0;
-		    bitmap[i+2] = 0;
-		    bitmap[i+3] = 0;
-		    return i*64;
-	    }
+    fn alloc_rank_8(bitmap: &mut [u64]) -> usize {
+        for i in (0..8).filter(|i| i % 4 == 0) {
+            if (bitmap[i] & bitmap[i+1] & bitmap[i+2] & bitmap[i+3]) != 0xffffffffffffffff_u64 {
+                continue;
+            }
+
+            bitmap[i] = 0;
+            bitmap[i+1] = 0;
+            bitmap[i+2] = 0;
+            bitmap[i+3] = 0;
+            return i*64;
+        }
 
-	    unreachable!();
+        unreachable!();
     }
 
-    fn summarize_0(bitmap: u64) -> u64
-    {
-	    let summary = (bitmap != 0) as u64;
+    fn summarize_0(bitmap: u64) -> u64 {
+        let mut summary = (bitmap != 0) as u64;
 
-	    uint64_t rank_1 = (bitmap & (bitmap >> 1)) & 0x5555555555555555_u64;
-	    summary |= ((rank_1 != 0) as u64 << 1);
+        let rank_1 = (bitmap & (bitmap >> 1)) & 0x5555555555555555_u64;
+        summary |= ((rank_1 != 0) as u64) << 1;
 
-	    uint64_t rank_2 = (rank_1 & (rank_1 >> 2)) & 0x1111111111111111_u64;
-	    summary |= ((rank_2 != 0) as u64 << 2);
+        let rank_2 = (rank_1 & (rank_1 >> 2)) & 0x1111111111111111_u64;
+        summary |= ((rank_2 != 0) as u64) << 2;
 
-	    uint64_t rank_3 = (rank_2 & (rank_2 >> 4)) & 0x0101010101010101_u64;
-	    summary |= ((rank_3 != 0) as u64 << 3);
+        let rank_3 = (rank_2 & (rank_2 >> 4)) & 0x0101010101010101_u64;
+        summary |= ((rank_3 != 0) as u64) << 3;
 
-	    uint64_t rank_4 = (rank_3 & (rank_3 >> 8)) & 0x0001000100010001_u64;
-	    summary |= ((rank_4 != 0) as u64 << 4);
+        let rank_4 = (rank_3 & (rank_3 >> 8)) & 0x0001000100010001_u64;
+        summary |= ((rank_4 != 0) as u64) << 4;
 
-	    uint64_t rank_5 = (rank_4 & (rank_4 >> 16)) & 0x0000000100000001_u64;
-	    summary |= ((rank_5 != 0) as u64 << 5);
+        let rank_5 = (rank_4 & (rank_4 >> 16)) & 0x0000000100000001_u64;
+        summary |= ((rank_5 != 0) as u64) << 5;
 
-	    uint64_t rank_6 = (rank_5 & (rank_5 >> 32)) & 0x0000000000000001_u64;
-	    summary |= ((rank_6 != 0) as u64 << 6);
+        let rank_6 = (rank_5 & (rank_5 >> 32)) & 0x0000000000000001_u64;
+        summary |= ((rank_6 != 0) as u64) << 6;
 
-	    summary
+        summary
     }
 
-    fn summarize_1(bitmap: &[u64; 2]) -> u64
-    {
-	    let summary0 = summarize_0(bitmap[0]);
-	    let summary1 = summarize_0(bitmap[1]);
+    fn summarize_1(bitmap: &[u64]) -> u64 {
+        let summary0 = Self::summarize_0(bitmap[0]);
+        let summary1 = Self::summarize_0(bitmap[1]);
 
-	    if (summary0 & summary1 & (1<<6)) {
-		    (1<<7)
-	    } else {
+        if (summary0 & summary1 & (1<<6)) != 0 {
+            1<<7
+        } else {
             summary0 | summary1
         }
     }
 
-    fn summarize_2(bitmap: &[u64; 4]) -> u64
-    {
-        let summary0 = summarize_1(&bitmap[0..2]);
-        let summary1 = summarize_1(&bitmap[2..4]);
+    fn summarize_2(bitmap: &[u64]) -> u64 {
+        let summary0 = Self::summarize_1(&bitmap[0..2]);
+        let summary1 = Self::summarize_1(&bitmap[2..4]);
 
-        if (summary0 & summary1 & (1<<7)) {
-	        (1<<8)
+        if (summary0 & summary1 & (1<<7)) != 0 {
+            1<<8
         } else {
             summary0 | summary1
         }
     }
 
-    fn summarize_3(bitmap: &[u64; 8]) -> u64
-    {
-	    let summary0 = summarize_2(&bitmap[0..4]);
-	    let summary1 = summarize_2(&bitmap[4..8]);
+    fn summarize_3(bitmap: &[u64]) -> u64 {
+        let summary0 = Self::summarize_2(&bitmap[0..4]);
+        let summary1 = Self::summarize_2(&bitmap[4..8]);
 
-	    if (summary0 & summary1 & (1<<8)) {
-		    (1<<9)
-	    } else {
+        if (summary0 & summary1 & (1<<8)) != 0 {
+            1<<9
+        } else {
             summary0 | summary1
         }
     }
 
-    fn alloc_in_bitmap(bitmap: &[u64; 8], rank: usize) -> usize {
-	    match rank {
-	        0 => alloc_rank_0(bitmap),
-	        1 => alloc_rank_1(bitmap),
-	        2 => alloc_rank_2(bitmap),
-	        3 => alloc_rank_3(bitmap),
-	        4 => alloc_rank_4(bitmap),
-	        5 => alloc_rank_5(bitmap),
-	        6 => alloc_rank_6(bitmap),
-	        7 => alloc_rank_7(bitmap),
-	        8 => alloc_rank_8(bitmap),
-	        _ => unreachable!(),
-	    }
-    }
-
-    fn dealloc_in_bitmap(bitmap: &[u64; 8], rank: usize, offset: usize)
-    {
-	    let bitmap_offset = offset >> 6;
-
-	    if (rank < 6) {
-		    let mask = ((1_u64<<(1<<rank))-1) << (offset & 0x3f);
-		    debug_assert!((bitmap[bitmap_offset] & mask) == 0);
-		    bitmap[bitmap_offset] |= mask;
-		    return;
-	    }
-
-	    match rank {
-	    case 8:
-		    assert(bitmap[bitmap_offset+2] == 0);
-		    bitmap[bitmap_offset+2] = 0xffffffffffffffffull;
-		    assert(bitmap[bitmap_offset+3] == 0);
-		    bitmap[bitmap_offset+3] = 0xffffffffffffffffull;
-	    case 7:
-		    assert(bitmap[bitmap_offset+1] == 0);
-		    bitmap[bitmap_offset+1] = 0xffffffffffffffffull;
-	    case 6:
-		    assert(bitmap[bitmap_offset] == 0);
-		    bitmap[bitmap_offset] = 0xffffffffffffffffull;
-		    return;
-	    }
-
-	    assert_not_reached();
+    fn alloc_in_bitmap(bitmap: &mut [u64], rank: usize) -> usize {
+        match rank {
+            0 => Self::alloc_rank_0(bitmap),
+            1 => Self::alloc_rank_1(bitmap),
+            2 => Self::alloc_rank_2(bitmap),
+            3 => Self::alloc_rank_3(bitmap),
+            4 => Self::alloc_rank_4(bitmap),
+            5 => Self::alloc_rank_5(bitmap),
+            6 => Self::alloc_rank_6(bitmap),
+            7 => Self::alloc_rank_7(bitmap),
+            8 => Self::alloc_rank_8(bitmap),
+            _ => unreachable!(),
+        }
     }
 
+    fn dealloc_in_bitmap(bitmap: &mut [u64], rank: usize, offset: usize) {
+        let bitmap_offset = offset >> 6;
+
+        if rank < 6 {
+            let mask = ((1_u64 << (1 << rank)) - 1) << (offset & 0x3f);
+            assert!((bitmap[bitmap_offset] & mask) == 0, "Double free in buddy allocator.");
+            bitmap[bitmap_offset] |= mask;
+            return;
+        }
+
+        let words = 1 << (rank - 6);
+        assert!(words <= 4);
+
+        for word in &mut bitmap[bitmap_offset..bitmap_offset + words] {
+            assert!(*word == 0, "Double free in buddy allocator.");
+            *word = 0xffffffffffffffff_u64;
+        }
+    }
+
+    /// Moves the pivot upwards until its subtree contains a free region
+    /// of the rank(s) in `search_bit`.
     fn search_up(&mut self, search_bit: u64) {
-	    let pivot_rank = self.pivot_rank;
-	    let pivot_rank_bit = 1_u64 << pivot_rank;
-	    let pivot = self.pivot;
-        let tree = self.tree_buffer;
+        let mut pivot_rank = self.pivot_rank;
+        let mut pivot_rank_bit = 1_u64 << pivot_rank;
+        let mut pivot = self.pivot;
+        let tree = &mut *self.tree_buffer;
+
+        while (search_bit & tree[pivot]) == 0 {
+            debug_assert!(pivot > 1);
+
+            if tree[pivot] == pivot_rank_bit && tree[pivot ^ 1] == pivot_rank_bit {
+                // Coalesce.
+                tree[pivot/2] = pivot_rank_bit << 1;
+            } else {
+                // Aggregate.
+                tree[pivot/2] = tree[pivot] | tree[pivot ^ 1];
+            }
+
+            pivot_rank += 1;
+            pivot_rank_bit <<= 1;
+            pivot /= 2;
+        }
 
+        self.pivot_rank = pivot_rank;
+        self.pivot = pivot;
+    }
 
-	    while ((search_bit & tree[pivot]) == 0) {
-		    debug_assert!(pivot > 1);
+    /// Moves the pivot downwards, following free regions of rank `search_bit`
+    /// and splitting them on the way, until it reaches either `rank` or the bitmap.
+    fn search_down(&mut self, search_bit: u64, rank: usize) {
+        let mut search_bit = search_bit;
+        let mut pivot_rank = self.pivot_rank;
+        let mut pivot_rank_bit = 1_u64 << pivot_rank;
+        let mut pivot = self.pivot;
+        let tree = &mut *self.tree_buffer;
+
+        // Move pivot downward.
+        while pivot_rank > BITMAP_RANK && pivot_rank > rank {
+            if tree[pivot] == pivot_rank_bit {
+                // Split.
+                tree[2*pivot] = pivot_rank_bit >> 1;
+                tree[2*pivot+1] = pivot_rank_bit >> 1;
+
+                search_bit >>= 1;
+            }
+
+            pivot *= 2;
+
+            if (search_bit & tree[pivot]) == 0 {
+                // Switch to right branch if left does not have the right region.
+                pivot += 1;
+            }
+
+            // Store summary of the upper parts of the tree.
+            tree[pivot/2] = tree[pivot/4] | tree[pivot ^ 1];
+
+            pivot_rank -= 1;
+            pivot_rank_bit >>= 1;
+        }
 
-		    if (tree[pivot] == pivot_rank_bit && tree[pivot ^ 1] == pivot_rank_bit) {
-			    // Coalesce.
-			    tree[pivot/2] = pivot_rank_bit << 1;
-		    } else {
-		        // Aggregate.
-			    tree[pivot/2] = tree[pivot] | tree[pivot ^ 1];
-		    }
+        self.pivot_rank = pivot_rank;
+        self.pivot = pivot;
+    }
 
-		    ++pivot_rank;
-		    pivot_rank_bit <<= 1;
-		    pivot /= 2;
-	    }
+    /// Moves the pivot upwards until its subtree contains `address`.
+    fn search_address_up(&mut self, address: usize) {
+        let mut pivot_rank = self.pivot_rank;
+        let mut pivot_rank_bit = 1_u64 << pivot_rank;
+        let mut pivot = self.pivot;
 
-	    self.pivot_rank = pivot_rank;
-	    self.pivot = pivot;
+        let mut address = address >> pivot_rank;
+        let mut paddress = self.pivot_address() >> pivot_rank;
+
+        let tree = &mut *self.tree_buffer;
+
+        while address != paddress {
+            debug_assert!(pivot > 1);
+
+            if tree[pivot] == pivot_rank_bit && tree[pivot ^ 1] == pivot_rank_bit {
+                // Coalesce.
+                tree[pivot/2] = pivot_rank_bit << 1;
+            } else {
+                // Just summary.
+                tree[pivot/2] = tree[pivot] | tree[pivot ^ 1];
+            }
+
+            pivot_rank += 1;
+            pivot_rank_bit <<= 1;
+            pivot /= 2;
+
+            address >>= 1;
+            paddress >>= 1;
+        }
+
+        self.pivot_rank = pivot_rank;
+        self.pivot = pivot;
     }
 
-    fn search_down(search_bit: u64, rank: usize)
-    {
-	    let pivot_rank = self.pivot_rank;
-	    let pivot_rank_bit = 1_u64 << pivot_rank;
-	    let pivot = self.pivot;
-	    let tree = self.tree_buffer;
-        let bitmap_rank = self.bitmap_rank;
+    /// Moves the pivot downwards towards `address`, until it reaches either `rank` or the bitmap.
+    fn search_address_down(&mut self, address: usize, rank: usize) {
+        let mut pivot_rank = self.pivot_rank;
+        let mut pivot_rank_bit = 1_u64 << pivot_rank;
+        let mut pivot = self.pivot;
+        let tree = &mut *self.tree_buffer;
+
+        while pivot_rank > BITMAP_RANK && pivot_rank > rank {
+            if tree[pivot] == pivot_rank_bit {
+                // Split.
+                tree[2*pivot] = pivot_rank_bit >> 1;
+                tree[2*pivot+1] = pivot_rank_bit >> 1;
+            }
+
+            if tree[pivot] == 0 {
+                // May be uninitialized.
+                tree[2*pivot] = 0;
+                tree[2*pivot+1] = 0;
+            }
+
+            pivot = 2*pivot + ((address >> (pivot_rank-1)) & 1);
+
+            // Store summary of the upper parts of the tree.
+            tree[pivot/2] = tree[pivot/4] | tree[pivot ^ 1];
+
+            pivot_rank -= 1;
+            pivot_rank_bit >>= 1;
+        }
+
+        self.pivot_rank = pivot_rank;
+        self.pivot = pivot;
+    }
 
+    /// Called when the pivot became completely free. If its buddy is free as well,
+    /// the pair is merged, and so on upwards. Without this, two free buddies could sit
+    /// next to each other and the summary would miss the larger rank.
+    fn coalesce_up(&mut self) {
+        let tree = &mut *self.tree_buffer;
+
+        while self.pivot > 1 {
+            let pivot_rank_bit = 1_u64 << self.pivot_rank;
+            if tree[self.pivot] != pivot_rank_bit || tree[self.pivot ^ 1] != pivot_rank_bit {
+                break;
+            }
+
+            tree[self.pivot/2] = pivot_rank_bit << 1;
+            self.pivot_rank += 1;
+            self.pivot /= 2;
+        }
+    }
 
-	    // Move pivot downward.
-	    while (pivot_rank > bitmap_rank && pivot_rank > rank) {
-		    if (tree[pivot] == pivot_rank_bit) {
-			    // Split.
-			    tree[2*pivot] = pivot_rank_bit >> 1;
-			    tree[2*pivot+1] = pivot_rank_bit >> 1;
+    /// Allocates a block of `1 << rank` frames, aligned to its size.
+    pub fn alloc_frame(&mut self, rank: usize) -> Option<usize> {
+        if rank > self.root_rank {
+            // Request is bigger than the whole memory.
+            return None;
+        }
 
-			    search_bit >>= 1;
-		    }
+        let rank_bit = 1_u64 << rank;
+        let mut search_bit = rank_bit;
 
-		    pivot *= 2;
+        // Encodes availability of all orders in the memory.
+        let master_summary = self.available_ranks();
 
-		    if (!(search_bit & tree[pivot])) {
-			    // Switch to right branch if left does not have the right region.
-			    ++pivot;
-		    }
+        if rank_bit > master_summary {
+            // Request is bigger than the largest free region.
+            return None;
+        }
 
-		    // Store summary of the upper parts of the tree.
-		    tree[pivot/2] = tree[pivot/4] | tree[pivot^1];
+        // Compute the smallest region that is big enough for the request.
+        while (search_bit & master_summary) == 0 {
+            search_bit <<= 1;
+        }
 
-		    --pivot_rank;
-		    pivot_rank_bit >>= 1;
-	    }
+        // Search for the correct node.
+        self.search_up(search_bit);
+        self.search_down(search_bit, rank);
 
-	    self.pivot_rank = pivot_rank;
-	    self.pivot = pivot;
+        Some(self.take_at_pivot(rank))
     }
 
-    fn search_address_up(address: usize)
-    {
-	    let pivot_rank = self.pivot_rank;
-	    let pivot_rank_bit = 1 << pivot_rank;
-	    let pivot = self.pivot;
+    /// Allocates a block of `1 << rank` frames that ends at or below `limit`.
+    /// Slower than `alloc_frame()`, since it always searches from the root for the lowest free block.
+    /// When `limit` is not aligned to a bitmap segment, a block in the segment straddling it
+    /// may be missed.
+    pub fn alloc_frame_below(&mut self, rank: usize, limit: usize) -> Option<usize> {
+        if rank > self.root_rank || limit < 1 << rank {
+            return None;
+        }
 
-	    let paddress = pivot_address();
+        // Every rank that can be split down to `rank`.
+        let search_mask = !((1_u64 << rank) - 1);
 
-	    address >>= pivot_rank;
-	    paddress >>= pivot_rank;
+        self.search_root();
+        if (self.tree_buffer[1] & search_mask) == 0 {
+            return None;
+        }
 
-	    while (address != paddress) {
-		    debug_assert!(pivot > 1);
+        if !self.search_lowest_down(search_mask, rank, limit) {
+            return None;
+        }
 
-		    if (tree[pivot] == pivot_rank_bit && tree[pivot ^ 1] == pivot_rank_bit) {
-			    // Coalesce.
-			    tree[pivot/2] = pivot_rank_bit << 1;
-		    } else {
-			    // Just summary.
-			    tree[pivot/2] = tree[pivot] | tree[pivot ^ 1];
-		    }
+        let address = self.take_at_pivot(rank);
+        if address + (1 << rank) > limit {
+            self.free_frame(rank, address);
+            return None;
+        }
 
-		    ++pivot_rank;
-		    pivot_rank_bit <<= 1;
-		    pivot /= 2;
+        Some(address)
+    }
 
-		    address >>= 1;
-		    paddress >>= 1;
-	    }
+    /// Moves the pivot all the way up to the root.
+    fn search_root(&mut self) {
+        let tree = &mut *self.tree_buffer;
 
-	    self.pivot_rank = pivot_rank;
-	    self.pivot = pivot;
+        while self.pivot > 1 {
+            let pivot_rank_bit = 1_u64 << self.pivot_rank;
+
+            if tree[self.pivot] == pivot_rank_bit && tree[self.pivot ^ 1] == pivot_rank_bit {
+                // Coalesce.
+                tree[self.pivot/2] = pivot_rank_bit << 1;
+            } else {
+                // Aggregate.
+                tree[self.pivot/2] = tree[self.pivot] | tree[self.pivot ^ 1];
+            }
+
+            self.pivot_rank += 1;
+            self.pivot /= 2;
+        }
     }
 
-    fn search_address_down(address: usize, rank: usize)
-    {
-	    int pivot_rank = _pivot_rank;
-	    uint64_t pivot_rank_bit = 1 << _pivot_rank;
-	    int64_t pivot = _pivot;
+    /// Like `search_down()`, but always takes the leftmost branch with a region of a rank in `search_mask`,
+    /// and gives up (returning false) once the branch starts at or above `limit`.
+    fn search_lowest_down(&mut self, search_mask: u64, rank: usize, limit: usize) -> bool {
+        while self.pivot_rank > BITMAP_RANK && self.pivot_rank > rank {
+            let pivot = self.pivot;
+            let pivot_rank_bit = 1_u64 << self.pivot_rank;
 
-	    while (pivot_rank > _bitmap_rank && pivot_rank > rank) {
-		    if (_tree_base[pivot] == pivot_rank_bit) {
-			    // Split.
-			    _tree_base[2*pivot] = pivot_rank_bit >> 1;
-			    _tree_base[2*pivot+1] = pivot_rank_bit >> 1;
-		    }
+            {
+                let tree = &mut *self.tree_buffer;
 
-		    if (_tree_base[pivot] == 0) {
-			    // May be uninitialized.
+                if tree[pivot] == pivot_rank_bit {
+                    // Split.
+                    tree[2*pivot] = pivot_rank_bit >> 1;
+                    tree[2*pivot+1] = pivot_rank_bit >> 1;
+                }
 
-			    _tree_base[2*pivot] = 0;
-			    _tree_base[2*pivot+1] = 0;
-		    }
+                let child = if (search_mask & tree[2*pivot]) != 0 { 2*pivot } else { 2*pivot + 1 };
+                debug_assert!((search_mask & tree[child]) != 0);
 
-		    pivot = 2*pivot + ((address >> (pivot_rank-1)) & 1);
+                // Store summary of the upper parts of the tree.
+                tree[pivot] = tree[pivot/2] | tree[child ^ 1];
+                self.pivot = child;
+            }
 
-		    // Store summary of the upper parts of the tree.
-		    _tree_base[pivot/2] = _tree_base[pivot/4] | _tree_base[pivot^1];
+            self.pivot_rank -= 1;
 
-		    --pivot_rank;
-		    pivot_rank_bit >>= 1;
-	    }
+            if self.pivot_address() >= limit {
+                return false;
+            }
+        }
 
-	    _pivot_rank = pivot_rank;
-	    _pivot = pivot;
+        true
     }
 
-    fn alloc_frame(rank: usize) -> usize
-    {
-	    if (rank > 63) {
-		    // Request is bigger than the range of 64b integer.
-		    return -1;
-	    }
+    /// Allocates the block of `1 << rank` frames the pivot was moved to by one of the searches.
+    fn take_at_pivot(&mut self, rank: usize) -> usize {
+        let rank_bit = 1_u64 << rank;
+        let pivot = self.pivot;
+
+        if self.pivot_rank == rank {
+            // Allocated.
+            debug_assert!(self.tree_buffer[pivot] == rank_bit);
+            self.tree_buffer[pivot] = 0;
+            return self.pivot_address();
+        }
+
+        assert!(self.pivot_rank == BITMAP_RANK);
+
+        // We have identified a 64-byte region of the bitmap.
 
-	    uint64_t rank_bit = 1 << rank;
-	    uint64_t search_bit = rank_bit;
+        let address = self.pivot_address();
+        let fully_free = self.tree_buffer[pivot] == 1 << BITMAP_RANK;
 
-	    // Encodes availability of all orders in the memory.
-	    uint64_t master_summary = _tree_base[_pivot] | _tree_base[_pivot/2];
+        let summary;
+        let offset;
+        {
+            let bitmap = self.bitmap(address);
 
-	    if (rank_bit > master_summary) {
-		    // Request is bigger than the largest free region.
-		    return -1;
-	    }
+            if fully_free {
+                for word in bitmap.iter_mut() {
+                    *word = 0xffffffffffffffff_u64;
+                }
+            }
 
-	    // Compute the smallest region that is big enough for the request.
-	    while ((search_bit & master_summary) == 0) {
-		    search_bit <<= 1;
-	    }
+            offset = Self::alloc_in_bitmap(bitmap, rank);
+            summary = Self::summarize_3(bitmap);
+        }
 
-	    // Search for the correct node.
-	    search_up(search_bit);
-	    search_down(search_bit, rank);
+        // Update the tree.
+        self.tree_buffer[pivot] = summary;
+        address + offset
+    }
 
-	    if (_pivot_rank == rank) {
-		    // Allocated.
-		    _tree_base[_pivot] = 0;
-		    return pivot_address();
-	    }
+    /// Releases a block of `1 << rank` frames previously obtained from `alloc_frame()`,
+    /// or a block aligned to its size that has never been freed.
+    pub fn free_frame(&mut self, rank: usize, address: usize) {
+        assert!(rank <= self.root_rank);
+        assert!(address & ((1 << rank) - 1) == 0);
+        assert!(address + (1 << rank) <= 1 << self.root_rank);
 
-	    assert(_pivot_rank == _bitmap_rank);
+        self.search_address_up(address);
+        self.search_address_down(address, rank);
+
+        let pivot = self.pivot;
+
+        if self.pivot_rank == rank {
+            assert!(self.tree_buffer[pivot] == 0, "Double free in buddy allocator.");
+            self.tree_buffer[pivot] = 1 << rank;
+            self.coalesce_up();
+            return;
+        }
 
-	    // We have identified a 64-byte region of the bitmap.
+        assert!(self.pivot_rank == BITMAP_RANK);
+        assert!(rank < BITMAP_RANK);
+        assert!(self.tree_buffer[pivot] != 1 << BITMAP_RANK, "Double free in buddy allocator.");
 
-	    int64_t address = pivot_address();
+        let fully_allocated = self.tree_buffer[pivot] == 0;
 
-	    void* bitmap = &_bitmap_base[(address >> _bitmap_rank) * 8];
+        let summary;
+        {
+            let bitmap = self.bitmap(address);
+
+            if fully_allocated {
+                for word in bitmap.iter_mut() {
+                    *word = 0;
+                }
+            }
+
+            Self::dealloc_in_bitmap(bitmap, rank, address & (SEGMENT_FRAMES - 1));
+            summary = Self::summarize_3(bitmap);
+        }
+
+        self.tree_buffer[pivot] = summary;
+        self.coalesce_up();
+    }
+}
 
-	    if (_tree_base[_pivot] == _bitmap_rank) {
-		    memset64(bitmap, 0xffffffffffffffffull, 8);
-	    }
+impl<'a> FrameAllocator for BuddyAllocator<'a> {
+    fn alloc_frames(&mut self, order: usize) -> Option<PhysicalAddress> {
+        self.alloc_frame(order).map(|frame| PhysicalAddress((frame * self.alloc_unit) as u64))
+    }
 
-	    int offset = alloc_in_bitmap(bitmap, rank);
+    fn free_frames(&mut self, address: PhysicalAddress, order: usize) {
+        assert!(address.0 as usize % self.alloc_unit == 0);
+        self.free_frame(order, address.0 as usize / self.alloc_unit)
+    }
 
-	    // Update the tree.
-	    _tree_base[_pivot] = summarize_3((aliasing_uint64_t*) bitmap);
-	    return address + offset;
+    fn alloc_frames_below(&mut self, order: usize, limit: PhysicalAddress) -> Option<PhysicalAddress> {
+        let limit = cmp::min(limit.0 as usize / self.alloc_unit, self.limit);
+        self.alloc_frame_below(order, limit).map(|frame| PhysicalAddress((frame * self.alloc_unit) as u64))
     }
+}
 
-    fn free_frame(rank: usize, address: usize)
-    {
-	    assert(rank < 64);
+#[test]
+fn test_buddy_buffer_sizes() {
+    assert_eq!(BuddyAllocator::bitmap_size(1), 8);
+    assert_eq!(BuddyAllocator::bitmap_size(512), 8);
+    assert_eq!(BuddyAllocator::bitmap_size(513), 16);
+    assert_eq!(BuddyAllocator::tree_size(512), 2);
+    assert_eq!(BuddyAllocator::tree_size(4096), 16);
+    assert_eq!(BuddyAllocator::tree_size(4097), 32);
+}
 
-	    search_address_up(address);
-	    search_address_down(address, rank);
+#[test]
+fn test_buddy_split_and_coalesce() {
+    let mut bitmap = [0u64; 64];
+    let mut tree = [0u64; 16];
+    let mut buddy = BuddyAllocator::new(4096, &mut bitmap, &mut tree, 4096);
+
+    buddy.dealloc(0, 4096);
+    assert_eq!(buddy.available_ranks(), 1 << 12);
+
+    // A single frame splits the whole memory down to rank 0.
+    let a = buddy.alloc_frame(0).unwrap();
+    let b = buddy.alloc_frame(0).unwrap();
+    assert_eq!(a, 0);
+    assert_eq!(b, 1);
+    assert!(buddy.alloc_frame(12).is_none());
+    assert!(buddy.available_ranks() & (1 << 11) != 0);
+
+    let c = buddy.alloc_frame(10).unwrap();
+    assert_eq!(c & 1023, 0);
+    assert!(c >= 1024);
+
+    buddy.free_frame(0, b);
+    buddy.free_frame(10, c);
+    buddy.free_frame(0, a);
+
+    // Everything merged back together.
+    assert_eq!(buddy.available_ranks(), 1 << 12);
+    assert_eq!(buddy.alloc_frame(12), Some(0));
+}
 
-	    assert(_pivot_rank == _bitmap_rank || _tree_base[_pivot] == 0);
+#[test]
+fn test_buddy_non_power_of_two() {
+    let mut bitmap = [0u64; 64];
+    let mut tree = [0u64; 16];
+    let mut buddy = BuddyAllocator::new(4096, &mut bitmap, &mut tree, 4096);
 
-	    if (_pivot_rank == rank) {
-		    _tree_base[_pivot] = 1 << rank;
-		    return;
-	    }
+    buddy.dealloc(0, 4096);
 
-	    assert(_pivot_rank == _bitmap_rank);
-	    assert(rank < _bitmap_rank);
+    let a = buddy.alloc(3).unwrap();
+    let b = buddy.alloc(1).unwrap();
+    // The fourth frame of the rank 2 block was returned.
+    assert_eq!(b, a + 3);
 
-	    void* bitmap = &_bitmap_base[(address >> _bitmap_rank) * 8];
+    buddy.dealloc(a, 3);
+    buddy.dealloc(b, 1);
+    assert_eq!(buddy.available_ranks(), 1 << 12);
+}
 
-	    if (_tree_base[_pivot] == 0) {
-		    memset64(bitmap, 0, 8);
-	    }
+#[test]
+fn test_buddy_exhaustion() {
+    let mut bitmap = [0u64; 64];
+    let mut tree = [0u64; 16];
+    let mut buddy = BuddyAllocator::new(4096, &mut bitmap, &mut tree, 4096);
 
-	    dealloc_in_bitmap((aliasing_uint64_t*) bitmap, rank, address & ((1<<_bitmap_rank) - 1));
+    // Only a part of the range is usable, with holes.
+    buddy.dealloc(100, 1000);
+    buddy.dealloc(2000, 3);
 
-	    _tree_base[_pivot] = summarize_3((aliasing_uint64_t*) bitmap);
+    let mut count = 0;
+    while let Some(frame) = buddy.alloc_frame(0) {
+        assert!((frame >= 100 && frame < 1100) || (frame >= 2000 && frame < 2003));
+        count += 1;
     }
+    assert_eq!(count, 1003);
+    assert_eq!(buddy.available_ranks(), 0);
+    assert!(buddy.alloc(1).is_none());
+
+    buddy.free_frame(0, 2001);
+    assert_eq!(buddy.alloc_frame(0), Some(2001));
+    assert!(buddy.alloc_frame(0).is_none());
+}
+
+#[test]
+fn test_buddy_alloc_below() {
+    let mut bitmap = [0u64; 64];
+    let mut tree = [0u64; 16];
+    let mut buddy = BuddyAllocator::new(4096, &mut bitmap, &mut tree, 4096);
+
+    buddy.dealloc(1024, 1024);
+    buddy.dealloc(3072, 1024);
+
+    // Regular allocations don't care where the block is.
+    let a = buddy.alloc_frame(10).unwrap();
+    buddy.free_frame(10, a);
+
+    assert_eq!(buddy.alloc_frame_below(9, 4096), Some(1024));
+    assert_eq!(buddy.alloc_frame_below(9, 2048), Some(1536));
+    assert_eq!(buddy.alloc_frame_below(0, 2048), None);
+    assert_eq!(buddy.alloc_frame_below(0, 1024), None);
+    assert_eq!(buddy.alloc_frame_below(10, 4096), Some(3072));
+    assert_eq!(buddy.available_ranks(), 0);
+
+    buddy.free_frame(9, 1536);
+    assert_eq!(buddy.alloc_frame_below(3, 4096), Some(1536));
 }
@@ -0,0 +1,62 @@
+use x86_64::PhysicalAddress;
+
+use memory::frame::FrameAllocator;
+use memory::physical::FRAME_SIZE;
+
+/// Frame allocator for early boot, handing out frames from a single range one after another.
+///
+/// Freed frames are never reused. Whatever was not handed out can be passed
+/// to the real frame allocator later, see `remaining()`.
+pub struct BumpAllocator {
+    next: u64,
+    limit: u64,
+}
+
+impl BumpAllocator {
+    /// Manages the physical range `start..limit`, which must be frame aligned.
+    pub const fn new(start: u64, limit: u64) -> Self {
+        BumpAllocator { next: start, limit: limit }
+    }
+
+    /// The part of the range that was not handed out yet.
+    pub fn remaining(&self) -> (PhysicalAddress, usize) {
+        (PhysicalAddress(self.next), ((self.limit - self.next) / FRAME_SIZE as u64) as usize)
+    }
+}
+
+impl FrameAllocator for BumpAllocator {
+    fn alloc_frames(&mut self, order: usize) -> Option<PhysicalAddress> {
+        self.alloc_frames_below(order, PhysicalAddress(u64::max_value()))
+    }
+
+    fn free_frames(&mut self, _address: PhysicalAddress, _order: usize) {
+        // Leaked. Early boot allocations are expected to live forever anyway.
+    }
+
+    fn alloc_frames_below(&mut self, order: usize, limit: PhysicalAddress) -> Option<PhysicalAddress> {
+        let size = (FRAME_SIZE as u64) << order;
+        let start = (self.next + size - 1) & !(size - 1);
+
+        if start + size > self.limit || start + size > limit.0 {
+            return None;
+        }
+
+        // Frames skipped due to alignment are lost.
+        self.next = start + size;
+        Some(PhysicalAddress(start))
+    }
+}
+
+#[test]
+fn test_bump_alloc() {
+    let mut bump = BumpAllocator::new(0x1000, 0x10000);
+
+    assert_eq!(bump.alloc_frames(0).map(|a| a.0), Some(0x1000));
+    assert_eq!(bump.alloc_frames(2).map(|a| a.0), Some(0x4000));
+    assert_eq!(bump.alloc_frames_below(0, PhysicalAddress(0x9000)).map(|a| a.0), Some(0x8000));
+    assert!(bump.alloc_frames_below(0, PhysicalAddress(0x9000)).is_none());
+    assert!(bump.alloc_frames(4).is_none());
+
+    let (next, frames) = bump.remaining();
+    assert_eq!((next.0, frames), (0x9000, 7));
+}
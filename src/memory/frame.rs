@@ -0,0 +1,15 @@
+use x86_64::PhysicalAddress;
+
+/// Source of physical memory for page tables, the heap, and everything else that needs whole frames.
+///
+/// Memory is handed out in blocks of `1 << order` contiguous frames, aligned to their size.
+pub trait FrameAllocator {
+    fn alloc_frames(&mut self, order: usize) -> Option<PhysicalAddress>;
+
+    /// Releases a block previously returned by `alloc_frames()` or `alloc_frames_below()`, with the same order.
+    fn free_frames(&mut self, address: PhysicalAddress, order: usize);
+
+    /// Like `alloc_frames()`, but the whole block lies below `limit`.
+    /// Meant for devices that can't address all of the memory (e.g. 32-bit DMA).
+    fn alloc_frames_below(&mut self, order: usize, limit: PhysicalAddress) -> Option<PhysicalAddress>;
+}
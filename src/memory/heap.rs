@@ -1,30 +1,325 @@
+use core::cmp;
+use core::fmt;
 use spin;
 
-use alloc::allocator::{Alloc, AllocErr, Layout};
-use efi_app;
+use alloc::allocator::{Alloc, AllocErr, Layout, CannotReallocInPlace};
 
-use memory::list_alloc_simple;
+use memory::frame::FrameAllocator;
+use memory::heap_debug;
+use memory::list_alloc_simple::{ListAlloc, ListAllocStats, SPAN_HISTOGRAM_BUCKETS};
+use memory::paging;
+use memory::physical;
+use memory::slab::{SlabAllocator, SlabClassStats, CLASS_COUNT, MIN_CLASS_SIZE};
+use percpu;
+use platform;
+
+// Minimum amount of memory requested at once when the heap runs dry.
+const HEAP_GROWTH: usize = 256 * 1024;
+
+/// The list allocator, refilled with fresh frames from the system frame allocator whenever it runs out.
+struct ListHeap {
+    list: ListAlloc,
+}
+
+impl ListHeap {
+    unsafe fn grow(&mut self, min_size: usize) -> bool {
+        // Before the switch, memory could only be handed out through UEFI's identity map,
+        // which goes away with the switch, taking the objects with it.
+        assert!(paging::is_flat_map_active(), "The heap can't be used before the kernel page tables are active.");
+
+        let pages = (cmp::max(HEAP_GROWTH, min_size) + physical::FRAME_SIZE - 1) / physical::FRAME_SIZE;
+        let order = pages.next_power_of_two().trailing_zeros() as usize;
+
+        let address = match physical::SystemFrames.alloc_frames(order) {
+            Some(address) => address,
+            None => return false,
+        };
+
+        self.list.provide(paging::phys_to_virt(address) as *mut u8, physical::FRAME_SIZE << order);
+        true
+    }
+}
+
+unsafe impl Alloc for ListHeap {
+    unsafe fn alloc(&mut self, layout: Layout) -> Result<*mut u8, AllocErr> {
+        match self.list.alloc(layout.clone()) {
+            Ok(ptr) => Ok(ptr),
+            Err(err) => {
+                if !self.grow(layout.size() + layout.align()) {
+                    return Err(err);
+                }
+                self.list.alloc(layout)
+            }
+        }
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        self.list.dealloc(ptr, layout)
+    }
+
+    fn usable_size(&self, layout: &Layout) -> (usize, usize) {
+        self.list.usable_size(layout)
+    }
+
+    unsafe fn realloc(&mut self, ptr: *mut u8, layout: Layout, new_layout: Layout) -> Result<*mut u8, AllocErr> {
+        match self.list.realloc(ptr, layout.clone(), new_layout.clone()) {
+            Ok(ptr) => Ok(ptr),
+            Err(err) => {
+                if !self.grow(new_layout.size() + new_layout.align()) {
+                    return Err(err);
+                }
+                self.list.realloc(ptr, layout, new_layout)
+            }
+        }
+    }
+
+    unsafe fn grow_in_place(&mut self, ptr: *mut u8, layout: Layout, new_layout: Layout) -> Result<(), CannotReallocInPlace> {
+        if self.list.grow_in_place(ptr, layout.size(), new_layout.size()) {
+            Ok(())
+        } else {
+            Err(CannotReallocInPlace)
+        }
+    }
+
+    unsafe fn shrink_in_place(&mut self, ptr: *mut u8, layout: Layout, new_layout: Layout) -> Result<(), CannotReallocInPlace> {
+        self.list.shrink_in_place(ptr, layout.size(), new_layout.size());
+        Ok(())
+    }
+}
+
+struct Heap {
+    // Small objects.
+    slab: SlabAllocator,
+    // Large objects, and backing memory for slabs.
+    list: ListHeap,
+}
+
+/// Snapshot of the heap's state.
+#[derive(Copy, Clone, Debug)]
+pub struct HeapStats {
+    pub list: ListAllocStats,
+    pub slabs: [SlabClassStats; CLASS_COUNT],
+}
+
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let l = &self.list;
+        try!(writeln!(f, "Heap: {} bytes total, {} allocated (peak {}), {} free, {} garbage.",
+                      l.total_bytes, l.allocated_bytes, l.peak_allocated_bytes, l.free_bytes, l.garbage_bytes));
+        try!(writeln!(f, "Free spans: {}, largest {} bytes.", l.free_spans, l.largest_free_span));
+
+        try!(write!(f, "Span sizes:"));
+        for i in 0..SPAN_HISTOGRAM_BUCKETS {
+            if l.span_histogram[i] != 0 {
+                try!(write!(f, " {}{}:{}", 16 << i, if i == SPAN_HISTOGRAM_BUCKETS - 1 { "+" } else { "" }, l.span_histogram[i]));
+            }
+        }
+        try!(writeln!(f, ""));
+
+        for s in self.slabs.iter() {
+            if s.slabs != 0 {
+                try!(writeln!(f, "Slab {:4}: {} slabs, {} used, {} free.", s.object_size, s.slabs, s.objects_in_use, s.objects_free));
+            }
+        }
+
+        Ok(())
+    }
+}
 
 pub struct HeapAllocator {
-    inner: spin::Mutex<efi_app::Allocator<list_alloc_simple::ListAlloc>>,
+    inner: spin::Mutex<Heap>,
 }
 
 impl HeapAllocator {
     pub const fn new() -> Self {
-        HeapAllocator { inner: spin::Mutex::new(efi_app::Allocator::new(list_alloc_simple::ListAlloc::new())) }
+        HeapAllocator { inner: spin::Mutex::new(Heap {
+            slab: SlabAllocator::new(),
+            list: ListHeap { list: ListAlloc::new() },
+        }) }
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        self.inner.lock().stats()
+    }
+
+    /// Like `stats()`, but gives up instead of waiting for the lock.
+    /// Meant for the panic path, where the lock may be held by the panicking code.
+    pub fn try_stats(&self) -> Option<HeapStats> {
+        self.inner.try_lock().map(|heap| heap.stats())
+    }
+
+    /// Objects sitting in per-CPU caches are counted as in use.
+    pub fn slab_stats(&self) -> [SlabClassStats; CLASS_COUNT] {
+        self.inner.lock().slab.stats()
+    }
+
+    /// Returns all objects cached by the current CPU to the shared heap.
+    pub fn flush_cpu_cache(&self) {
+        if let Some(cpu) = percpu::current() {
+            platform::uninterruptible(|| unsafe {
+                cpu.heap_cache().flush(|class, objects| self.inner.lock().drain(class, objects));
+            })
+        }
+    }
+
+    unsafe fn alloc_raw(&self, layout: Layout) -> Result<*mut u8, AllocErr> {
+        match SlabAllocator::class_of(&layout) {
+            Some(class) => self.alloc_small(class, layout),
+            None => self.inner.lock().alloc(layout),
+        }
+    }
+
+    unsafe fn dealloc_raw(&self, ptr: *mut u8, layout: Layout) {
+        match SlabAllocator::class_of(&layout) {
+            Some(class) => self.dealloc_small(class, ptr, layout),
+            None => self.inner.lock().dealloc(ptr, layout),
+        }
+    }
+
+    unsafe fn alloc_small(&self, class: usize, layout: Layout) -> Result<*mut u8, AllocErr> {
+        let cpu = match percpu::current() {
+            Some(cpu) => cpu,
+            None => return self.inner.lock().alloc(layout),
+        };
+
+        let object = platform::uninterruptible(|| {
+            cpu.heap_cache().alloc(class, |slots| self.inner.lock().fill(class, slots))
+        });
+
+        object.ok_or(AllocErr::Exhausted { request: layout })
+    }
+
+    unsafe fn dealloc_small(&self, class: usize, ptr: *mut u8, layout: Layout) {
+        let cpu = match percpu::current() {
+            Some(cpu) => cpu,
+            None => return self.inner.lock().dealloc(ptr, layout),
+        };
+
+        platform::uninterruptible(|| {
+            cpu.heap_cache().dealloc(class, ptr, |objects| self.inner.lock().drain(class, objects))
+        })
+    }
+}
+
+impl Heap {
+    fn stats(&self) -> HeapStats {
+        HeapStats { list: self.list.list.stats(), slabs: self.slab.stats() }
+    }
+
+    unsafe fn alloc(&mut self, layout: Layout) -> Result<*mut u8, AllocErr> {
+        match SlabAllocator::class_of(&layout) {
+            Some(class) => self.slab.alloc(class, &mut self.list),
+            None => self.list.alloc(layout),
+        }
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        match SlabAllocator::class_of(&layout) {
+            Some(class) => self.slab.dealloc(ptr, class, &mut self.list),
+            None => self.list.dealloc(ptr, layout),
+        }
+    }
+
+    // Takes up to `slots.len()` objects for a per-CPU cache.
+    unsafe fn fill(&mut self, class: usize, slots: &mut [*mut u8]) -> usize {
+        for i in 0..slots.len() {
+            match self.slab.alloc(class, &mut self.list) {
+                Ok(object) => slots[i] = object,
+                Err(_) => return i,
+            }
+        }
+        slots.len()
+    }
+
+    // Takes back objects flushed from a per-CPU cache.
+    unsafe fn drain(&mut self, class: usize, objects: &[*mut u8]) {
+        for &object in objects {
+            self.slab.dealloc(object, class, &mut self.list);
+        }
+    }
+
+    // Resizing stays in place as long as the object doesn't move between size classes,
+    // or between slabs and the list allocator.
+    unsafe fn resize_in_place(&mut self, ptr: *mut u8, layout: Layout, new_layout: Layout) -> Result<(), CannotReallocInPlace> {
+        match (SlabAllocator::class_of(&layout), SlabAllocator::class_of(&new_layout)) {
+            (Some(a), Some(b)) if a == b => Ok(()),
+            (None, None) if new_layout.size() >= layout.size() => self.list.grow_in_place(ptr, layout, new_layout),
+            (None, None) => self.list.shrink_in_place(ptr, layout, new_layout),
+            _ => Err(CannotReallocInPlace),
+        }
+    }
+
+    unsafe fn realloc(&mut self, ptr: *mut u8, layout: Layout, new_layout: Layout) -> Result<*mut u8, AllocErr> {
+        self.list.realloc(ptr, layout, new_layout)
     }
 }
 
 unsafe impl<'a> Alloc for &'a HeapAllocator {
     #[inline]
     unsafe fn alloc(&mut self, layout: Layout) -> Result<*mut u8, AllocErr> {
-        self.inner.lock().alloc(layout)
+        if cfg!(feature = "heap-debug") {
+            let block = try!(self.alloc_raw(heap_debug::outer_layout(&layout)));
+            return Ok(heap_debug::arm(block, &layout));
+        }
+
+        self.alloc_raw(layout)
     }
 
     #[inline]
     unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
-        self.inner.lock().dealloc(ptr, layout)
+        if cfg!(feature = "heap-debug") {
+            let block = heap_debug::disarm(ptr, &layout);
+            return self.dealloc_raw(block, heap_debug::outer_layout(&layout));
+        }
+
+        self.dealloc_raw(ptr, layout)
+    }
+
+    #[inline]
+    fn usable_size(&self, layout: &Layout) -> (usize, usize) {
+        if cfg!(feature = "heap-debug") {
+            // Freeing with any other size is reported as corruption.
+            return (layout.size(), layout.size());
+        }
+
+        match SlabAllocator::class_of(layout) {
+            Some(class) => (layout.size(), MIN_CLASS_SIZE << class),
+            None => self.inner.lock().list.usable_size(layout),
+        }
     }
-}
 
+    #[inline]
+    unsafe fn realloc(&mut self, ptr: *mut u8, layout: Layout, new_layout: Layout) -> Result<*mut u8, AllocErr> {
+        match (SlabAllocator::class_of(&layout), SlabAllocator::class_of(&new_layout)) {
+            _ if cfg!(feature = "heap-debug") => {},
+            (None, None) => return self.inner.lock().realloc(ptr, layout, new_layout),
+            (Some(a), Some(b)) if a == b => return Ok(ptr),
+            _ => {},
+        }
+
+        // Moving between classes (or checking redzones), go through the front door.
+        let new_ptr = try!(Alloc::alloc(self, new_layout.clone()));
+        let size = if layout.size() < new_layout.size() { layout.size() } else { new_layout.size() };
+        ::core::ptr::copy_nonoverlapping(ptr, new_ptr, size);
+        Alloc::dealloc(self, ptr, layout);
+        Ok(new_ptr)
+    }
 
+    #[inline]
+    unsafe fn grow_in_place(&mut self, ptr: *mut u8, layout: Layout, new_layout: Layout) -> Result<(), CannotReallocInPlace> {
+        if cfg!(feature = "heap-debug") {
+            return Err(CannotReallocInPlace);
+        }
+
+        self.inner.lock().resize_in_place(ptr, layout, new_layout)
+    }
+
+    #[inline]
+    unsafe fn shrink_in_place(&mut self, ptr: *mut u8, layout: Layout, new_layout: Layout) -> Result<(), CannotReallocInPlace> {
+        if cfg!(feature = "heap-debug") {
+            return Err(CannotReallocInPlace);
+        }
+
+        self.inner.lock().resize_in_place(ptr, layout, new_layout)
+    }
+}
@@ -0,0 +1,146 @@
+//! Heap corruption detection, enabled by the `heap-debug` feature.
+//!
+//! Every allocation is wrapped into a larger block:
+//!
+//! ```text
+//! | redzone ... | size | align | state | canary | user data ... | redzone |
+//!                                               ^ pointer returned to the user
+//! ```
+//!
+//! The front part is at least 32 bytes and at least the requested alignment.
+//! Freed memory is poisoned, and the state word is kept outside the first 16 bytes
+//! of the block, so that it survives the bookkeeping the underlying allocators write into freed blocks.
+
+use core::cmp;
+use core::ptr;
+
+use alloc::allocator::Layout;
+
+const HEADER_SIZE: usize = 32;
+const TAIL_REDZONE: usize = 16;
+
+const STATE_LIVE: u64 = 0x4c49_5645_a110_ca7e;
+const STATE_FREED: u64 = 0x4652_4545_dead_f4ee;
+const CANARY: u64 = 0xc0de_c0de_c0de_c0de;
+
+const REDZONE_BYTE: u8 = 0xfd;
+const FREE_POISON_BYTE: u8 = 0xdd;
+const ALLOC_POISON_BYTE: u8 = 0xcd;
+
+#[inline]
+fn front_size(layout: &Layout) -> usize {
+    cmp::max(HEADER_SIZE, layout.align())
+}
+
+/// The layout actually requested from the underlying allocator.
+pub fn outer_layout(layout: &Layout) -> Layout {
+    let size = front_size(layout) + layout.size() + TAIL_REDZONE;
+    Layout::from_size_align(size, cmp::max(layout.align(), 16)).unwrap()
+}
+
+#[inline]
+unsafe fn field(user: *mut u8, offset: usize) -> *mut u64 {
+    user.offset(-(offset as isize)) as *mut u64
+}
+
+/// Sets up redzones in a fresh block, and returns the pointer for the user.
+pub unsafe fn arm(block: *mut u8, layout: &Layout) -> *mut u8 {
+    let front = front_size(layout);
+    let user = block.offset(front as isize);
+
+    ptr::write_bytes(block, REDZONE_BYTE, front - HEADER_SIZE);
+    *field(user, 32) = layout.size() as u64;
+    *field(user, 24) = layout.align() as u64;
+    *field(user, 16) = STATE_LIVE;
+    *field(user, 8) = CANARY;
+
+    ptr::write_bytes(user, ALLOC_POISON_BYTE, layout.size());
+    ptr::write_bytes(user.offset(layout.size() as isize), REDZONE_BYTE, TAIL_REDZONE);
+    user
+}
+
+/// Verifies the block being freed, poisons it, and returns the pointer to the underlying block.
+/// Any inconsistency is a panic.
+pub unsafe fn disarm(user: *mut u8, layout: &Layout) -> *mut u8 {
+    let front = front_size(layout);
+    let block = user.offset(-(front as isize));
+
+    match *field(user, 16) {
+        STATE_LIVE => {},
+        STATE_FREED => panic!("Heap corruption: double free of block at {:p} (size {}).", user, layout.size()),
+        _ => panic!("Heap corruption: bad header of block at {:p} (size {}), underrun or invalid pointer.", user, layout.size()),
+    }
+
+    let size = *field(user, 32) as usize;
+    let align = *field(user, 24) as usize;
+    if size != layout.size() || align != layout.align() {
+        panic!("Heap corruption: block at {:p} allocated with size {} (align {}), freed with size {} (align {}).",
+               user, size, align, layout.size(), layout.align());
+    }
+
+    if *field(user, 8) != CANARY || (0..front - HEADER_SIZE).any(|i| *block.offset(i as isize) != REDZONE_BYTE) {
+        panic!("Heap corruption: underrun before block at {:p} (size {}).", user, size);
+    }
+
+    let tail = user.offset(size as isize);
+    if let Some(i) = (0..TAIL_REDZONE).find(|&i| *tail.offset(i as isize) != REDZONE_BYTE) {
+        panic!("Heap corruption: overrun past block at {:p} (size {}), {} bytes after the end.", user, size, i);
+    }
+
+    ptr::write_bytes(user, FREE_POISON_BYTE, size);
+    *field(user, 16) = STATE_FREED;
+    block
+}
+
+#[cfg(test)]
+#[repr(C, align(64))]
+struct TestBlock([u8; 256]);
+
+#[test]
+fn test_heap_debug_roundtrip() {
+    let layout = Layout::from_size_align(40, 8).unwrap();
+    assert!(outer_layout(&layout).size() <= 256);
+
+    let mut block = TestBlock([0; 256]);
+    unsafe {
+        let user = arm(block.0.as_mut_ptr(), &layout);
+        *user.offset(39) = 1;
+        assert_eq!(disarm(user, &layout), block.0.as_mut_ptr());
+        assert_eq!(*user, FREE_POISON_BYTE);
+    }
+}
+
+#[test]
+#[should_panic(expected = "overrun")]
+fn test_heap_debug_overrun() {
+    let layout = Layout::from_size_align(40, 8).unwrap();
+    let mut block = TestBlock([0; 256]);
+    unsafe {
+        let user = arm(block.0.as_mut_ptr(), &layout);
+        *user.offset(40) = 1;
+        disarm(user, &layout);
+    }
+}
+
+#[test]
+#[should_panic(expected = "double free")]
+fn test_heap_debug_double_free() {
+    let layout = Layout::from_size_align(40, 64).unwrap();
+    let mut block = TestBlock([0; 256]);
+    unsafe {
+        let user = arm(block.0.as_mut_ptr(), &layout);
+        disarm(user, &layout);
+        disarm(user, &layout);
+    }
+}
+
+#[test]
+#[should_panic(expected = "freed with size")]
+fn test_heap_debug_size_mismatch() {
+    let layout = Layout::from_size_align(40, 8).unwrap();
+    let mut block = TestBlock([0; 256]);
+    unsafe {
+        let user = arm(block.0.as_mut_ptr(), &layout);
+        disarm(user, &Layout::from_size_align(48, 8).unwrap());
+    }
+}
@@ -0,0 +1,229 @@
+use core::mem;
+use spin;
+
+use x86_64::{PhysicalAddress, VirtualAddress};
+use x86_64::registers::control_regs;
+
+use memory::frame::FrameAllocator;
+use memory::paging::{self, L4Table, Mapper, MapError, CacheMode, PAGE_SIZE, ENTRY_COUNT, WRITABLE, GLOBAL, FLAT_MEMORY_START};
+use memory::kernel_stack::KernelStack;
+use platform;
+use relocate;
+use uefi;
+
+// Size of the stack the kernel continues on in the upper half, in pages.
+const KERNEL_STACK_PAGES: usize = 16;
+
+/// Where the kernel image is mapped in the kernel's own tables. The top 2 GiB of the address space,
+/// so that the whole image is reachable with sign-extended 32-bit addresses.
+pub const KERNEL_BASE: usize = 0xffff_ffff_8000_0000;
+
+extern {
+    // Defined by the linker script.
+    static ImageBase: u8;
+    static ImageEnd: u8;
+    static _text: u8;
+    static _etext: u8;
+    static _data: u8;
+    static _edata: u8;
+}
+
+// The L4 table of the kernel's own tables, once active.
+static KERNEL_L4: spin::Once<PhysicalAddress> = spin::Once::new();
+
+/// The master table of the kernel half. Every address space shares its upper half L3 tables.
+pub fn kernel_l4() -> PhysicalAddress {
+    match KERNEL_L4.try() {
+        Some(&l4) => l4,
+        None => panic!("The kernel page tables are not active yet."),
+    }
+}
+
+/// Physical extent of the loaded kernel image. Only valid while the image runs identity mapped.
+pub fn image_range() -> (PhysicalAddress, usize) {
+    unsafe {
+        let start = &ImageBase as *const u8 as usize;
+        let end = &ImageEnd as *const u8 as usize;
+        (PhysicalAddress(start as u64), end - start)
+    }
+}
+
+#[inline]
+fn page_align(offset: usize) -> usize {
+    (offset + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+/// Maps the image at `KERNEL_BASE`, code read-only and executable, `.data` and `.bss` writable
+/// and non-executable, and everything else (headers, rodata, dynamic linking info) read-only and non-executable.
+unsafe fn map_image<A: FrameAllocator>(mapper: &mut Mapper, allocator: &mut A) -> Result<(), MapError> {
+    let (image, image_size) = image_range();
+    let offset_of = |symbol: &u8| symbol as *const u8 as usize - image.0 as usize;
+    let nx = paging::no_execute();
+
+    // Offsets into the image. The linker script page-aligns the start of each section involved.
+    let text = offset_of(&_text);
+    let text_end = page_align(offset_of(&_etext));
+    let data = offset_of(&_data);
+    let data_end = page_align(offset_of(&_edata));
+    let end = page_align(image_size);
+
+    let parts = [
+        (0, text, GLOBAL | nx),
+        (text, text_end, GLOBAL),
+        (text_end, data, GLOBAL | nx),
+        (data, data_end, WRITABLE | GLOBAL | nx),
+        (data_end, end, GLOBAL | nx),
+    ];
+
+    for &(start, end, flags) in parts.iter() {
+        if start < end {
+            let frame = PhysicalAddress(image.0 + start as u64);
+            try!(mapper.map_range(VirtualAddress(KERNEL_BASE + start), frame, end - start, flags, CacheMode::WriteBack, allocator));
+        }
+    }
+
+    Ok(())
+}
+
+/// Maps the RAM described by `map` into the flat map, merging adjacent descriptors so that huge pages can span them.
+/// MMIO and reserved ranges are left out: write-back mappings of devices could be accessed speculatively,
+/// and would alias the UC or WC mappings `map_mmio()` makes of them.
+unsafe fn map_flat_memory<A: FrameAllocator>(mapper: &mut Mapper, map: &uefi::RawMemoryMap, allocator: &mut A) -> Result<(), MapError> {
+    let mut range: Option<(u64, u64)> = None;
+
+    for i in 0..map.len() {
+        let desc = map.get(i);
+        if !desc.is_ram() {
+            continue;
+        }
+
+        range = match range {
+            Some((start, end)) if end == desc.physical_start => Some((start, desc.physical_end())),
+            Some((start, end)) => {
+                try!(paging::map_flat_range(mapper, start, end, allocator));
+                Some((desc.physical_start, desc.physical_end()))
+            },
+            None => Some((desc.physical_start, desc.physical_end())),
+        };
+    }
+
+    match range {
+        Some((start, end)) => paging::map_flat_range(mapper, start, end, allocator),
+        None => Ok(()),
+    }
+}
+
+/// Page tables built from our own frames, independent of the ones UEFI left in CR3.
+///
+/// The upper half holds all RAM at `FLAT_MEMORY_START`, and the kernel image at `KERNEL_BASE`.
+/// There is no identity map of memory, only a temporary one of the kernel image and of the stack,
+/// so that execution can continue right after the switch. It goes away with `drop_identity_mapping()`.
+pub struct KernelTables {
+    l4: PhysicalAddress,
+}
+
+impl KernelTables {
+    /// Must be called while UEFI's identity mapping is active. The tables UEFI uses are not modified,
+    /// so if the boot is aborted before `activate()`, the new tables can simply be forgotten.
+    pub unsafe fn build<A: FrameAllocator>(map: &uefi::RawMemoryMap, stack: (PhysicalAddress, usize), allocator: &mut A) -> Result<KernelTables, MapError> {
+        assert!(!paging::is_flat_map_active());
+
+        let (l4_frame, l4) = match L4Table::create(allocator) {
+            Some(table) => table,
+            None => return Err(MapError::OutOfFrames),
+        };
+
+        // Every upper half entry gets its table right away. Address spaces copy the entries,
+        // so kernel mappings made later show up in all of them.
+        for i in ENTRY_COUNT / 2..ENTRY_COUNT {
+            if l4.next_table_create(i, allocator).is_none() {
+                return Err(MapError::OutOfFrames);
+            }
+        }
+
+        let mut mapper = Mapper::new(l4);
+        try!(map_flat_memory(&mut mapper, map, allocator));
+
+        try!(map_image(&mut mapper, allocator));
+
+        // Temporary, until we run from `KERNEL_BASE`. Necessarily both writable and executable.
+        let (image, image_size) = image_range();
+        let image_size = page_align(image_size);
+        try!(mapper.map_range(VirtualAddress(image.0 as usize), image, image_size, WRITABLE, CacheMode::WriteBack, allocator));
+        let (stack_start, stack_size) = stack;
+        try!(mapper.map_range(VirtualAddress(stack_start.0 as usize), stack_start, stack_size, WRITABLE, CacheMode::WriteBack, allocator));
+
+        Ok(KernelTables { l4: l4_frame })
+    }
+
+    #[inline]
+    pub fn l4_frame(&self) -> PhysicalAddress {
+        self.l4
+    }
+
+    /// Switches to the tables. From here on, physical memory is only accessible through the flat map,
+    /// and the permissions of the image mapping are enforced, in the kernel too.
+    pub unsafe fn activate(&self) {
+        if platform::has_nx() {
+            platform::enable_nx();
+        }
+        paging::init_pat();
+        platform::enable_write_protect();
+
+        control_regs::cr3_write(self.l4);
+        paging::set_flat_map_active();
+        KERNEL_L4.call_once(|| self.l4);
+
+        // PCID 0 is the kernel's, which is what CR3 holds now.
+        if platform::has_pcid() {
+            platform::enable_pcid();
+        }
+    }
+}
+
+/// Moves execution to the kernel image mapping at `KERNEL_BASE`.
+/// Must be called on the kernel's own tables, while running from the temporary identity mapping.
+///
+/// All absolute addresses in the image are relocated again for the new base, then `continuation`
+/// is called through its upper half address, on a freshly allocated, guard-paged stack.
+pub unsafe fn enter_higher_half(ldbase: u64, dyn: *const u8, continuation: extern "C" fn() -> !) -> ! {
+    assert!(paging::is_flat_map_active());
+
+    let offset = (KERNEL_BASE as u64).wrapping_sub(ldbase);
+
+    // From here on, pointers stored in the image point to the upper half, while the code still runs below.
+    // Both mappings are backed by the same frames, so it doesn't matter which one is used.
+    let size = image_range().1 as u64;
+    if let Err(err) = relocate::relocate(KERNEL_BASE as u64, size, (dyn as u64).wrapping_add(offset) as *const u8) {
+        panic!("Failed to relocate the kernel to the upper half: {}.", err);
+    }
+
+    // Never freed.
+    let stack = KernelStack::new(KERNEL_STACK_PAGES, "kernel_main").expect("Out of memory for the kernel stack.");
+    let stack_top = stack.top();
+    mem::forget(stack);
+    let entry = (continuation as usize as u64).wrapping_add(offset);
+
+    asm!("mov $0, %rsp
+          xor %ebp, %ebp
+          call *$1
+          ud2"
+         :: "r"(stack_top), "r"(entry) : "memory" : "volatile");
+
+    unreachable!()
+}
+
+/// Removes the temporary identity mapping of the image and of the boot stack, leaving the lower half empty.
+/// Must be called from the upper half, once nothing refers to the low addresses anymore.
+pub unsafe fn drop_identity_mapping() {
+    assert!(platform::stack_pointer() >= FLAT_MEMORY_START, "Still running on the boot stack.");
+
+    let l4 = L4Table::current_mut();
+    for i in 0..256 {
+        // The tables behind the entries come from the early pool, and are simply leaked.
+        l4[i].clear();
+    }
+
+    // None of the dropped mappings are global, so reloading CR3 flushes them all.
+    control_regs::cr3_write(control_regs::cr3());
+}
@@ -0,0 +1,104 @@
+//! Kernel stacks, each one in its own kernel region, so that the guard gap below it catches overflows.
+
+use spin;
+
+use alloc::btree_map::BTreeMap;
+use alloc::vec::Vec;
+
+use x86_64::{PhysicalAddress, VirtualAddress};
+
+use memory::frame::FrameAllocator;
+use memory::paging::{self, MapError, PAGE_SIZE, WRITABLE, GLOBAL};
+use memory::physical::SystemFrames;
+use memory::virtual_region::{self, GUARD_SIZE};
+
+// Bottom of every live stack -> its owner, for the fault handlers.
+static STACKS: spin::Mutex<Option<BTreeMap<usize, &'static str>>> = spin::Mutex::new(None);
+
+pub struct KernelStack {
+    bottom: VirtualAddress,
+    frames: Vec<PhysicalAddress>,
+    owner: &'static str,
+}
+
+fn free_frames(frames: &[PhysicalAddress]) {
+    for &frame in frames {
+        SystemFrames.free_frames(frame, 0);
+    }
+}
+
+impl KernelStack {
+    /// Maps a stack of `pages` pages. The owner is what overflows are reported as.
+    pub fn new(pages: usize, owner: &'static str) -> Result<KernelStack, MapError> {
+        let mut frames = Vec::with_capacity(pages);
+        for _ in 0..pages {
+            match SystemFrames.alloc_frames(0) {
+                Some(frame) => frames.push(frame),
+                None => {
+                    free_frames(&frames);
+                    return Err(MapError::OutOfFrames);
+                }
+            }
+        }
+
+        let bottom = match unsafe { virtual_region::vmap(&frames, WRITABLE | GLOBAL | paging::no_execute()) } {
+            Ok(bottom) => bottom,
+            Err(err) => {
+                free_frames(&frames);
+                return Err(err);
+            }
+        };
+
+        let mut stacks = STACKS.lock();
+        if stacks.is_none() {
+            *stacks = Some(BTreeMap::new());
+        }
+        if let Some(ref mut stacks) = *stacks {
+            stacks.insert(bottom.0, owner);
+        }
+
+        Ok(KernelStack { bottom: bottom, frames: frames, owner: owner })
+    }
+
+    /// Initial stack pointer.
+    #[inline]
+    pub fn top(&self) -> usize {
+        self.bottom.0 + self.frames.len() * PAGE_SIZE
+    }
+
+    #[inline]
+    pub fn owner(&self) -> &'static str {
+        self.owner
+    }
+}
+
+impl Drop for KernelStack {
+    /// Must not be running on the stack, obviously.
+    fn drop(&mut self) {
+        if let Some(ref mut stacks) = *STACKS.lock() {
+            stacks.remove(&self.bottom.0);
+        }
+
+        unsafe { virtual_region::vunmap(self.bottom); }
+        free_frames(&self.frames);
+    }
+}
+
+/// If `address` is in the guard gap below a kernel stack, returns the stack's owner.
+/// Meant for fault handlers, so it gives up rather than wait for the lock.
+pub fn guard_owner(address: VirtualAddress) -> Option<&'static str> {
+    let stacks = match STACKS.try_lock() {
+        Some(stacks) => stacks,
+        None => return None,
+    };
+
+    let above = match *stacks {
+        Some(ref stacks) => stacks.range(address.0..).next().map(|(&bottom, &owner)| (bottom, owner)),
+        None => None,
+    };
+
+    match above {
+        Some((bottom, owner)) if bottom - GUARD_SIZE <= address.0 && address.0 < bottom => Some(owner),
+        _ => None,
+    }
+}
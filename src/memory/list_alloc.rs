@@ -1,11 +1,13 @@
 use core::mem;
 use core::ptr::Unique;
 use core::cmp;
-use core::marker::PhantomData;
 
 use alloc::allocator::{Alloc, Layout, AllocErr};
 use memory::paging::FLAT_MEMORY_START;
 
+// Same algorithm as `list_alloc_simple`, but the lists are expressed with owned `Span`s
+// instead of raw pointers, so that the borrow checker keeps an eye on the list manipulation.
+
 struct Span(Unique<SpanHeader>);
 struct SpanList(Option<Span>);
 struct SpanListLen {
@@ -26,7 +28,7 @@ impl Span {
     unsafe fn new(address: usize, size: usize) -> Span {
         debug_assert!(size & MARK == 0);
 
-        let new_span = Span(Unique::new(address as *mut SpanHeader));
+        let mut new_span = Span(Unique::new(address as *mut SpanHeader));
         *(new_span.header_mut()) = SpanHeader { size: size, tail: SpanList(None) };
         new_span
     }
@@ -37,8 +39,8 @@ impl Span {
     }
 
     #[inline]
-    unsafe fn header_mut(&mut self) -> &mut SpanHeader {
-        self.0.as_mut()
+    fn header_mut(&mut self) -> &mut SpanHeader {
+        unsafe{self.0.as_mut()}
     }
 
     #[inline]
@@ -52,23 +54,19 @@ impl Span {
     }
 
     #[inline]
-    unsafe fn set_size(&mut self, size: usize) {
+    fn set_size(&mut self, size: usize) {
         debug_assert!(size & MARK == 0);
         self.header_mut().size = size;
     }
 
     #[inline]
     fn mark(&mut self) {
-        unsafe {
-            self.header_mut().size |= MARK;
-        }
+        self.header_mut().size |= MARK;
     }
 
     #[inline]
     fn unmark(&mut self) {
-        unsafe {
-            self.header_mut().size &= !MARK;
-        }
+        self.header_mut().size &= !MARK;
     }
 
     #[inline]
@@ -91,23 +89,10 @@ impl Span {
         self.limit() == next.address()
     }
 
-    #[inline]
-    fn skip(&self, n: usize) -> Option<&Span> {
-        let mut s = self;
-        for i in 0..n {
-            match s.next() {
-                None => return None,
-                Some(t) => s = t,
-            }
-        }
-
-        Some(s)
-    }
-
     #[inline]
     fn skip_mut(&mut self, n: usize) -> Option<&mut Span> {
         let mut s = self;
-        for i in 0..n {
+        for _ in 0..n {
             match s.next_mut() {
                 None => return None,
                 Some(t) => s = t,
@@ -124,16 +109,18 @@ impl Span {
 
     #[inline]
     fn next_mut(&mut self) -> Option<&mut Span> {
-        unsafe { self.header_mut().tail.first_mut() }
+        self.header_mut().tail.first_mut()
     }
 
+    /// Absorbs an adjacent span that follows this one.
     #[inline]
-    fn last_mut(&mut self) -> &mut Span {
-        let mut s = self;
-        while let Some(n) = s.next_mut() {
-            s = n;
-        }
-        s
+    fn extend(&mut self, next: Span) {
+        debug_assert!(self.is_adjacent(&next));
+        debug_assert!(next.next().is_none());
+
+        let size = self.size() + next.size();
+        self.set_size(size);
+        mem::forget(next);
     }
 
     #[inline]
@@ -145,7 +132,7 @@ impl Span {
         let back_size = self.size() - front_size;
 
         self.set_size(front_size);
-        Span::new(address, back_size)
+        unsafe { Span::new(address, back_size) }
     }
 
     #[inline]
@@ -179,13 +166,37 @@ impl SpanList {
         self.0.as_mut()
     }
 
+    #[inline]
+    fn first_address(&self) -> usize {
+        self.first().map_or(0, |span| span.address())
+    }
+
+    #[inline]
+    fn push(&mut self, mut span: Span) {
+        debug_assert!(span.next().is_none());
+
+        span.header_mut().tail = self.take();
+        self.0 = Some(span);
+    }
+
+    #[inline]
+    fn pop(&mut self) -> Option<Span> {
+        let mut first = self.take();
+
+        if let Some(ref mut span) = first.0 {
+            *self = span.header_mut().tail.take();
+        }
+
+        first.0
+    }
+
     fn count(&self) -> usize {
-        let mut iter: Option<&mut Span> = self.0.as_mut();
+        let mut iter = self.first();
         let mut cnt = 0;
 
         while let Some(span) = iter {
             cnt += 1;
-            iter = span.next_mut();
+            iter = span.next();
         }
 
         cnt
@@ -216,28 +227,69 @@ impl SpanList {
 
     #[inline]
     fn take(&mut self) -> SpanList {
-        let nlist = SpanList(self.0);
-        self.0 = None;
-        nlist
+        SpanList(self.0.take())
     }
 }
 
 impl SpanListLen {
+    #[inline]
+    const fn new() -> SpanListLen {
+        SpanListLen { list: SpanList::new(), length: 0 }
+    }
+
+    #[inline]
+    fn empty(&self) -> bool {
+        self.list.empty()
+    }
+
+    #[inline]
+    fn first(&self) -> Option<&Span> {
+        self.list.first()
+    }
+
+    #[inline]
+    fn first_address(&self) -> usize {
+        self.list.first_address()
+    }
+
+    #[inline]
+    fn push(&mut self, span: Span) {
+        self.list.push(span);
+        self.length += 1;
+    }
+
+    #[inline]
+    fn pop(&mut self) -> Option<Span> {
+        let span = self.list.pop();
+        if span.is_some() {
+            self.length -= 1;
+        }
+        span
+    }
 
     #[inline]
     fn take(&mut self) -> SpanListLen {
-        let nlist = SpanListLen { list: self.list.take(), size: self.size };
-        self.size = 0;
+        let nlist = SpanListLen { list: self.list.take(), length: self.length };
+        self.length = 0;
 
         nlist
     }
 
+    #[inline]
+    fn len(&self) -> usize {
+        self.length
+    }
+
     fn check_length(&self) -> bool {
         assert!(self.list.count() == self.length);
         true
     }
 
-    fn split_at(self, at: usize) -> (SpanListLen, SpanListLen) {
+    fn check_links(&mut self) -> bool {
+        self.list.check_links()
+    }
+
+    fn split_at(mut self, at: usize) -> (SpanListLen, SpanListLen) {
         assert!(at <= self.len());
 
         if at == 0 {
@@ -257,118 +309,91 @@ impl SpanListLen {
         (self, second)
     }
 
-    fn merge(a: SpanListLen, b: SpanListLen) -> SpanListLen {
-        unimplemented!()
-
-        // TODO
-        /*
-        let mut dummy = SegmentHeader{size:0, next:None};
-        let mut out_list = SpanListLen{
-            list: SpanList(Some(Span::new(&mut dummy as usize, mem::size_of_val(&dummy))),
-            length: 1,
-        };
-
-        let mut length = 0;
-
-        {
-            let mut tail = out_list.first_mut().unwrap();
-
-            let mut a = a;
-            let mut b = b;
-
-            loop {
-                if a.empty() {
-                    mem::swap(&mut a, &mut b);
-                }
+    /// Unlinks the span at position `index`.
+    fn remove(&mut self, index: usize) -> Span {
+        assert!(index < self.length);
 
-                if a.empty() {
-                    break;
-                }
+        self.length -= 1;
+        if index == 0 {
+            return self.list.pop().unwrap();
+        }
 
-                if b.empty() {
-                    if let SpanList(Some(span)) = a {
-                        if tail.is_adjacent(&span) {
-                        } else {
+        let previous = self.list.first_mut().unwrap().skip_mut(index - 1).unwrap();
+        previous.header_mut().tail.pop().unwrap()
+    }
 
-                        }
-                    } else {
-                        unreachable!()
-                    }
+    /// Merges two address-ordered lists, coalescing adjacent spans.
+    fn merge(a: SpanListLen, b: SpanListLen) -> SpanListLen {
+        let mut a = a;
+        let mut b = b;
 
+        // Spans are collected in reverse, so that the last one is always at hand for coalescing.
+        let mut reversed = SpanListLen::new();
 
-                    let s = a.pop().unwrap();
-                    if tail.is_adjacent(&s) {
-                        tail.extend_with(s);
-                    } else {
-                        tail.header_mut().tail = SpanList(Some(s));
-                        length += 1;
-                    }
-
-                    let SomeList(
-                }
+        loop {
+            if a.empty() || (!b.empty() && a.first_address() > b.first_address()) {
+                mem::swap(&mut a, &mut b);
+            }
 
-                match (a.first(), b.first()) {
-                    (None, None) => {
-                        break;
-                    },
-                    (Some(s1), None) => {
-                        if tail.is_adjacent(s1) {
-                            tail.extend(s1);
-                        } else {
-                            tail.as_mut().next = Some(s1);
-                        }
+            let span = match a.pop() {
+                Some(span) => span,
+                None => break,
+            };
 
-                        return head.next;
-                    },
-                    (Some(s1), Some(s2)) => {
-                        debug_assert!(!s1.as_ref().overlaps(s2.as_ref()));
+            debug_assert!(b.first().map_or(true, |other| !span.overlaps(other)));
 
-                        let s = if s1.as_ref().address() < s2.as_ref().address() { l1 = s1.as_ref().next; s1 } else { l2 = s2.as_ref().next; s2 };
+            let adjacent = match reversed.list.first() {
+                Some(last) => {
+                    assert!(last.limit() <= span.address());
+                    last.is_adjacent(&span)
+                },
+                None => false,
+            };
 
-                        if tail.as_ref().is_adjacent(s.as_ref()) {
-                            tail.as_mut().extend(s.as_ref());
-                        } else {
-                            tail.as_mut().next = Some(s);
-                            tail = s;
-                        }
-                    },
-                    _ => unreachable!()
-                }
+            if adjacent {
+                reversed.list.first_mut().unwrap().extend(span);
+            } else {
+                reversed.push(span);
             }
-            */
-    }
+        }
 
-    fn len(&self) -> usize {
-        self.length
+        let mut list = SpanListLen::new();
+        while let Some(span) = reversed.pop() {
+            list.push(span);
+        }
+
+        debug_assert!(list.check_length());
+        debug_assert!(list.check_links());
+        list
     }
 
     fn sort(&mut self) {
-        unimplemented!()
-    }
-/*
-        unsafe fn sort_list(l: Option<Unique<SegmentHeader>>, len: usize) -> Option<Unique<SegmentHeader>> {
-        if len <= 1 {
-            return l;
+        if self.length <= 1 {
+            return;
         }
 
-        let len1 = len / 2;
-        let len2 = len - len1;
+        let len = self.length;
+        let (mut first, mut second) = self.take().split_at(len / 2);
 
-        let (l1, l2) = Self::split_list(l, len1);
-        let l1 = Self::sort_list(l1, len1);
-        let l2 = Self::sort_list(l2, len2);
-        Self::merge_lists(l1, l2)
+        first.sort();
+        second.sort();
+
+        *self = Self::merge(first, second);
     }
-*/
 }
 
 #[test]
-fn test_segment_header_size() {
-    assert_eq!(mem::size_of::<SegmentHeader>(), 2*mem::size_of::<usize>());
+fn test_span_header_size() {
+    assert_eq!(mem::size_of::<SpanHeader>(), 2*mem::size_of::<usize>());
 }
 
+// Free spans are kept in segregated lists by size, as in `list_alloc_simple`.
+const BIN_COUNT: usize = 16;
+
 pub struct ListAlloc {
-    free_list: SpanListLen,
+    bins: [SpanListLen; BIN_COUNT],
+    // Bit `i` is set iff `bins[i]` is not empty.
+    nonempty_bins: usize,
     garbage_list: SpanListLen,
     garbage_limit: usize,
 
@@ -378,11 +403,19 @@ pub struct ListAlloc {
     current_garbage_bytes: usize,
 }
 
+unsafe impl Send for ListAlloc {}
+
 impl ListAlloc {
     #[inline]
     pub const fn new() -> Self {
         Self {
-            free_list: SpanListLen::new(), garbage_list: SpanListLen::new(),
+            bins: [
+                SpanListLen::new(), SpanListLen::new(), SpanListLen::new(), SpanListLen::new(),
+                SpanListLen::new(), SpanListLen::new(), SpanListLen::new(), SpanListLen::new(),
+                SpanListLen::new(), SpanListLen::new(), SpanListLen::new(), SpanListLen::new(),
+                SpanListLen::new(), SpanListLen::new(), SpanListLen::new(), SpanListLen::new(),
+            ],
+            nonempty_bins: 0, garbage_list: SpanListLen::new(),
             garbage_limit: 1024, total_bytes: 0, current_allocated_bytes: 0,
             current_free_bytes: 0, current_garbage_bytes: 0
         }
@@ -404,34 +437,89 @@ impl ListAlloc {
         (((val-1)/align)+1)*align
     }
 
-    unsafe fn search_free_list(&mut self, size: usize, align: usize) -> bool {
-        loop {
-            if let Some(span) = self.free_list.first() {
-                let required_top = Self::align_up(span.address(), align) + size;
-                if required_top <= span.limit() {
-                    return true;
+    // log2(size / 16), clamped to the last bin.
+    #[inline]
+    fn bin_of(size: usize) -> usize {
+        let bin = mem::size_of::<usize>() * 8 - 1 - (size / mem::size_of::<SpanHeader>()).leading_zeros() as usize;
+        cmp::min(bin, BIN_COUNT - 1)
+    }
+
+    #[inline]
+    fn bin_push(&mut self, span: Span) {
+        let bin = Self::bin_of(span.size());
+        self.bins[bin].push(span);
+        self.nonempty_bins |= 1 << bin;
+    }
+
+    #[inline]
+    fn bin_remove(&mut self, bin: usize, index: usize) -> Span {
+        let span = self.bins[bin].remove(index);
+        if self.bins[bin].empty() {
+            self.nonempty_bins &= !(1 << bin);
+        }
+        span
+    }
+
+    // Looks for the smallest span that can hold `size` bytes at the given alignment,
+    // and returns its bin and its position in there. See `list_alloc_simple::ListAlloc::find_best_fit()`.
+    fn find_best_fit(&self, size: usize, align: usize) -> Option<(usize, usize)> {
+        let mut bin = Self::bin_of(size);
+
+        while bin < BIN_COUNT {
+            let remaining = self.nonempty_bins >> bin;
+            if remaining == 0 {
+                return None;
+            }
+            bin += remaining.trailing_zeros() as usize;
+
+            let mut best = None;
+            let mut best_size = usize::max_value();
+            let mut iter = self.bins[bin].first();
+            let mut index = 0;
+
+            while let Some(span) = iter {
+                let required = Self::align_up(span.address(), align) - span.address() + size;
+
+                if required <= span.size() && span.size() < best_size {
+                    best = Some(index);
+                    best_size = span.size();
+
+                    if required == best_size {
+                        break;
+                    }
                 }
-            } else {
-                return false;
+
+                iter = span.next();
+                index += 1;
             }
 
-            self.garbage_list.push(self.free_list.pop().unwrap());
+            if let Some(index) = best {
+                return Some((bin, index));
+            }
+
+            bin += 1;
         }
+
+        None
     }
 
     pub unsafe fn alloc(&mut self, layout: Layout) -> Result<*mut u8, AllocErr> {
         let size = Self::align_up(layout.size(), mem::size_of::<SpanHeader>());
         let align = cmp::max(layout.align(), mem::size_of::<SpanHeader>());
 
-        if !self.search_free_list(size, align) {
-            self.gc();
+        let (bin, index) = match self.find_best_fit(size, align) {
+            Some(found) => found,
+            None => {
+                self.gc();
 
-            if !self.search_free_list(size, align) {
-                return Err(AllocErr::Exhausted{request: layout});
+                match self.find_best_fit(size, align) {
+                    Some(found) => found,
+                    None => return Err(AllocErr::Exhausted{request: layout}),
+                }
             }
-        }
+        };
 
-        let mut span = self.free_list.pop().unwrap();
+        let mut span = self.bin_remove(bin, index);
 
         let alloc_start = Self::align_up(span.address(), align);
         let alloc_end = alloc_start + size;
@@ -440,12 +528,12 @@ impl ListAlloc {
 
         if alloc_end < span.limit() {
             let nspan = span.split_at(alloc_end);
-            self.free_list.push(nspan);
+            self.bin_push(nspan);
         }
 
         if alloc_start > span.address() {
             let nspan = span.split_at(alloc_start);
-            self.free_list.push(span);
+            self.bin_push(span);
             span = nspan;
         }
 
@@ -474,11 +562,23 @@ impl ListAlloc {
         }
     }
 
-    unsafe fn gc(&mut self) {
-        let garbage = self.garbage_list.take();
-        garbage.sort();
+    fn gc(&mut self) {
+        let mut all = self.garbage_list.take();
+
+        for bin in 0..BIN_COUNT {
+            let mut list = self.bins[bin].take();
+            while let Some(span) = list.pop() {
+                all.push(span);
+            }
+        }
+        self.nonempty_bins = 0;
+
+        // Sorting merges adjacent spans as well.
+        all.sort();
 
-        self.free_list = SpanListLen::merge(self.free_list, garbage);
+        while let Some(span) = all.pop() {
+            self.bin_push(span);
+        }
 
         self.current_free_bytes += self.current_garbage_bytes;
         self.current_garbage_bytes = 0;
@@ -490,27 +590,29 @@ impl ListAlloc {
         // Basics.
         assert_eq!(self.total_bytes, self.current_allocated_bytes + self.current_free_bytes + self.current_garbage_bytes);
 
-        // Verify free list.
-            assert!(self.free_list.check_length());
-            assert!(self.free_list.check_links());
-
+        // Verify bins.
             let mut counted_bytes: usize = 0;
-            let mut previous_limit: usize = 0;
-            let mut head = self.free_list.first();
 
-            while let Some(span) = head {
-                assert!(previous_limit == 0 || previous_limit < span.address());
-                counted_bytes += span.size();
-                previous_limit = span.limit();
-                head = span.next();
+            for bin in 0..BIN_COUNT {
+                assert!(self.bins[bin].check_length());
+                assert!(self.bins[bin].check_links());
+                assert_eq!(self.bins[bin].empty(), self.nonempty_bins & (1 << bin) == 0);
+
+                let mut head = self.bins[bin].first();
+
+                while let Some(span) = head {
+                    assert_eq!(Self::bin_of(span.size()), bin);
+                    counted_bytes += span.size();
+                    head = span.next();
+                }
             }
 
             assert_eq!(self.current_free_bytes, counted_bytes);
 
 
             // Verify garbage list as well as we are able.
-            assert!(self.free_list.check_length());
-            assert!(self.free_list.check_links());
+            assert!(self.garbage_list.check_length());
+            assert!(self.garbage_list.check_links());
 
             let mut counted_bytes: usize = 0;
             let mut head = self.garbage_list.first();
@@ -552,3 +654,100 @@ unsafe impl efi_app::FrontAllocator for ListAlloc {
         ListAlloc::provide(self, (FLAT_MEMORY_START + addr.0 as usize) as *mut u8, size)
     }
 }
+
+
+// Both list allocators run the same algorithm, so given identical memory and identical
+// sequences of requests, they must hand out identical addresses.
+
+#[cfg(test)]
+#[repr(C, align(4096))]
+struct TestArena([u8; 64 * 1024]);
+
+#[cfg(test)]
+struct TestRng(u64);
+
+#[cfg(test)]
+impl TestRng {
+    fn next(&mut self) -> u64 {
+        // xorshift64
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+#[cfg(test)]
+fn run_trace_against_both(seed: u64, steps: usize) {
+    use memory::list_alloc_simple;
+
+    const SLOTS: usize = 64;
+
+    let mut arena_a = TestArena([0; 64 * 1024]);
+    let mut arena_b = TestArena([0; 64 * 1024]);
+    let base_a = arena_a.0.as_mut_ptr();
+    let base_b = arena_b.0.as_mut_ptr();
+
+    let mut a = ListAlloc::new();
+    let mut b = list_alloc_simple::ListAlloc::new();
+
+    unsafe {
+        a.provide(base_a, 64 * 1024);
+        b.provide(base_b, 64 * 1024);
+    }
+
+    // (offset, size, align) of the live blocks.
+    let mut live: [Option<(usize, usize, usize)>; SLOTS] = [None; SLOTS];
+    let mut rng = TestRng(seed);
+
+    for _ in 0..steps {
+        let slot = (rng.next() % SLOTS as u64) as usize;
+
+        match live[slot] {
+            Some((offset, size, align)) => unsafe {
+                let layout = Layout::from_size_align(size, align).unwrap();
+
+                // Check that nobody scribbled over the block in the meantime.
+                for i in 0..layout.size() {
+                    assert_eq!(*base_a.offset((offset + i) as isize), slot as u8);
+                    assert_eq!(*base_b.offset((offset + i) as isize), slot as u8);
+                }
+
+                a.dealloc(base_a.offset(offset as isize), layout.clone());
+                b.dealloc(base_b.offset(offset as isize), layout);
+                live[slot] = None;
+            },
+            None => unsafe {
+                let size = 1 + (rng.next() % 2048) as usize;
+                let align = 1 << (rng.next() % 8);
+                let layout = Layout::from_size_align(size, align).unwrap();
+
+                match (a.alloc(layout.clone()), b.alloc(layout.clone())) {
+                    (Ok(pa), Ok(pb)) => {
+                        let offset = pa as usize - base_a as usize;
+                        assert_eq!(offset, pb as usize - base_b as usize);
+                        assert_eq!(pa as usize % align, 0);
+
+                        for i in 0..size {
+                            *pa.offset(i as isize) = slot as u8;
+                            *pb.offset(i as isize) = slot as u8;
+                        }
+
+                        live[slot] = Some((offset, size, align));
+                    },
+                    (Err(_), Err(_)) => {},
+                    _ => panic!("List allocators disagree on exhaustion."),
+                }
+            },
+        }
+    }
+
+    assert_eq!(a.current_allocated_bytes + a.current_free_bytes + a.current_garbage_bytes, 64 * 1024);
+}
+
+#[test]
+fn test_list_alloc_matches_simple() {
+    for seed in 1..32_u64 {
+        run_trace_against_both(seed.wrapping_mul(0x9e3779b97f4a7c15), 4000);
+    }
+}
@@ -0,0 +1,429 @@
+// Host benchmarks comparing allocation strategies: first fit over a single address-ordered list
+// (`baseline` below, the allocator before size bins), against best fit over segregated bins
+// (`list_alloc_simple`). Both run identical random traces over the same arena. Run with `cargo bench`.
+
+use spin;
+use test::{self, Bencher};
+
+use alloc::allocator::{Alloc, AllocErr, Layout};
+
+use memory::list_alloc_simple;
+
+const ARENA_SIZE: usize = 4 * 1024 * 1024;
+const SLOTS: usize = 512;
+const STEPS: usize = 20_000;
+
+#[repr(C, align(4096))]
+struct Arena([u8; ARENA_SIZE]);
+
+// Shared by all benchmarks, which may run on multiple threads in `cargo test`.
+static ARENA: spin::Mutex<Arena> = spin::Mutex::new(Arena([0; ARENA_SIZE]));
+
+struct Workload {
+    min_size: usize,
+    max_size: usize,
+    max_align: usize,
+    // Percentage of steps that allocate, while there is a free slot.
+    alloc_percent: u64,
+}
+
+// Many small blocks, as the heap sees them when slabs are bypassed.
+const SMALL: Workload = Workload { min_size: 16, max_size: 512, max_align: 16, alloc_percent: 50 };
+// Sizes across several orders of magnitude.
+const MIXED: Workload = Workload { min_size: 16, max_size: 64 * 1024, max_align: 64, alloc_percent: 50 };
+// Large, page-aligned blocks with the heap close to full. This is where first fit thrashes.
+const LARGE: Workload = Workload { min_size: 4096, max_size: 256 * 1024, max_align: 4096, alloc_percent: 60 };
+
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        // xorshift64
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+// The baseline: `list_alloc_simple` as it was before the free spans were split into size bins,
+// copied verbatim apart from the statistics,
+// the consistency checks and one host fix in `merge`. There is a single address-ordered free list, and spans
+// at its front that are too small for a request are moved to the garbage list, so a search is first fit
+// that consumes the list. Once it runs dry, a full sort and merge of all garbage rebuilds it.
+mod baseline {
+    use core::mem;
+    use core::ptr::null_mut;
+    use core::cmp;
+
+    use alloc::allocator::{Alloc, Layout, AllocErr};
+
+    macro_rules! d {
+        ($expression:expr) => ({
+                debug_assert!($expression != null_mut());
+                (&mut *$expression)
+        })
+    }
+
+    struct SpanList {
+        first: *mut SpanHeader,
+        length: usize,
+    }
+
+    impl SpanList {
+        #[inline]
+        const fn new() -> SpanList {
+            SpanList { first: null_mut(), length: 0 }
+        }
+
+        #[inline]
+        fn empty(&self) -> bool {
+            self.first == null_mut()
+        }
+
+        #[inline]
+        unsafe fn push(&mut self, entry: *mut SpanHeader) {
+            assert!(entry != null_mut());
+
+            d!(entry).next = self.first;
+            self.first = entry;
+            self.length += 1;
+        }
+
+        #[inline]
+        unsafe fn pop(&mut self) -> *mut SpanHeader {
+            assert!(self.first != null_mut());
+
+            self.length -= 1;
+
+            let hdr = self.first;
+            self.first = d!(hdr).next;
+            d!(hdr).next = null_mut();
+            hdr
+        }
+
+        #[inline]
+        fn first_address(&self) -> usize {
+            self.first as usize
+        }
+
+        #[inline]
+        fn len(&self) -> usize {
+            self.length
+        }
+
+        #[inline]
+        fn take(&mut self) -> SpanList {
+            let mut nlist = SpanList::new();
+            mem::swap(self, &mut nlist);
+            nlist
+        }
+
+        unsafe fn merge(mut a: SpanList, mut b: SpanList) -> SpanList {
+            if a.length == 0 && b.length == 0 {
+                return a;
+            }
+
+            // Unlike the original, don't compare the dummy head against spans. It lives on the stack,
+            // which on the host may well be above the arena.
+            let mut head = SpanHeader{size: 0, next: null_mut()};
+            let head_ptr: *mut SpanHeader = &mut head;
+            let mut tail = head_ptr;
+            let mut length = 0;
+
+            loop {
+                if a.first_address() > b.first_address() {
+                    mem::swap(&mut a, &mut b);
+                }
+
+                assert!(!b.empty());
+
+                if a.empty() {
+                    d!(tail).next = b.first;
+                    length += b.length;
+                    break;
+                }
+
+                let hdr = a.pop();
+
+                assert!(tail == head_ptr || d!(tail).limit() <= d!(hdr).address());
+
+                if tail != head_ptr && d!(tail).limit() == d!(hdr).address() {
+                    d!(tail).size += d!(hdr).size;
+                } else {
+                    d!(tail).next = hdr;
+                    tail = hdr;
+                    length += 1;
+                }
+            }
+
+            SpanList{ first: head.next, length: length }
+        }
+
+        unsafe fn split_off(&mut self, n: usize) -> SpanList {
+            assert!(self.length > n);
+
+            let other_len = self.length - n;
+            self.length = n;
+
+            let mut hdr = self.first;
+            for _ in 1..n {
+                hdr = d!(hdr).next;
+            }
+
+            let list = SpanList{ first: d!(hdr).next, length: other_len };
+            d!(hdr).next = null_mut();
+            list
+        }
+
+        unsafe fn sort(&mut self) {
+            if self.length <= 1 {
+                return;
+            }
+
+            let len = self.length;
+            let mut other = self.split_off(len / 2);
+
+            self.sort();
+            other.sort();
+
+            *self = Self::merge(self.take(), other);
+        }
+    }
+
+    #[repr(C)]
+    struct SpanHeader {
+        size: usize,
+        next: *mut SpanHeader,
+    }
+
+    impl SpanHeader {
+        #[inline]
+        fn address(&self) -> usize {
+            self as *const SpanHeader as usize
+        }
+
+        #[inline]
+        fn limit(&self) -> usize {
+            self.address() + self.size
+        }
+
+        #[inline]
+        unsafe fn split_at(&mut self, address: usize) -> *mut SpanHeader {
+            assert!(address >= self.address() + mem::size_of::<SpanHeader>());
+            assert!(address <= self.limit() - mem::size_of::<SpanHeader>());
+
+            let front_size = address - self.address();
+            let back_size = self.size - front_size;
+
+            self.size = front_size;
+            let span = address as *mut SpanHeader;
+            *span = SpanHeader { size: back_size, next: null_mut() };
+            span
+        }
+    }
+
+    pub struct ListAlloc {
+        free_list: SpanList,
+        garbage_list: SpanList,
+        garbage_limit: usize,
+    }
+
+    impl ListAlloc {
+        #[inline]
+        pub const fn new() -> Self {
+            Self { free_list: SpanList::new(), garbage_list: SpanList::new(), garbage_limit: 1024 }
+        }
+
+        pub unsafe fn provide(&mut self, ptr: *mut u8, size: usize) {
+            assert!((ptr as usize) % mem::size_of::<SpanHeader>() == 0);
+            assert!(size % mem::size_of::<SpanHeader>() == 0);
+
+            self.dealloc(ptr, Layout::array::<u8>(size).unwrap());
+        }
+
+        #[inline]
+        fn align_up(val: usize, align: usize) -> usize {
+            debug_assert!(align.is_power_of_two());
+
+            (((val-1)/align)+1)*align
+        }
+
+        unsafe fn search_free_list(&mut self, size: usize, align: usize) -> bool {
+            while !self.free_list.empty() {
+                let address = self.free_list.first as usize;
+                let limit = address + d!(self.free_list.first).size;
+
+                let required_top = Self::align_up(address, align) + size;
+                if required_top <= limit {
+                    return true;
+                }
+
+                self.garbage_list.push(self.free_list.pop());
+            }
+
+            false
+        }
+
+        unsafe fn gc(&mut self) {
+            let mut garbage = self.garbage_list.take();
+            garbage.sort();
+
+            self.free_list = SpanList::merge(self.free_list.take(), garbage);
+        }
+    }
+
+    unsafe impl Alloc for ListAlloc {
+        unsafe fn alloc(&mut self, layout: Layout) -> Result<*mut u8, AllocErr> {
+            let size = Self::align_up(layout.size(), mem::size_of::<SpanHeader>());
+            let align = cmp::max(layout.align(), mem::size_of::<SpanHeader>());
+
+            if !self.search_free_list(size, align) {
+                self.gc();
+
+                if !self.search_free_list(size, align) {
+                    return Err(AllocErr::Exhausted{request: layout});
+                }
+            }
+
+            let mut span = self.free_list.pop();
+
+            let alloc_start = Self::align_up(span as usize, align);
+            let alloc_end = alloc_start + size;
+
+            assert!(alloc_end <= d!(span).limit());
+
+            if alloc_end < d!(span).limit() {
+                let nspan = d!(span).split_at(alloc_end);
+                self.free_list.push(nspan);
+            }
+
+            if alloc_start > d!(span).address() {
+                let nspan = d!(span).split_at(alloc_start);
+                self.free_list.push(span);
+                span = nspan;
+            }
+
+            Ok(span as *mut u8)
+        }
+
+        unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+            let size = Self::align_up(layout.size(), mem::size_of::<SpanHeader>());
+
+            let span = ptr as *mut SpanHeader;
+            *span = SpanHeader { size: size, next: null_mut() };
+            self.garbage_list.push(span);
+
+            if self.garbage_list.len() >= self.garbage_limit {
+                self.gc();
+            }
+        }
+    }
+}
+
+// Runs one trace, and returns the number of requests that failed.
+unsafe fn run_trace<A: Alloc>(a: &mut A, workload: &Workload) -> usize {
+    let mut live: [Option<(*mut u8, usize, usize)>; SLOTS] = [None; SLOTS];
+    let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+    let mut failed = 0;
+
+    for _ in 0..STEPS {
+        let slot = (rng.next() % SLOTS as u64) as usize;
+        let allocate = rng.next() % 100 < workload.alloc_percent;
+
+        match live[slot] {
+            Some((ptr, size, align)) if !allocate => {
+                a.dealloc(ptr, Layout::from_size_align(size, align).unwrap());
+                live[slot] = None;
+            },
+            None if allocate => {
+                let size = workload.min_size + (rng.next() as usize % (workload.max_size - workload.min_size + 1));
+                let align = 1 << (rng.next() % (workload.max_align.trailing_zeros() as u64 + 1));
+
+                match a.alloc(Layout::from_size_align(size, align).unwrap()) {
+                    Ok(ptr) => live[slot] = Some((ptr, size, align)),
+                    Err(_) => failed += 1,
+                }
+            },
+            _ => {},
+        }
+    }
+
+    for slot in live.iter_mut() {
+        if let Some((ptr, size, align)) = slot.take() {
+            a.dealloc(ptr, Layout::from_size_align(size, align).unwrap());
+        }
+    }
+
+    failed
+}
+
+fn bench_first_fit(b: &mut Bencher, workload: &Workload) {
+    let mut arena = ARENA.lock();
+
+    b.iter(|| unsafe {
+        let mut a = baseline::ListAlloc::new();
+        a.provide(arena.0.as_mut_ptr(), ARENA_SIZE);
+        test::black_box(run_trace(&mut a, workload))
+    });
+}
+
+fn bench_segregated(b: &mut Bencher, workload: &Workload) {
+    let mut arena = ARENA.lock();
+
+    b.iter(|| unsafe {
+        let mut a = list_alloc_simple::ListAlloc::new();
+        a.provide(arena.0.as_mut_ptr(), ARENA_SIZE);
+        test::black_box(run_trace(&mut a, workload))
+    });
+}
+
+#[bench]
+fn bench_small_first_fit(b: &mut Bencher) {
+    bench_first_fit(b, &SMALL);
+}
+
+#[bench]
+fn bench_small_segregated(b: &mut Bencher) {
+    bench_segregated(b, &SMALL);
+}
+
+#[bench]
+fn bench_mixed_first_fit(b: &mut Bencher) {
+    bench_first_fit(b, &MIXED);
+}
+
+#[bench]
+fn bench_mixed_segregated(b: &mut Bencher) {
+    bench_segregated(b, &MIXED);
+}
+
+#[bench]
+fn bench_large_first_fit(b: &mut Bencher) {
+    bench_first_fit(b, &LARGE);
+}
+
+#[bench]
+fn bench_large_segregated(b: &mut Bencher) {
+    bench_segregated(b, &LARGE);
+}
+
+// Besides speed, what matters is how often requests fail. In general neither strategy is guaranteed
+// to beat the other, but `list_alloc_simple` can't fail the small workload, as it fully coalesces free
+// spans before giving up: at most SLOTS blocks of at most 512 bytes plus alignment padding are live,
+// which split the rest of the arena into at most SLOTS + 1 free spans. So after a merge, the largest
+// of them is at least (ARENA_SIZE - SLOTS * 528) / (SLOTS + 1), about 7.6K, which fits any request.
+// The baseline gets no such assertion, as its merge can leave neighbouring spans apart.
+#[test]
+fn test_small_workload_never_fails() {
+    let worst_live = SLOTS * (SMALL.max_size + SMALL.max_align);
+    assert!((ARENA_SIZE - worst_live) / (SLOTS + 1) >= SMALL.max_size + SMALL.max_align);
+
+    let mut arena = ARENA.lock();
+
+    unsafe {
+        let mut a = list_alloc_simple::ListAlloc::new();
+        a.provide(arena.0.as_mut_ptr(), ARENA_SIZE);
+        assert_eq!(run_trace(&mut a, &SMALL), 0);
+    }
+}
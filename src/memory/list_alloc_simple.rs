@@ -1,8 +1,9 @@
 use core::mem;
+use core::ptr;
 use core::ptr::null_mut;
 use core::cmp;
 
-use alloc::allocator::{Alloc, Layout, AllocErr};
+use alloc::allocator::{Alloc, Layout, AllocErr, CannotReallocInPlace};
 use memory::paging::FLAT_MEMORY_START;
 
 // A little wrapper for a little checking.
@@ -109,8 +110,10 @@ impl SpanList {
             return a;
         }
 
+        // The dummy head lives on the stack, so it must never be compared against spans.
         let mut head = SpanHeader{size: 0, next: null_mut()};
-        let mut tail: *mut SpanHeader = &mut head;
+        let head_ptr: *mut SpanHeader = &mut head;
+        let mut tail = head_ptr;
         let mut length = 0;
 
 
@@ -122,6 +125,11 @@ impl SpanList {
             assert!(!b.empty());
 
             if a.empty() {
+                if tail != head_ptr && d!(tail).limit() == b.first_address() {
+                    let hdr = b.pop();
+                    d!(tail).size += d!(hdr).size;
+                }
+
                 d!(tail).next = b.first;
                 length += b.length;
                 break;
@@ -129,9 +137,9 @@ impl SpanList {
 
             let hdr = a.pop();
 
-            assert!(d!(tail).limit() <= d!(hdr).address());
+            assert!(tail == head_ptr || d!(tail).limit() <= d!(hdr).address());
 
-            if d!(tail).limit() == d!(hdr).address() {
+            if tail != head_ptr && d!(tail).limit() == d!(hdr).address() {
                 d!(tail).size += d!(hdr).size;
             } else {
                 d!(tail).next = hdr;
@@ -146,6 +154,54 @@ impl SpanList {
         list
     }
 
+    // Removes the span `link` points to, which must be either `self.first` or the `next` of a span in the list.
+    #[inline]
+    unsafe fn unlink(&mut self, link: *mut *mut SpanHeader) -> *mut SpanHeader {
+        let hdr = *link;
+        assert!(hdr != null_mut());
+
+        *link = d!(hdr).next;
+        d!(hdr).next = null_mut();
+        self.length -= 1;
+        hdr
+    }
+
+    // Returns the link pointing to the span starting exactly at `address`, or null.
+    unsafe fn find(&mut self, address: usize) -> *mut *mut SpanHeader {
+        let mut link: *mut *mut SpanHeader = &mut self.first;
+
+        while *link != null_mut() {
+            if d!(*link).address() == address {
+                return link;
+            }
+
+            link = &mut d!(*link).next;
+        }
+
+        null_mut()
+    }
+
+    // Finds the span starting exactly at `address` and cuts `size` bytes off its front.
+    // The rest of the span (if any) stays in place.
+    // Returns false if there is no such span, or if it is too small.
+    unsafe fn take_front(&mut self, address: usize, size: usize) -> bool {
+        let link = self.find(address);
+        if link == null_mut() || d!(*link).size < size {
+            return false;
+        }
+
+        let hdr = *link;
+        if d!(hdr).size == size {
+            self.unlink(link);
+        } else {
+            let rest = d!(hdr).split_at(address + size);
+            d!(rest).next = d!(hdr).next;
+            *link = rest;
+        }
+
+        true
+    }
+
     unsafe fn split_off(&mut self, n: usize) -> SpanList {
         assert!(self.length > n);
 
@@ -231,8 +287,136 @@ fn test_segment_header_size() {
     assert_eq!(mem::size_of::<SpanHeader>(), 2*mem::size_of::<usize>());
 }
 
+#[cfg(test)]
+#[repr(C, align(16))]
+struct TestArena([u8; 4096]);
+
+#[test]
+fn test_grow_and_shrink_in_place() {
+    let mut arena = TestArena([0; 4096]);
+    let base = arena.0.as_mut_ptr();
+    let mut a = ListAlloc::new();
+
+    unsafe {
+        a.provide(base, 4096);
+
+        let p = a.alloc(Layout::from_size_align(64, 16).unwrap()).unwrap();
+        let q = a.alloc(Layout::from_size_align(64, 16).unwrap()).unwrap();
+        assert_eq!(q as usize, p as usize + 64);
+
+        // `p` is followed by `q`, so it can't grow until `q` is gone.
+        assert!(!a.grow_in_place(p, 64, 128));
+        a.dealloc(q, Layout::from_size_align(64, 16).unwrap());
+
+        // The span behind `p` is now in the garbage list.
+        assert!(a.grow_in_place(p, 64, 96));
+        assert!(a.grow_in_place(p, 96, 128));
+        assert_eq!(a.current_allocated_bytes, 128);
+
+        a.shrink_in_place(p, 128, 32);
+        assert_eq!(a.current_allocated_bytes, 32);
+
+        // Grow from the bins, across the span that was just released.
+        a.gc();
+        assert!(a.grow_in_place(p, 32, 1024));
+        assert_eq!(a.current_free_bytes + a.current_garbage_bytes, 4096 - 1024);
+
+        let r = a.realloc(p, Layout::from_size_align(1024, 16).unwrap(), Layout::from_size_align(8192, 16).unwrap());
+        assert!(r.is_err());
+
+        let r = a.realloc(p, Layout::from_size_align(1024, 16).unwrap(), Layout::from_size_align(2048, 16).unwrap());
+        assert_eq!(r.ok(), Some(p));
+    }
+}
+
+#[test]
+fn test_vec_growth_stays_in_place() {
+    let mut arena = TestArena([0; 4096]);
+    let mut a = ListAlloc::new();
+
+    unsafe {
+        a.provide(arena.0.as_mut_ptr(), 4096);
+
+        // Doubling the capacity on push, like `Vec` does, through the `Alloc` interface the heap uses.
+        let mut capacity = 4;
+        let p = Alloc::alloc(&mut a, Layout::array::<u64>(capacity).unwrap()).unwrap();
+        while capacity < 256 {
+            let r = Alloc::realloc(&mut a, p, Layout::array::<u64>(capacity).unwrap(), Layout::array::<u64>(capacity * 2).unwrap());
+            assert_eq!(r.ok(), Some(p));
+            capacity *= 2;
+        }
+        assert_eq!(a.current_allocated_bytes, 256 * 8);
+
+        assert!(Alloc::grow_in_place(&mut a, p, Layout::array::<u64>(256).unwrap(), Layout::array::<u64>(384).unwrap()).is_ok());
+        assert_eq!(a.current_allocated_bytes, 384 * 8);
+    }
+}
+
+#[test]
+fn test_zero_size() {
+    let mut arena = TestArena([0; 4096]);
+    let mut a = ListAlloc::new();
+
+    unsafe {
+        a.provide(arena.0.as_mut_ptr(), 4096);
+
+        // Zero-sized blocks still take a whole span, so they are distinct and can be freed again.
+        let layout = Layout::from_size_align(0, 1).unwrap();
+        let p = a.alloc(layout.clone()).unwrap();
+        let q = a.alloc(layout.clone()).unwrap();
+        assert!(p != q);
+        assert_eq!(a.current_allocated_bytes, 2 * mem::size_of::<SpanHeader>());
+        assert_eq!(Alloc::usable_size(&a, &layout).1, mem::size_of::<SpanHeader>());
+
+        a.dealloc(p, layout.clone());
+        a.dealloc(q, layout);
+        assert_eq!(a.current_allocated_bytes, 0);
+    }
+}
+
+#[test]
+fn test_bin_of() {
+    assert_eq!(ListAlloc::bin_of(0), 0);
+    assert_eq!(ListAlloc::bin_of(15), 0);
+    assert_eq!(ListAlloc::bin_of(16), 0);
+    assert_eq!(ListAlloc::bin_of(31), 0);
+    assert_eq!(ListAlloc::bin_of(32), 1);
+    assert_eq!(ListAlloc::bin_of(16 << (BIN_COUNT - 1)), BIN_COUNT - 1);
+    assert_eq!(ListAlloc::bin_of(usize::max_value()), BIN_COUNT - 1);
+}
+
+// Free spans are kept in segregated lists by size. Bin `i` holds spans of `16 << i` bytes
+// up to twice that, the last bin is open-ended.
+pub const BIN_COUNT: usize = 16;
+pub const SPAN_HISTOGRAM_BUCKETS: usize = BIN_COUNT;
+
+/// Snapshot of the allocator's state.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ListAllocStats {
+    pub total_bytes: usize,
+    pub allocated_bytes: usize,
+    pub free_bytes: usize,
+    pub garbage_bytes: usize,
+    pub peak_allocated_bytes: usize,
+
+    // Fragmentation. Garbage spans are included, since they are free memory too,
+    // just not yet sorted and merged.
+    pub free_spans: usize,
+    pub largest_free_span: usize,
+    // Bucket `i` counts spans of `16 << i` bytes up to twice that, the last bucket is open-ended.
+    pub span_histogram: [usize; SPAN_HISTOGRAM_BUCKETS],
+}
+
+/// General purpose allocator working with spans of free memory.
+///
+/// Freed blocks go to an unsorted garbage list first. Once enough garbage accumulates
+/// (or an allocation can't be satisfied), all free memory is sorted, adjacent spans are merged,
+/// and the result is redistributed into bins by size. Allocation takes the best fitting span
+/// from the smallest bin that has one, and returns the unused parts of the span to their bins.
 pub struct ListAlloc {
-    free_list: SpanList,
+    bins: [SpanList; BIN_COUNT],
+    // Bit `i` is set iff `bins[i]` is not empty.
+    nonempty_bins: usize,
     garbage_list: SpanList,
     garbage_limit: usize,
 
@@ -240,6 +424,7 @@ pub struct ListAlloc {
     current_allocated_bytes: usize,
     current_free_bytes: usize,
     current_garbage_bytes: usize,
+    peak_allocated_bytes: usize,
 }
 
 unsafe impl Send for ListAlloc {}
@@ -248,12 +433,44 @@ impl ListAlloc {
     #[inline]
     pub const fn new() -> Self {
         Self {
-            free_list: SpanList::new(), garbage_list: SpanList::new(),
+            bins: [
+                SpanList::new(), SpanList::new(), SpanList::new(), SpanList::new(),
+                SpanList::new(), SpanList::new(), SpanList::new(), SpanList::new(),
+                SpanList::new(), SpanList::new(), SpanList::new(), SpanList::new(),
+                SpanList::new(), SpanList::new(), SpanList::new(), SpanList::new(),
+            ],
+            nonempty_bins: 0, garbage_list: SpanList::new(),
             garbage_limit: 1024, total_bytes: 0, current_allocated_bytes: 0,
-            current_free_bytes: 0, current_garbage_bytes: 0
+            current_free_bytes: 0, current_garbage_bytes: 0, peak_allocated_bytes: 0
         }
     }
 
+    pub fn stats(&self) -> ListAllocStats {
+        let mut stats = ListAllocStats {
+            total_bytes: self.total_bytes,
+            allocated_bytes: self.current_allocated_bytes,
+            free_bytes: self.current_free_bytes,
+            garbage_bytes: self.current_garbage_bytes,
+            peak_allocated_bytes: self.peak_allocated_bytes,
+            .. ListAllocStats::default()
+        };
+
+        for list in self.bins.iter().chain(Some(&self.garbage_list)) {
+            let mut hdr = list.first;
+
+            while hdr != null_mut() {
+                let size = unsafe { (*hdr).size };
+
+                stats.span_histogram[Self::bin_of(size)] += 1;
+                stats.free_spans += 1;
+                stats.largest_free_span = cmp::max(stats.largest_free_span, size);
+                hdr = unsafe { (*hdr).next };
+            }
+        }
+
+        stats
+    }
+
     pub unsafe fn provide(&mut self, ptr: *mut u8, size: usize) {
         assert!((ptr as usize) % mem::size_of::<SpanHeader>() == 0);
         assert!(size % mem::size_of::<SpanHeader>() == 0);
@@ -270,35 +487,92 @@ impl ListAlloc {
         (((val-1)/align)+1)*align
     }
 
-    unsafe fn search_free_list(&mut self, size: usize, align: usize) -> bool {
-        while !self.free_list.empty() {
-            let address = self.free_list.first as usize;
-            let limit = address + d!(self.free_list.first).size;
+    // log2(size / 16), clamped to the last bin.
+    #[inline]
+    fn bin_of(size: usize) -> usize {
+        // Anything below the smallest span size goes to bin 0, instead of underflowing.
+        let units = cmp::max(size / mem::size_of::<SpanHeader>(), 1);
+        let bin = mem::size_of::<usize>() * 8 - 1 - units.leading_zeros() as usize;
+        cmp::min(bin, BIN_COUNT - 1)
+    }
+
+    #[inline]
+    unsafe fn bin_push(&mut self, span: *mut SpanHeader) {
+        let bin = Self::bin_of(d!(span).size);
+        self.bins[bin].push(span);
+        self.nonempty_bins |= 1 << bin;
+    }
 
-            let required_top = Self::align_up(address, align) + size;
-            if required_top <= limit {
-                return true;
+    #[inline]
+    unsafe fn bin_unlink(&mut self, bin: usize, link: *mut *mut SpanHeader) -> *mut SpanHeader {
+        let span = self.bins[bin].unlink(link);
+        if self.bins[bin].empty() {
+            self.nonempty_bins &= !(1 << bin);
+        }
+        span
+    }
+
+    // Looks for the smallest span that can hold `size` bytes at the given alignment.
+    // Bins below the one `size` belongs to are too small, and any span that fits
+    // in a lower bin is a better fit than anything in a higher one.
+    // Returns the bin and the link pointing to the span.
+    unsafe fn find_best_fit(&mut self, size: usize, align: usize) -> Option<(usize, *mut *mut SpanHeader)> {
+        let mut bin = Self::bin_of(size);
+
+        while bin < BIN_COUNT {
+            let remaining = self.nonempty_bins >> bin;
+            if remaining == 0 {
+                return None;
+            }
+            bin += remaining.trailing_zeros() as usize;
+
+            let mut best: *mut *mut SpanHeader = null_mut();
+            let mut best_size = usize::max_value();
+            let mut link: *mut *mut SpanHeader = &mut self.bins[bin].first;
+
+            while *link != null_mut() {
+                let hdr = *link;
+                let required = Self::align_up(d!(hdr).address(), align) - d!(hdr).address() + size;
+
+                if required <= d!(hdr).size && d!(hdr).size < best_size {
+                    best = link;
+                    best_size = d!(hdr).size;
+
+                    if required == best_size {
+                        break;
+                    }
+                }
+
+                link = &mut d!(hdr).next;
             }
 
-            self.garbage_list.push(self.free_list.pop());
+            if best != null_mut() {
+                return Some((bin, best));
+            }
+
+            bin += 1;
         }
 
-        false
+        None
     }
 
     pub unsafe fn alloc(&mut self, layout: Layout) -> Result<*mut u8, AllocErr> {
-        let size = Self::align_up(layout.size(), mem::size_of::<SpanHeader>());
+        let size = Self::align_up(cmp::max(layout.size(), 1), mem::size_of::<SpanHeader>());
         let align = cmp::max(layout.align(), mem::size_of::<SpanHeader>());
 
-        if !self.search_free_list(size, align) {
-            self.gc();
+        let (bin, link) = match self.find_best_fit(size, align) {
+            Some(found) => found,
+            None => {
+                self.gc();
 
-            if !self.search_free_list(size, align) {
-                return Err(AllocErr::Exhausted{request: layout});
+                match self.find_best_fit(size, align) {
+                    Some(found) => found,
+                    None => return Err(AllocErr::Exhausted{request: layout}),
+                }
             }
-        }
+        };
 
-        let mut span = self.free_list.pop();
+        let mut span = self.bin_unlink(bin, link);
 
         let alloc_start = Self::align_up(span as usize, align);
         let alloc_end = alloc_start + size;
@@ -307,17 +581,18 @@ impl ListAlloc {
 
         if alloc_end < d!(span).limit() {
             let nspan = d!(span).split_at(alloc_end);
-            self.free_list.push(nspan);
+            self.bin_push(nspan);
         }
 
         if alloc_start > d!(span).address() {
             let nspan = d!(span).split_at(alloc_start);
-            self.free_list.push(span);
+            self.bin_push(span);
             span = nspan;
         }
 
         self.current_allocated_bytes += size;
         self.current_free_bytes -= size;
+        self.peak_allocated_bytes = cmp::max(self.peak_allocated_bytes, self.current_allocated_bytes);
 
         debug_assert!(self.debug_check());
 
@@ -328,7 +603,7 @@ impl ListAlloc {
     }
 
     pub unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
-        let size = Self::align_up(layout.size(), mem::size_of::<SpanHeader>());
+        let size = Self::align_up(cmp::max(layout.size(), 1), mem::size_of::<SpanHeader>());
 
         let span = ptr as *mut SpanHeader;
         *span = SpanHeader { size: size, next: null_mut() };
@@ -343,11 +618,110 @@ impl ListAlloc {
         }
     }
 
+    /// Tries to extend the block at `ptr` from `old_size` to `new_size` bytes,
+    /// by taking over the free (or garbage) span right behind it.
+    pub unsafe fn grow_in_place(&mut self, ptr: *mut u8, old_size: usize, new_size: usize) -> bool {
+        let old_size = Self::align_up(cmp::max(old_size, 1), mem::size_of::<SpanHeader>());
+        let new_size = Self::align_up(cmp::max(new_size, 1), mem::size_of::<SpanHeader>());
+        debug_assert!(new_size >= old_size);
+
+        if new_size == old_size {
+            return true;
+        }
+
+        let limit = ptr as usize + old_size;
+        let extra = new_size - old_size;
+
+        if self.take_free_front(limit, extra) {
+            self.current_free_bytes -= extra;
+        } else if self.garbage_list.take_front(limit, extra) {
+            self.current_garbage_bytes -= extra;
+        } else {
+            return false;
+        }
+
+        self.current_allocated_bytes += extra;
+        self.peak_allocated_bytes = cmp::max(self.peak_allocated_bytes, self.current_allocated_bytes);
+        debug_assert!(self.debug_check());
+        true
+    }
+
+    // Like `SpanList::take_front()`, but looks through all bins, and moves the rest of the span to the right bin.
+    unsafe fn take_free_front(&mut self, address: usize, size: usize) -> bool {
+        for bin in 0..BIN_COUNT {
+            if self.nonempty_bins & (1 << bin) == 0 {
+                continue;
+            }
+
+            let link = self.bins[bin].find(address);
+            if link == null_mut() {
+                continue;
+            }
+
+            if d!(*link).size < size {
+                return false;
+            }
+
+            let span = self.bin_unlink(bin, link);
+            if d!(span).size > size {
+                let rest = d!(span).split_at(address + size);
+                self.bin_push(rest);
+            }
+
+            return true;
+        }
+
+        false
+    }
+
+    /// Shrinks the block at `ptr` from `old_size` to `new_size` bytes. The tail is released.
+    pub unsafe fn shrink_in_place(&mut self, ptr: *mut u8, old_size: usize, new_size: usize) {
+        // Even a zero-sized block keeps its header space, so that it can be freed later.
+        let old_size = Self::align_up(cmp::max(old_size, 1), mem::size_of::<SpanHeader>());
+        let new_size = Self::align_up(cmp::max(new_size, 1), mem::size_of::<SpanHeader>());
+        debug_assert!(new_size <= old_size);
+
+        if new_size < old_size {
+            self.dealloc(ptr.offset(new_size as isize), Layout::array::<u8>(old_size - new_size).unwrap());
+        }
+    }
+
+    pub unsafe fn realloc(&mut self, ptr: *mut u8, layout: Layout, new_layout: Layout) -> Result<*mut u8, AllocErr> {
+        if (ptr as usize) % new_layout.align() == 0 {
+            if new_layout.size() <= layout.size() {
+                self.shrink_in_place(ptr, layout.size(), new_layout.size());
+                return Ok(ptr);
+            }
+
+            if self.grow_in_place(ptr, layout.size(), new_layout.size()) {
+                return Ok(ptr);
+            }
+        }
+
+        let new_ptr = try!(self.alloc(new_layout.clone()));
+        ptr::copy_nonoverlapping(ptr, new_ptr, cmp::min(layout.size(), new_layout.size()));
+        self.dealloc(ptr, layout);
+        Ok(new_ptr)
+    }
+
     unsafe fn gc(&mut self) {
-        let mut garbage = self.garbage_list.take();
-        garbage.sort();
+        let mut all = self.garbage_list.take();
+
+        for bin in 0..BIN_COUNT {
+            let mut list = self.bins[bin].take();
+            while !list.empty() {
+                all.push(list.pop());
+            }
+        }
+        self.nonempty_bins = 0;
 
-        self.free_list = SpanList::merge(self.free_list.take(), garbage);
+        // Sorting merges adjacent spans as well.
+        all.sort();
+
+        while !all.empty() {
+            let span = all.pop();
+            self.bin_push(span);
+        }
 
         self.current_free_bytes += self.current_garbage_bytes;
         self.current_garbage_bytes = 0;
@@ -359,27 +733,29 @@ impl ListAlloc {
         // Basics.
         assert_eq!(self.total_bytes, self.current_allocated_bytes + self.current_free_bytes + self.current_garbage_bytes);
 
-        // Verify free list.
-            assert!(self.free_list.check_length());
-            assert!(self.free_list.check_links());
-
+        // Verify bins.
             let mut counted_bytes: usize = 0;
-            let mut previous_limit: usize = 0;
-            let mut head = self.free_list.first;
 
-            while head != null_mut() {
-                assert!(previous_limit == 0 || previous_limit < d!(head).address());
-                counted_bytes += d!(head).size;
-                previous_limit = d!(head).limit();
-                head = d!(head).next;
+            for bin in 0..BIN_COUNT {
+                assert!(self.bins[bin].check_length());
+                assert!(self.bins[bin].check_links());
+                assert_eq!(self.bins[bin].empty(), self.nonempty_bins & (1 << bin) == 0);
+
+                let mut head = self.bins[bin].first;
+
+                while head != null_mut() {
+                    assert_eq!(Self::bin_of(d!(head).size), bin);
+                    counted_bytes += d!(head).size;
+                    head = d!(head).next;
+                }
             }
 
             assert_eq!(self.current_free_bytes, counted_bytes);
 
 
             // Verify garbage list as well as we are able.
-            assert!(self.free_list.check_length());
-            assert!(self.free_list.check_links());
+            assert!(self.garbage_list.check_length());
+            assert!(self.garbage_list.check_links());
 
             let mut counted_bytes: usize = 0;
             let mut head = self.garbage_list.first;
@@ -402,6 +778,27 @@ unsafe impl Alloc for ListAlloc {
     unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
         ListAlloc::dealloc(self, ptr, layout)
     }
+
+    fn usable_size(&self, layout: &Layout) -> (usize, usize) {
+        (layout.size(), Self::align_up(cmp::max(layout.size(), 1), mem::size_of::<SpanHeader>()))
+    }
+
+    unsafe fn realloc(&mut self, ptr: *mut u8, layout: Layout, new_layout: Layout) -> Result<*mut u8, AllocErr> {
+        ListAlloc::realloc(self, ptr, layout, new_layout)
+    }
+
+    unsafe fn grow_in_place(&mut self, ptr: *mut u8, layout: Layout, new_layout: Layout) -> Result<(), CannotReallocInPlace> {
+        if ListAlloc::grow_in_place(self, ptr, layout.size(), new_layout.size()) {
+            Ok(())
+        } else {
+            Err(CannotReallocInPlace)
+        }
+    }
+
+    unsafe fn shrink_in_place(&mut self, ptr: *mut u8, layout: Layout, new_layout: Layout) -> Result<(), CannotReallocInPlace> {
+        ListAlloc::shrink_in_place(self, ptr, layout.size(), new_layout.size());
+        Ok(())
+    }
 }
 
 
@@ -0,0 +1,140 @@
+use core::ptr::null_mut;
+
+use memory::slab::CLASS_COUNT;
+
+// Maximum number of objects cached per size class, per CPU.
+pub const MAGAZINE_CAPACITY: usize = 32;
+
+// Number of objects moved between a magazine and the shared heap at once.
+// Half the capacity, so that alternating alloc/free at the boundary doesn't hit the lock every time.
+pub const MAGAZINE_BATCH: usize = MAGAZINE_CAPACITY / 2;
+
+#[derive(Copy, Clone)]
+struct Magazine {
+    rounds: [*mut u8; MAGAZINE_CAPACITY],
+    count: usize,
+}
+
+impl Magazine {
+    const fn new() -> Self {
+        Magazine { rounds: [0 as *mut u8; MAGAZINE_CAPACITY], count: 0 }
+    }
+}
+
+/// Per-CPU cache of small objects, one bounded magazine per slab size class.
+///
+/// The cache never talks to the shared heap itself. Instead, the caller passes in
+/// closures that refill or drain a batch of objects under the global lock.
+/// The cache must only ever be touched by its own CPU, with interrupts disabled.
+pub struct MagazineCache {
+    magazines: [Magazine; CLASS_COUNT],
+}
+
+impl MagazineCache {
+    pub const fn new() -> Self {
+        MagazineCache {
+            magazines: [
+                Magazine::new(), Magazine::new(), Magazine::new(), Magazine::new(),
+                Magazine::new(), Magazine::new(), Magazine::new(), Magazine::new(),
+            ],
+        }
+    }
+
+    /// Takes an object from the magazine. If it's empty, `refill` is asked to fill
+    /// a slice of free slots and return how many it filled. Returns `None` if that was zero.
+    pub fn alloc<F>(&mut self, class: usize, refill: F) -> Option<*mut u8>
+        where F: FnOnce(&mut [*mut u8]) -> usize
+    {
+        let mag = &mut self.magazines[class];
+
+        if mag.count == 0 {
+            let filled = refill(&mut mag.rounds[..MAGAZINE_BATCH]);
+            debug_assert!(filled <= MAGAZINE_BATCH);
+            mag.count = filled;
+
+            if filled == 0 {
+                return None;
+            }
+        }
+
+        mag.count -= 1;
+        let object = mag.rounds[mag.count];
+        mag.rounds[mag.count] = null_mut();
+        Some(object)
+    }
+
+    /// Puts an object into the magazine. If it's full, the oldest `MAGAZINE_BATCH`
+    /// objects are handed to `drain` first.
+    pub fn dealloc<F>(&mut self, class: usize, object: *mut u8, drain: F)
+        where F: FnOnce(&[*mut u8])
+    {
+        let mag = &mut self.magazines[class];
+
+        if mag.count == MAGAZINE_CAPACITY {
+            drain(&mag.rounds[..MAGAZINE_BATCH]);
+
+            // Keep the most recently freed (cache-hot) objects.
+            for i in MAGAZINE_BATCH..MAGAZINE_CAPACITY {
+                mag.rounds[i - MAGAZINE_BATCH] = mag.rounds[i];
+            }
+            mag.count -= MAGAZINE_BATCH;
+        }
+
+        mag.rounds[mag.count] = object;
+        mag.count += 1;
+    }
+
+    /// Hands every cached object to `drain`, one size class at a time.
+    pub fn flush<F>(&mut self, mut drain: F)
+        where F: FnMut(usize, &[*mut u8])
+    {
+        for class in 0..CLASS_COUNT {
+            let mag = &mut self.magazines[class];
+            if mag.count > 0 {
+                drain(class, &mag.rounds[..mag.count]);
+                mag.count = 0;
+            }
+        }
+    }
+
+    /// Number of objects currently cached for the class.
+    #[inline]
+    pub fn cached(&self, class: usize) -> usize {
+        self.magazines[class].count
+    }
+}
+
+#[test]
+fn test_magazine_refill_and_drain() {
+    let mut cache = MagazineCache::new();
+    let mut next = 0x1000_usize;
+
+    // First allocation refills a batch.
+    let a = cache.alloc(3, |slots| {
+        for slot in slots.iter_mut() {
+            *slot = next as *mut u8;
+            next += 0x100;
+        }
+        slots.len()
+    });
+    assert!(a.is_some());
+    assert_eq!(cache.cached(3), MAGAZINE_BATCH - 1);
+
+    // Exhausted refill fails cleanly.
+    assert_eq!(cache.alloc(4, |_| 0), None);
+
+    for i in 0..MAGAZINE_CAPACITY - cache.cached(3) {
+        cache.dealloc(3, (0x10000 + i * 0x100) as *mut u8, |_| panic!("Drained too early."));
+    }
+    assert_eq!(cache.cached(3), MAGAZINE_CAPACITY);
+
+    let mut drained = 0;
+    cache.dealloc(3, 0x20000 as *mut u8, |objects| drained += objects.len());
+    assert_eq!(drained, MAGAZINE_BATCH);
+    assert_eq!(cache.cached(3), MAGAZINE_CAPACITY - MAGAZINE_BATCH + 1);
+
+    let mut flushed = 0;
+    cache.flush(|class, objects| { assert_eq!(class, 3); flushed += objects.len(); });
+    assert_eq!(flushed, MAGAZINE_CAPACITY - MAGAZINE_BATCH + 1);
+    assert_eq!(cache.cached(3), 0);
+}
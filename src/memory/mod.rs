@@ -1,3 +1,20 @@
 pub mod list_alloc_simple;
+pub mod list_alloc;
+#[cfg(test)]
+mod list_alloc_bench;
+pub mod frame;
+pub mod bump_alloc;
+pub mod buddy_alloc;
+pub mod physical;
 pub mod paging;
+pub mod kernel_space;
+pub mod virtual_region;
+pub mod kernel_stack;
+pub mod address_space;
+pub mod page_dump;
+pub mod slab;
+pub mod magazine;
 pub mod heap;
+pub mod heap_debug;
+
+pub use self::frame::FrameAllocator;
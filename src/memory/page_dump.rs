@@ -0,0 +1,236 @@
+//! Readable dumps of page table hierarchies, for debugging.
+//!
+//! Pages of one size that map consecutive frames with the same flags are coalesced into ranges,
+//! printed one per line:
+//!
+//! ```text
+//! ffff800000000000-ffff8000ffffffff -> 0x0 RW NX G 1G
+//! ffffc00000001000-ffffc00000300fff -> 0xc0000000 RW NX WC 4K
+//! ```
+//!
+//! The memory type is only shown if it's not write-back.
+
+use core::fmt;
+
+use x86_64::{PhysicalAddress, VirtualAddress};
+
+use memory::paging::{L4Table, Entry, PageSize, EntryFlags, CacheMode, ENTRY_COUNT};
+use memory::paging::{PRESENT, HUGE_PAGE, WRITABLE, USER_ACCESSIBLE, GLOBAL, NO_EXECUTE};
+
+// Flags that make two mappings different, besides the memory type. Accessed and dirty bits don't.
+fn significant(flags: EntryFlags) -> EntryFlags {
+    flags & (WRITABLE | USER_ACCESSIBLE | GLOBAL | NO_EXECUTE)
+}
+
+/// Consecutive pages of one size, mapping consecutive frames with the same flags.
+#[derive(Copy, Clone)]
+pub struct MappedRange {
+    pub start: usize,
+    /// Last byte of the range, so that a range can end at the top of the address space.
+    pub last: usize,
+    pub frame: PhysicalAddress,
+    pub size: PageSize,
+    pub flags: EntryFlags,
+    pub cache: CacheMode,
+}
+
+impl MappedRange {
+    fn new(address: usize, entry: &Entry, size: PageSize) -> MappedRange {
+        MappedRange {
+            start: address,
+            last: address + (size.bytes() - 1),
+            frame: entry.page_frame_address(size).unwrap(),
+            size: size,
+            flags: significant(entry.flags()),
+            cache: entry.cache_mode(size),
+        }
+    }
+
+    fn extends_to(&self, next: &MappedRange) -> bool {
+        next.start == self.last.wrapping_add(1) && next.size == self.size && next.flags == self.flags
+            && next.cache == self.cache && next.frame.0 == self.frame.0 + (next.start - self.start) as u64
+    }
+}
+
+impl fmt::Display for MappedRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{:016x}-{:016x} -> {:#x} {}", self.start, self.last, self.frame.0,
+                    if self.flags.contains(WRITABLE) { "RW" } else { "R" }));
+
+        let optional = [(USER_ACCESSIBLE, "U"), (NO_EXECUTE, "NX"), (GLOBAL, "G")];
+        for &(flag, name) in optional.iter() {
+            if self.flags.contains(flag) {
+                try!(write!(f, " {}", name));
+            }
+        }
+
+        if self.cache != CacheMode::WriteBack {
+            try!(write!(f, " {}", self.cache));
+        }
+
+        write!(f, " {}", match self.size {
+            PageSize::Size4K => "4K",
+            PageSize::Size2M => "2M",
+            PageSize::Size1G => "1G",
+        })
+    }
+}
+
+/// Calls `func` for every page mapped by the hierarchy, in address order.
+fn for_each_page<E, F>(l4: &L4Table, mut func: F) -> Result<(), E>
+    where F: FnMut(MappedRange) -> Result<(), E>
+{
+    for i4 in 0..ENTRY_COUNT {
+        let l3 = match l4.next_table(i4) {
+            Some(l3) => l3,
+            None => continue,
+        };
+
+        // Upper half addresses are sign extended.
+        let base = if i4 < ENTRY_COUNT / 2 { i4 << 39 } else { 0xffff_0000_0000_0000 | (i4 << 39) };
+
+        for i3 in 0..ENTRY_COUNT {
+            let base = base | (i3 << 30);
+            let entry = l3[i3];
+            if entry.flags().contains(PRESENT | HUGE_PAGE) {
+                try!(func(MappedRange::new(base, &entry, PageSize::Size1G)));
+                continue;
+            }
+
+            let l2 = match l3.next_table(i3) {
+                Some(l2) => l2,
+                None => continue,
+            };
+
+            for i2 in 0..ENTRY_COUNT {
+                let base = base | (i2 << 21);
+                let entry = l2[i2];
+                if entry.flags().contains(PRESENT | HUGE_PAGE) {
+                    try!(func(MappedRange::new(base, &entry, PageSize::Size2M)));
+                    continue;
+                }
+
+                let l1 = match l2.next_table(i2) {
+                    Some(l1) => l1,
+                    None => continue,
+                };
+
+                for i1 in 0..ENTRY_COUNT {
+                    let entry = l1[i1];
+                    if entry.flags().contains(PRESENT) {
+                        try!(func(MappedRange::new(base | (i1 << 12), &entry, PageSize::Size4K)));
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Calls `func` for every range mapped by the hierarchy, in address order.
+pub fn for_each_range<E, F>(l4: &L4Table, mut func: F) -> Result<(), E>
+    where F: FnMut(&MappedRange) -> Result<(), E>
+{
+    let mut current: Option<MappedRange> = None;
+
+    try!(for_each_page(l4, |page| {
+        if let Some(ref mut range) = current {
+            if range.extends_to(&page) {
+                range.last = page.last;
+                return Ok(());
+            }
+            try!(func(range));
+        }
+
+        current = Some(page);
+        Ok(())
+    }));
+
+    match current {
+        Some(ref range) => func(range),
+        None => Ok(()),
+    }
+}
+
+/// Writes all ranges mapped by the hierarchy, one per line.
+pub fn dump<W: fmt::Write + ?Sized>(out: &mut W, l4: &L4Table) -> fmt::Result {
+    for_each_range(l4, |range| writeln!(out, "{}", range))
+}
+
+// Whether every page of the range is mapped the same way by `other`.
+fn mapped_same(range: &MappedRange, other: &L4Table) -> bool {
+    let pages = (range.last - range.start) / range.size.bytes() + 1;
+
+    (0..pages).all(|i| {
+        let address = range.start + i * range.size.bytes();
+        match other.lookup_entry(VirtualAddress(address)) {
+            Some((entry, size)) => {
+                let page = MappedRange::new(address, &entry, size);
+                page.size == range.size && page.flags == range.flags && page.cache == range.cache
+                    && page.frame.0 == range.frame.0 + (address - range.start) as u64
+            },
+            None => false,
+        }
+    })
+}
+
+/// Writes the ranges that `old` and `new` map differently: first those of `old`, prefixed with `-`,
+/// then those of `new`, prefixed with `+`. Tables shared by both are walked twice, but never reported.
+pub fn diff<W: fmt::Write + ?Sized>(out: &mut W, old: &L4Table, new: &L4Table) -> fmt::Result {
+    try!(for_each_range(old, |range| {
+        if mapped_same(range, new) { Ok(()) } else { writeln!(out, "- {}", range) }
+    }));
+
+    for_each_range(new, |range| {
+        if mapped_same(range, old) { Ok(()) } else { writeln!(out, "+ {}", range) }
+    })
+}
+
+#[cfg(test)]
+#[repr(C, align(4096))]
+struct TestFrames([u8; 32 * 4096]);
+
+#[cfg(test)]
+static mut TEST_FRAMES: TestFrames = TestFrames([0; 32 * 4096]);
+
+#[test]
+fn test_page_dump_and_diff() {
+    use alloc::string::String;
+    use memory::bump_alloc::BumpAllocator;
+    use memory::paging::{self, Mapper, Page};
+
+    // Outside the kernel, physical memory is "identity mapped", so the tables can live in a static.
+    let start = unsafe { TEST_FRAMES.0.as_ptr() as u64 };
+    let mut frames = BumpAllocator::new(start, start + 32 * 4096);
+    // Otherwise WC and WP fall back to types that don't need the PAT bit.
+    paging::assume_pat_programmed();
+
+    let (_, old) = L4Table::create(&mut frames).unwrap();
+    let (_, new) = L4Table::create(&mut frames).unwrap();
+
+    for l4 in [&mut *old, &mut *new].iter_mut() {
+        let mut mapper = unsafe { Mapper::new(l4) };
+        mapper.map_range(VirtualAddress(0xffff_8000_0000_0000), PhysicalAddress(0), 0x40_0000, WRITABLE | NO_EXECUTE | GLOBAL, CacheMode::WriteBack, &mut frames).unwrap();
+        mapper.map_range(VirtualAddress(0xffff_c000_0000_1000), PhysicalAddress(0x10_0000), 0x3000, WRITABLE, CacheMode::WriteBack, &mut frames).unwrap();
+    }
+
+    {
+        let mut mapper = unsafe { Mapper::new(new) };
+        mapper.map_to(Page::containing_address(VirtualAddress(0x20_0000)), PhysicalAddress(0x5000), USER_ACCESSIBLE, &mut frames).unwrap();
+        // The PAT bit of 4 KiB entries must not be mistaken for the huge page flag, nor for a part of the address.
+        mapper.map_range(VirtualAddress(0x20_1000), PhysicalAddress(0x7000), 0x1000, WRITABLE, CacheMode::WriteCombining, &mut frames).unwrap();
+        mapper.map_range(VirtualAddress(0x40_0000), PhysicalAddress(0xc000_0000), 0x20_0000, WRITABLE, CacheMode::WriteProtected, &mut frames).unwrap();
+    }
+
+    let mut out = String::new();
+    dump(&mut out, old).unwrap();
+    assert_eq!(out, "ffff800000000000-ffff8000003fffff -> 0x0 RW NX G 2M\n\
+                     ffffc00000001000-ffffc00000003fff -> 0x100000 RW 4K\n");
+
+    let mut out = String::new();
+    diff(&mut out, old, new).unwrap();
+    assert_eq!(out, "+ 0000000000200000-0000000000200fff -> 0x5000 R U 4K\n\
+                     + 0000000000201000-0000000000201fff -> 0x7000 RW WC 4K\n\
+                     + 0000000000400000-00000000005fffff -> 0xc0000000 RW WP 2M\n");
+}
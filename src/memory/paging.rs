@@ -1,12 +1,40 @@
 use core;
 use core::ops::{Index, IndexMut};
 use core::marker::PhantomData;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
-use x86_64::PhysicalAddress;
+use x86_64::{PhysicalAddress, VirtualAddress};
+use x86_64::instructions::tlb;
 use x86_64::registers::control_regs;
+use x86_64::registers::msr;
+
+use memory::frame::FrameAllocator;
+use platform;
 
 // Start of the upper virtual memory half on current processors with 4-level page tables and 48-bit virtual addresses.
 pub const FLAT_MEMORY_START: usize = 0xffff800000000000;
+// The flat map takes the lower half of the upper half, 64 TiB. The rest is for `virtual_region` and the image.
+pub const FLAT_MEMORY_END: usize = 0xffffc00000000000;
+
+// Where physical memory is visible. UEFI identity maps it, our own tables map it at `FLAT_MEMORY_START`.
+static PHYSICAL_MEMORY_OFFSET: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the virtual address through which the physical address can be accessed right now.
+#[inline]
+pub fn phys_to_virt(address: PhysicalAddress) -> usize {
+    address.0 as usize + PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed)
+}
+
+/// Whether the kernel's own tables are active, so that physical memory is accessed through the flat map.
+#[inline]
+pub fn is_flat_map_active() -> bool {
+    PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed) == FLAT_MEMORY_START
+}
+
+/// Must be called right after switching to tables that contain the flat map.
+pub unsafe fn set_flat_map_active() {
+    PHYSICAL_MEMORY_OFFSET.store(FLAT_MEMORY_START, Ordering::Relaxed);
+}
 
 bitflags! {
     pub struct EntryFlags: u64 {
@@ -18,11 +46,157 @@ bitflags! {
         const ACCESSED =        1 << 5;
         const DIRTY =           1 << 6;
         const HUGE_PAGE =       1 << 7;
+        // Bit 7 is the PAT bit in level 1 entries. Use `CacheMode` rather than these three.
         const GLOBAL =          1 << 8;
         const NO_EXECUTE =      1 << 63;
     }
 }
 
+const IA32_PAT: u32 = 0x277;
+
+// Bits selecting the PAT entry, besides `WRITE_THROUGH` and `NO_CACHE`.
+const PAT_BIT_4K: u64 = 1 << 7;
+const PAT_BIT_HUGE: u64 = 1 << 12;
+
+// Set once `init_pat()` programmed the PAT. Until then, and on processors without one,
+// only the first four entries can be selected, the PAT bit in entries is reserved without PAT support.
+static PAT_PROGRAMMED: AtomicBool = AtomicBool::new(false);
+
+/// Memory types a mapping can use, through the page attribute table.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CacheMode {
+    /// WB, normal memory.
+    WriteBack,
+    /// WT, reads are cached, writes go straight to memory.
+    WriteThrough,
+    /// UC, for MMIO registers.
+    Uncached,
+    /// UC-, like UC, but can be overridden to WC by the MTRRs.
+    UncachedMinus,
+    /// WC, for framebuffers.
+    WriteCombining,
+    /// WP, reads are cached, writes invalidate.
+    WriteProtected,
+}
+
+impl CacheMode {
+    // Entries 0-3 are the power-on defaults, so that mappings made by UEFI keep their meaning.
+    // Entries 6 and 7 are never selected.
+    const PAT_LAYOUT: [CacheMode; 6] = [
+        CacheMode::WriteBack, CacheMode::WriteThrough, CacheMode::UncachedMinus, CacheMode::Uncached,
+        CacheMode::WriteCombining, CacheMode::WriteProtected,
+    ];
+
+    // The memory type encoding used in the PAT MSR.
+    fn memory_type(self) -> u64 {
+        match self {
+            CacheMode::Uncached => 0,
+            CacheMode::WriteCombining => 1,
+            CacheMode::WriteThrough => 4,
+            CacheMode::WriteProtected => 5,
+            CacheMode::WriteBack => 6,
+            CacheMode::UncachedMinus => 7,
+        }
+    }
+
+    fn pat_index(self) -> u64 {
+        Self::PAT_LAYOUT.iter().position(|&mode| mode == self).unwrap() as u64
+    }
+
+    // The mode a mapping asking for this one really gets. Without a programmed PAT, only the first
+    // four entries can be selected, so WC degrades to UC- and WP to UC. Both keep the memory out of
+    // the cache rather than caching writes the mapping asked not to be cached, and UC- still lets
+    // the MTRRs make a framebuffer WC.
+    fn effective(self, pat_programmed: bool) -> CacheMode {
+        if pat_programmed {
+            return self;
+        }
+        match self {
+            CacheMode::WriteCombining => CacheMode::UncachedMinus,
+            CacheMode::WriteProtected => CacheMode::Uncached,
+            mode => mode,
+        }
+    }
+
+    /// Entry bits selecting the mode, in an entry mapping a page of `size`.
+    pub fn entry_bits(self, size: PageSize) -> u64 {
+        self.entry_bits_with(size, PAT_PROGRAMMED.load(Ordering::Relaxed))
+    }
+
+    fn entry_bits_with(self, size: PageSize, pat_programmed: bool) -> u64 {
+        let index = self.effective(pat_programmed).pat_index();
+        let pat_bit = if size == PageSize::Size4K { PAT_BIT_4K } else { PAT_BIT_HUGE };
+
+        let mut bits = 0;
+        if index & 1 != 0 { bits |= WRITE_THROUGH.bits(); }
+        if index & 2 != 0 { bits |= NO_CACHE.bits(); }
+        if index & 4 != 0 { bits |= pat_bit; }
+        bits
+    }
+
+    fn from_entry_bits(bits: u64, size: PageSize) -> CacheMode {
+        Self::from_entry_bits_with(bits, size, PAT_PROGRAMMED.load(Ordering::Relaxed))
+    }
+
+    fn from_entry_bits_with(bits: u64, size: PageSize, pat_programmed: bool) -> CacheMode {
+        let pat_bit = if size == PageSize::Size4K { PAT_BIT_4K } else { PAT_BIT_HUGE };
+
+        let mut index = 0;
+        if bits & WRITE_THROUGH.bits() != 0 { index |= 1; }
+        if bits & NO_CACHE.bits() != 0 { index |= 2; }
+        if bits & pat_bit != 0 { index |= 4; }
+
+        // Until it is programmed, the PAT holds the power-on defaults, where entries 4-7 repeat 0-3.
+        if !pat_programmed {
+            index &= 3;
+        }
+
+        // Nothing we map selects 6 or 7, they still hold UC- and UC from the power-on defaults.
+        match Self::PAT_LAYOUT.get(index) {
+            Some(&mode) => mode,
+            None if index == 6 => CacheMode::UncachedMinus,
+            None => CacheMode::Uncached,
+        }
+    }
+}
+
+impl core::fmt::Display for CacheMode {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.write_str(match *self {
+            CacheMode::WriteBack => "WB",
+            CacheMode::WriteThrough => "WT",
+            CacheMode::Uncached => "UC",
+            CacheMode::UncachedMinus => "UC-",
+            CacheMode::WriteCombining => "WC",
+            CacheMode::WriteProtected => "WP",
+        })
+    }
+}
+
+/// Programs the PAT to match `CacheMode`. Without PAT support, WC degrades to UC- and WP to UC.
+pub unsafe fn init_pat() {
+    if !platform::has_pat() {
+        return;
+    }
+
+    let mut pat = 0;
+    for (i, mode) in CacheMode::PAT_LAYOUT.iter().enumerate() {
+        pat |= mode.memory_type() << (i * 8);
+    }
+    pat |= CacheMode::UncachedMinus.memory_type() << 48;
+    pat |= CacheMode::Uncached.memory_type() << 56;
+
+    // Only entries nothing is mapped with change, so there are no stale cache lines to worry about.
+    msr::wrmsr(IA32_PAT, pat);
+    PAT_PROGRAMMED.store(true, Ordering::SeqCst);
+}
+
+/// Makes mappings use the whole PAT layout, as if `init_pat()` had run. Host tests can't program the PAT.
+#[cfg(test)]
+pub fn assume_pat_programmed() {
+    PAT_PROGRAMMED.store(true, Ordering::SeqCst);
+}
+
 #[derive(Copy, Clone)]
 pub struct Entry(u64);
 
@@ -39,18 +213,45 @@ impl Entry {
         EntryFlags::from_bits_truncate(self.0)
     }
 
+    /// The frame of a level 2-4 entry. Level 1 entries need `page_frame_address()`,
+    /// because their PAT bit is where the huge page flag is elsewhere.
     pub fn frame_address(&self) -> Option<PhysicalAddress> {
-        if self.flags().contains(PRESENT) {
+        let flags = self.flags();
+
+        if !flags.contains(PRESENT) {
+            None
+        } else if flags.contains(HUGE_PAGE) {
+            // Bit 12 is the PAT bit in huge page entries, huge frames are aligned way past it anyway.
+            Some(PhysicalAddress(self.0 & 0x000f_ffff_ffff_e000))
+        } else {
             Some(PhysicalAddress(self.0 & 0x000f_ffff_ffff_f000))
+        }
+    }
+
+    /// The frame of an entry mapping a page of `size`.
+    pub fn page_frame_address(&self, size: PageSize) -> Option<PhysicalAddress> {
+        if size == PageSize::Size4K {
+            if self.flags().contains(PRESENT) { Some(PhysicalAddress(self.0 & 0x000f_ffff_ffff_f000)) } else { None }
         } else {
-            None
+            self.frame_address()
         }
     }
 
+    /// The memory type of an entry mapping a page of `size`.
+    pub fn cache_mode(&self, size: PageSize) -> CacheMode {
+        CacheMode::from_entry_bits(self.0, size)
+    }
+
     pub fn set(&mut self, frame: PhysicalAddress, flags: EntryFlags) {
         assert!((frame.0 & !0x000f_ffff_ffff_f000) == 0);
         self.0 = frame.0 | flags.bits();
     }
+
+    /// Changes the memory type of an entry mapping a page of `size`.
+    pub fn set_cache_mode(&mut self, mode: CacheMode, size: PageSize) {
+        let pat_bit = if size == PageSize::Size4K { PAT_BIT_4K } else { PAT_BIT_HUGE };
+        self.0 = (self.0 & !(WRITE_THROUGH.bits() | NO_CACHE.bits() | pat_bit)) | mode.entry_bits(size);
+    }
 }
 
 impl core::fmt::Debug for Entry {
@@ -64,6 +265,69 @@ impl core::fmt::Debug for Entry {
 }
 
 pub const ENTRY_COUNT: usize = 512;
+pub const PAGE_SIZE: usize = 4096;
+
+/// Page sizes supported by the hardware. 2 MiB pages are mapped directly by a level 2 entry,
+/// 1 GiB pages by a level 3 entry.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PageSize {
+    Size4K,
+    Size2M,
+    Size1G,
+}
+
+impl PageSize {
+    #[inline]
+    pub fn bytes(&self) -> usize {
+        match *self {
+            PageSize::Size4K => 4096,
+            PageSize::Size2M => 2 * 1024 * 1024,
+            PageSize::Size1G => 1024 * 1024 * 1024,
+        }
+    }
+
+    pub fn is_supported(&self) -> bool {
+        match *self {
+            PageSize::Size1G => platform::has_1g_pages(),
+            _ => true,
+        }
+    }
+}
+
+/// A 4 KiB page of virtual memory.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Page {
+    number: usize,
+}
+
+impl Page {
+    pub fn containing_address(address: VirtualAddress) -> Page {
+        // Must be canonical, i.e. bits 48-63 are copies of bit 47.
+        assert!(address.0 < 0x0000_8000_0000_0000 || address.0 >= 0xffff_8000_0000_0000,
+                "Invalid virtual address: {:#x}", address.0);
+        Page { number: address.0 / PAGE_SIZE }
+    }
+
+    pub fn start_address(&self) -> VirtualAddress {
+        VirtualAddress(self.number * PAGE_SIZE)
+    }
+
+    fn l4_index(&self) -> usize {
+        (self.number >> 27) & 0o777
+    }
+
+    fn l3_index(&self) -> usize {
+        (self.number >> 18) & 0o777
+    }
+
+    fn l2_index(&self) -> usize {
+        (self.number >> 9) & 0o777
+    }
+
+    fn l1_index(&self) -> usize {
+        self.number & 0o777
+    }
+}
 
 
 pub trait TableLevel {}
@@ -117,12 +381,12 @@ impl<L> Table<L> where L: TableLevel {
 
 #[inline]
 unsafe fn flat_mapped_ref<'a, T>(addr: PhysicalAddress) -> &'a T {
-    &*((addr.0 as usize + FLAT_MEMORY_START) as *const T)
+    &*(phys_to_virt(addr) as *const T)
 }
 
 #[inline]
 unsafe fn flat_mapped_mut<'a, T>(addr: PhysicalAddress) -> &'a mut T {
-    &mut *((addr.0 as usize + FLAT_MEMORY_START) as *mut T)
+    &mut *(phys_to_virt(addr) as *mut T)
 }
 
 impl<L> Table<L> where L: HierarchicalLevel
@@ -135,6 +399,23 @@ impl<L> Table<L> where L: HierarchicalLevel
         self.next_table_address(index).map(|address| unsafe { flat_mapped_mut(address) })
     }
 
+    /// Like `next_table_mut()`, but if the entry is empty, a new zeroed table is allocated and linked in.
+    /// Returns `None` if the allocator is out of frames.
+    pub fn next_table_create<A: FrameAllocator>(&mut self, index: usize, allocator: &mut A) -> Option<&mut Table<L::NextLevel>> {
+        if self[index].is_unused() {
+            let frame = match allocator.alloc_frames(0) {
+                Some(frame) => frame,
+                None => return None,
+            };
+
+            self[index].set(frame, PRESENT | WRITABLE);
+            unsafe { flat_mapped_mut::<Table<L::NextLevel>>(frame).clear(); }
+        }
+
+        assert!(!self[index].flags().contains(HUGE_PAGE), "Entry {} is a huge page, not a table.", index);
+        self.next_table_mut(index)
+    }
+
     fn next_table_address(&self, index: usize) -> Option<PhysicalAddress> {
         let entry_flags = self[index].flags();
         if entry_flags.contains(PRESENT) && !entry_flags.contains(HUGE_PAGE) {
@@ -146,26 +427,286 @@ impl<L> Table<L> where L: HierarchicalLevel
 }
 
 impl Table<Level4> {
-    /// Assumes that physical memory is currently identity-mapped.
-    pub unsafe fn initialize_high_mapping() {
-        let l4 = &mut *(control_regs::cr3().0 as usize as *mut Table<Level4>);
-        for i in 0..256 {
-            assert!(l4[i+256].is_unused());
-            l4[i+256] = l4[i];
+    /// Finds the mapping containing `address`.
+    /// Returns the start of the frame it maps to, the size of the page, and the flags of the entry.
+    pub fn lookup(&self, address: VirtualAddress) -> Option<(PhysicalAddress, PageSize, EntryFlags)> {
+        self.lookup_entry(address).map(|(entry, size)| (entry.page_frame_address(size).unwrap(), size, entry.flags()))
+    }
+
+    /// Finds the entry mapping `address`, and the size of the page it maps.
+    pub fn lookup_entry(&self, address: VirtualAddress) -> Option<(Entry, PageSize)> {
+        let page = Page::containing_address(address);
+
+        let l3 = match self.next_table(page.l4_index()) {
+            Some(l3) => l3,
+            None => return None,
+        };
+
+        let entry = l3[page.l3_index()];
+        if entry.flags().contains(PRESENT | HUGE_PAGE) {
+            return Some((entry, PageSize::Size1G));
         }
+
+        let l2 = match l3.next_table(page.l3_index()) {
+            Some(l2) => l2,
+            None => return None,
+        };
+
+        let entry = l2[page.l2_index()];
+        if entry.flags().contains(PRESENT | HUGE_PAGE) {
+            return Some((entry, PageSize::Size2M));
+        }
+
+        let l1 = match l2.next_table(page.l2_index()) {
+            Some(l1) => l1,
+            None => return None,
+        };
+
+        let entry = l1[page.l1_index()];
+        if entry.flags().contains(PRESENT) { Some((entry, PageSize::Size4K)) } else { None }
+    }
+
+    /// Allocates a new, empty table.
+    pub fn create<'a, A: FrameAllocator>(allocator: &mut A) -> Option<(PhysicalAddress, &'a mut Self)> {
+        allocator.alloc_frames(0).map(|frame| {
+            let table = unsafe { flat_mapped_mut::<Self>(frame) };
+            table.clear();
+            (frame, table)
+        })
+    }
+
+    pub unsafe fn from_frame<'a>(frame: PhysicalAddress) -> &'a mut Self {
+        flat_mapped_mut(frame)
     }
 
     pub unsafe fn current<'a>() -> &'a Self {
-        unsafe { flat_mapped_ref(control_regs::cr3()) }
+        flat_mapped_ref(Self::current_frame())
     }
 
     pub unsafe fn current_mut<'a>() -> &'a mut Self {
-        unsafe { flat_mapped_mut(control_regs::cr3()) }
+        flat_mapped_mut(Self::current_frame())
+    }
+
+    // The low bits of CR3 hold the PCID, or cache flags without PCIDs.
+    fn current_frame() -> PhysicalAddress {
+        PhysicalAddress(control_regs::cr3().0 & !0xfff)
     }
 }
 
 pub type L4Table = Table<Level4>;
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MapError {
+    /// The page is already mapped.
+    AlreadyMapped,
+    /// The page lies inside a huge page mapping.
+    HugePage,
+    /// A page table was needed, but the frame allocator is out of frames.
+    OutOfFrames,
+    /// The processor doesn't support 1 GiB pages.
+    UnsupportedPageSize,
+    /// No free virtual range is large enough for the mapping.
+    OutOfAddressSpace,
+}
+
+/// Creates and removes mappings in a page table hierarchy.
+///
+/// Page tables are accessed through the flat physical mapping, and missing intermediate tables
+/// are taken from the frame allocator passed in. Removing a mapping invalidates its TLB entry,
+/// which is only meaningful when the hierarchy is the active one.
+pub struct Mapper<'a> {
+    l4: &'a mut L4Table,
+}
+
+impl<'a> Mapper<'a> {
+    /// The caller must make sure nobody else modifies the hierarchy at the same time.
+    pub unsafe fn new(l4: &'a mut L4Table) -> Mapper<'a> {
+        Mapper { l4: l4 }
+    }
+
+    /// Mapper for the currently active hierarchy.
+    pub unsafe fn current() -> Mapper<'a> {
+        Mapper::new(L4Table::current_mut())
+    }
+
+    pub fn translate(&self, address: VirtualAddress) -> Option<PhysicalAddress> {
+        self.lookup(address).map(|(frame, size, _)| PhysicalAddress(frame.0 + (address.0 % size.bytes()) as u64))
+    }
+
+    /// Finds the mapping containing `address`.
+    /// Returns the start of the frame it maps to, the size of the page, and the flags of the entry.
+    pub fn lookup(&self, address: VirtualAddress) -> Option<(PhysicalAddress, PageSize, EntryFlags)> {
+        self.l4.lookup(address)
+    }
+
+    /// Maps a single 4 KiB page of normal, write-back memory.
+    pub fn map_to<A: FrameAllocator>(&mut self, page: Page, frame: PhysicalAddress, flags: EntryFlags, allocator: &mut A) -> Result<(), MapError> {
+        self.map_sized(page.start_address(), frame, PageSize::Size4K, flags, CacheMode::WriteBack, allocator)
+    }
+
+    /// Maps a single page of the given size. Both addresses must be aligned to the size.
+    /// The memory type is only ever given by `cache`, not by the flags.
+    pub fn map_sized<A: FrameAllocator>(&mut self, address: VirtualAddress, frame: PhysicalAddress, size: PageSize, flags: EntryFlags, cache: CacheMode, allocator: &mut A) -> Result<(), MapError> {
+        assert!(address.0 % size.bytes() == 0 && frame.0 as usize % size.bytes() == 0,
+                "Mapping of {:#x} to {:#x} is not aligned to {:?}.", address.0, frame.0, size);
+        assert!(!flags.intersects(WRITE_THROUGH | NO_CACHE | HUGE_PAGE), "Flags {:?} overlap with the cache mode.", flags);
+
+        if !size.is_supported() {
+            return Err(MapError::UnsupportedPageSize);
+        }
+
+        let page = Page::containing_address(address);
+
+        let entry = {
+            let l3 = try!(Self::next_or_create(&mut *self.l4, page.l4_index(), allocator));
+            if size == PageSize::Size1G {
+                &mut l3[page.l3_index()]
+            } else {
+                let l2 = try!(Self::next_or_create(l3, page.l3_index(), allocator));
+                if size == PageSize::Size2M {
+                    &mut l2[page.l2_index()]
+                } else {
+                    let l1 = try!(Self::next_or_create(l2, page.l2_index(), allocator));
+                    &mut l1[page.l1_index()]
+                }
+            }
+        };
+
+        if !entry.is_unused() {
+            return Err(MapError::AlreadyMapped);
+        }
+
+        if size == PageSize::Size4K {
+            entry.set(frame, flags | PRESENT);
+        } else {
+            entry.set(frame, flags | PRESENT | HUGE_PAGE);
+        }
+        entry.set_cache_mode(cache, size);
+
+        Ok(())
+    }
+
+    /// Maps `size` bytes of physical memory starting at `frame` to `address`,
+    /// using the largest pages the alignment of both addresses allows.
+    /// If this fails, the part mapped so far stays mapped.
+    pub fn map_range<A: FrameAllocator>(&mut self, address: VirtualAddress, frame: PhysicalAddress, size: usize, flags: EntryFlags, cache: CacheMode, allocator: &mut A) -> Result<(), MapError> {
+        assert!((address.0 | frame.0 as usize | size) % PAGE_SIZE == 0);
+
+        let mut offset = 0;
+
+        while offset < size {
+            let virt = address.0 + offset;
+            let phys = frame.0 as usize + offset;
+
+            let page_size = [PageSize::Size1G, PageSize::Size2M, PageSize::Size4K].iter().cloned()
+                .find(|s| (virt | phys) % s.bytes() == 0 && size - offset >= s.bytes() && s.is_supported())
+                .unwrap();
+
+            try!(self.map_sized(VirtualAddress(virt), PhysicalAddress(phys as u64), page_size, flags, cache, allocator));
+            offset += page_size.bytes();
+        }
+
+        Ok(())
+    }
+
+    /// Removes the mapping containing the page, and returns the frame and size of what was mapped.
+    /// If the page is a part of a huge page, the whole huge page is unmapped.
+    /// Page tables that become empty are not released.
+    pub fn unmap(&mut self, page: Page) -> Option<(PhysicalAddress, PageSize)> {
+        let removed = match self.entry_mut(page) {
+            Some((entry, size)) => {
+                let frame = entry.page_frame_address(size);
+                entry.clear();
+                frame.map(|frame| (frame, size))
+            },
+            None => None,
+        };
+
+        if removed.is_some() {
+            // Invalidating any address inside a huge page drops the whole TLB entry.
+            flush_tlb(page.start_address());
+        }
+
+        removed
+    }
+
+    /// Replaces the flags of the 4 KiB mapping of `page`, keeping its frame and memory type.
+    /// Returns false if the page isn't mapped, or is a part of a huge page.
+    pub fn protect(&mut self, page: Page, flags: EntryFlags) -> bool {
+        assert!(!flags.intersects(WRITE_THROUGH | NO_CACHE | HUGE_PAGE), "Flags {:?} overlap with the cache mode.", flags);
+
+        match self.entry_mut(page) {
+            Some((entry, PageSize::Size4K)) => {
+                let (frame, cache) = match entry.page_frame_address(PageSize::Size4K) {
+                    Some(frame) => (frame, entry.cache_mode(PageSize::Size4K)),
+                    None => return false,
+                };
+                entry.set(frame, flags | PRESENT);
+                entry.set_cache_mode(cache, PageSize::Size4K);
+            },
+            _ => return false,
+        }
+
+        flush_tlb(page.start_address());
+        true
+    }
+
+    // The entry mapping the page, which is a level 3 or level 2 entry for huge pages.
+    fn entry_mut(&mut self, page: Page) -> Option<(&mut Entry, PageSize)> {
+        let l3 = match self.l4.next_table_mut(page.l4_index()) {
+            Some(l3) => l3,
+            None => return None,
+        };
+
+        if l3[page.l3_index()].flags().contains(PRESENT | HUGE_PAGE) {
+            return Some((&mut l3[page.l3_index()], PageSize::Size1G));
+        }
+
+        let l2 = match l3.next_table_mut(page.l3_index()) {
+            Some(l2) => l2,
+            None => return None,
+        };
+
+        if l2[page.l2_index()].flags().contains(PRESENT | HUGE_PAGE) {
+            return Some((&mut l2[page.l2_index()], PageSize::Size2M));
+        }
+
+        l2.next_table_mut(page.l2_index()).map(|l1| (&mut l1[page.l1_index()], PageSize::Size4K))
+    }
+
+    fn next_or_create<'b, L, A>(table: &'b mut Table<L>, index: usize, allocator: &mut A) -> Result<&'b mut Table<L::NextLevel>, MapError>
+        where L: HierarchicalLevel, A: FrameAllocator
+    {
+        if table[index].flags().contains(PRESENT | HUGE_PAGE) {
+            return Err(MapError::HugePage);
+        }
+
+        table.next_table_create(index, allocator).ok_or(MapError::OutOfFrames)
+    }
+}
+
+// Host tests run their tables in user mode, where `invlpg` faults, and never activate them anyway.
+#[inline]
+fn flush_tlb(address: VirtualAddress) {
+    if !cfg!(test) {
+        tlb::flush(address);
+    }
+}
+
+/// `NO_EXECUTE` if the processor supports it, nothing otherwise.
+/// Tables using it must not be activated before `platform::enable_nx()`.
+pub fn no_execute() -> EntryFlags {
+    if platform::has_nx() { NO_EXECUTE } else { EntryFlags::empty() }
+}
+
+/// Maps the physical range `start..end` at its place in the flat map, write-back, using the largest pages possible.
+/// With 1 GiB pages, every 512 GiB of memory costs a single table.
+pub fn map_flat_range<A: FrameAllocator>(mapper: &mut Mapper, start: u64, end: u64, allocator: &mut A) -> Result<(), MapError> {
+    assert!(end as usize <= FLAT_MEMORY_END - FLAT_MEMORY_START, "Physical memory doesn't fit into the flat map.");
+    mapper.map_range(VirtualAddress(FLAT_MEMORY_START + start as usize), PhysicalAddress(start), (end - start) as usize,
+                     WRITABLE | GLOBAL | no_execute(), CacheMode::WriteBack, allocator)
+}
+
 impl<L> Index<usize> for Table<L> where L: TableLevel {
     type Output = Entry;
 
@@ -181,3 +722,143 @@ impl<L> IndexMut<usize> for Table<L> where L: TableLevel {
 }
 
 
+
+#[test]
+fn test_cache_mode_entry_bits() {
+    let modes = [
+        CacheMode::WriteBack, CacheMode::WriteThrough, CacheMode::Uncached,
+        CacheMode::UncachedMinus, CacheMode::WriteCombining, CacheMode::WriteProtected,
+    ];
+
+    for &programmed in [false, true].iter() {
+        for &mode in modes.iter() {
+            let expected = mode.effective(programmed);
+
+            // Level 1 entries have the PAT bit where the huge page flag is elsewhere.
+            let entry = Entry(0x7000 | (PRESENT | WRITABLE).bits() | mode.entry_bits_with(PageSize::Size4K, programmed));
+            assert_eq!(CacheMode::from_entry_bits_with(entry.0, PageSize::Size4K, programmed), expected);
+            assert_eq!(entry.page_frame_address(PageSize::Size4K), Some(PhysicalAddress(0x7000)));
+
+            // Huge entries have it in the lowest bit of the frame address.
+            for &size in [PageSize::Size2M, PageSize::Size1G].iter() {
+                let bits = mode.entry_bits_with(size, programmed);
+                let entry = Entry(0x4000_0000 | (PRESENT | WRITABLE | HUGE_PAGE).bits() | bits);
+                assert_eq!(CacheMode::from_entry_bits_with(entry.0, size, programmed), expected);
+                assert_eq!(entry.page_frame_address(size), Some(PhysicalAddress(0x4000_0000)));
+                assert_eq!(entry.flags(), PRESENT | WRITABLE | HUGE_PAGE | EntryFlags::from_bits_truncate(bits));
+            }
+        }
+    }
+
+    // Without the PAT, the PAT bit is never set, and reads back as the power-on defaults.
+    assert_eq!(CacheMode::WriteCombining.entry_bits_with(PageSize::Size4K, false), NO_CACHE.bits());
+    assert_eq!(CacheMode::WriteProtected.entry_bits_with(PageSize::Size2M, false), (WRITE_THROUGH | NO_CACHE).bits());
+    assert_eq!(CacheMode::from_entry_bits_with(PAT_BIT_4K, PageSize::Size4K, false), CacheMode::WriteBack);
+    assert_eq!(CacheMode::WriteCombining.entry_bits_with(PageSize::Size4K, true), PAT_BIT_4K);
+    assert_eq!(CacheMode::WriteProtected.entry_bits_with(PageSize::Size1G, true), PAT_BIT_HUGE | WRITE_THROUGH.bits());
+}
+
+#[cfg(test)]
+#[repr(C, align(4096))]
+struct TestFrames([u8; 16 * 4096]);
+
+// Outside the kernel, physical memory is "identity mapped", so tables can live anywhere.
+#[cfg(test)]
+fn test_frame_allocator(frames: &TestFrames) -> ::memory::bump_alloc::BumpAllocator {
+    let start = frames.0.as_ptr() as u64;
+    ::memory::bump_alloc::BumpAllocator::new(start, start + frames.0.len() as u64)
+}
+
+#[test]
+fn test_map_translate_unmap() {
+    let test_frames = TestFrames([0; 16 * 4096]);
+    let mut frames = test_frame_allocator(&test_frames);
+    let (_, l4) = L4Table::create(&mut frames).unwrap();
+    let mut mapper = unsafe { Mapper::new(l4) };
+
+    let page = Page::containing_address(VirtualAddress(0x1234_5000));
+    mapper.map_to(page, PhysicalAddress(0x9000), WRITABLE, &mut frames).unwrap();
+    // A level 3, 2 and 1 table.
+    assert_eq!(frames.remaining().1, 16 - 4);
+
+    assert_eq!(mapper.translate(VirtualAddress(0x1234_5678)), Some(PhysicalAddress(0x9678)));
+    assert_eq!(mapper.lookup(VirtualAddress(0x1234_5000)), Some((PhysicalAddress(0x9000), PageSize::Size4K, PRESENT | WRITABLE)));
+    assert_eq!(mapper.translate(VirtualAddress(0x1234_6000)), None);
+
+    // The neighbour shares all tables.
+    let next = Page::containing_address(VirtualAddress(0x1234_6000));
+    mapper.map_to(next, PhysicalAddress(0x3000), EntryFlags::empty(), &mut frames).unwrap();
+    assert_eq!(frames.remaining().1, 16 - 4);
+    assert_eq!(mapper.map_to(page, PhysicalAddress(0xa000), WRITABLE, &mut frames), Err(MapError::AlreadyMapped));
+
+    assert!(mapper.protect(next, WRITABLE | NO_EXECUTE));
+    assert_eq!(mapper.lookup(VirtualAddress(0x1234_6000)), Some((PhysicalAddress(0x3000), PageSize::Size4K, PRESENT | WRITABLE | NO_EXECUTE)));
+
+    assert_eq!(mapper.unmap(page), Some((PhysicalAddress(0x9000), PageSize::Size4K)));
+    assert_eq!(mapper.translate(VirtualAddress(0x1234_5678)), None);
+    assert_eq!(mapper.unmap(page), None);
+    assert!(!mapper.protect(page, WRITABLE));
+    assert_eq!(mapper.translate(VirtualAddress(0x1234_6fff)), Some(PhysicalAddress(0x3fff)));
+
+    // Nothing is left for the tables of another level 4 entry, and nothing is mapped halfway.
+    while frames.alloc_frames(0).is_some() {}
+    let far = Page::containing_address(VirtualAddress(0xffff_8000_0000_0000));
+    assert_eq!(mapper.map_to(far, PhysicalAddress(0x9000), WRITABLE, &mut frames), Err(MapError::OutOfFrames));
+    assert_eq!(mapper.translate(far.start_address()), None);
+}
+
+#[test]
+fn test_map_range_page_sizes() {
+    let test_frames = TestFrames([0; 16 * 4096]);
+    let mut frames = test_frame_allocator(&test_frames);
+    let (_, l4) = L4Table::create(&mut frames).unwrap();
+    let mut mapper = unsafe { Mapper::new(l4) };
+    let huge = if PageSize::Size1G.is_supported() { PageSize::Size1G } else { PageSize::Size2M };
+
+    // Both aligned: a 1 GiB page, then 2 MiB and 4 KiB for the rest.
+    mapper.map_range(VirtualAddress(0), PhysicalAddress(0x4000_0000), 0x4020_1000, WRITABLE, CacheMode::WriteBack, &mut frames).unwrap();
+    assert_eq!(mapper.lookup(VirtualAddress(0x3fff_f000)).map(|(_, size, _)| size), Some(huge));
+    assert_eq!(mapper.lookup(VirtualAddress(0x4000_0000)), Some((PhysicalAddress(0x8000_0000), PageSize::Size2M, PRESENT | WRITABLE | HUGE_PAGE)));
+    assert_eq!(mapper.lookup(VirtualAddress(0x4020_0000)), Some((PhysicalAddress(0x8020_0000), PageSize::Size4K, PRESENT | WRITABLE)));
+    assert_eq!(mapper.translate(VirtualAddress(0x4012_3456)), Some(PhysicalAddress(0x8012_3456)));
+
+    // The virtual address is aligned, but the physical one only to 4 KiB.
+    mapper.map_range(VirtualAddress(0x8000_0000), PhysicalAddress(0x20_1000), 0x20_0000, WRITABLE, CacheMode::WriteBack, &mut frames).unwrap();
+    assert_eq!(mapper.lookup(VirtualAddress(0x8000_0000)).map(|(_, size, _)| size), Some(PageSize::Size4K));
+    assert_eq!(mapper.translate(VirtualAddress(0x801f_f000)), Some(PhysicalAddress(0x40_0000)));
+
+    // Both misaligned by the same amount: 4 KiB pages up to the first 2 MiB boundary, then a huge page.
+    mapper.map_range(VirtualAddress(0xc000_1000), PhysicalAddress(0x1000), 0x40_0000, WRITABLE, CacheMode::WriteBack, &mut frames).unwrap();
+    assert_eq!(mapper.lookup(VirtualAddress(0xc01f_f000)).map(|(_, size, _)| size), Some(PageSize::Size4K));
+    assert_eq!(mapper.lookup(VirtualAddress(0xc020_0000)).map(|(_, size, _)| size), Some(PageSize::Size2M));
+    assert_eq!(mapper.lookup(VirtualAddress(0xc040_0000)).map(|(_, size, _)| size), Some(PageSize::Size4K));
+    assert_eq!(mapper.translate(VirtualAddress(0xc040_0000)), Some(PhysicalAddress(0x40_0000)));
+
+    // Inside huge pages, there are no tables to put smaller pages into.
+    let inside = Page::containing_address(VirtualAddress(0x4000_5000));
+    assert_eq!(mapper.map_to(inside, PhysicalAddress(0x5000), WRITABLE, &mut frames), Err(MapError::HugePage));
+    if huge == PageSize::Size1G {
+        assert_eq!(mapper.map_sized(VirtualAddress(0x20_0000), PhysicalAddress(0), PageSize::Size2M, WRITABLE, CacheMode::WriteBack, &mut frames),
+                   Err(MapError::HugePage));
+    } else {
+        assert_eq!(mapper.map_sized(VirtualAddress(0x4000_0000), PhysicalAddress(0), PageSize::Size1G, WRITABLE, CacheMode::WriteBack, &mut frames),
+                   Err(MapError::UnsupportedPageSize));
+    }
+
+    // A huge page can't replace a huge page, nor a table of smaller pages.
+    assert_eq!(mapper.map_sized(VirtualAddress(0x4000_0000), PhysicalAddress(0), PageSize::Size2M, WRITABLE, CacheMode::WriteBack, &mut frames),
+               Err(MapError::AlreadyMapped));
+    assert_eq!(mapper.map_sized(VirtualAddress(0xc000_0000), PhysicalAddress(0), PageSize::Size2M, WRITABLE, CacheMode::WriteBack, &mut frames),
+               Err(MapError::AlreadyMapped));
+
+    // Unmapping a part of a huge page takes all of it, and nothing else.
+    assert_eq!(mapper.unmap(inside), Some((PhysicalAddress(0x8000_0000), PageSize::Size2M)));
+    assert_eq!(mapper.translate(VirtualAddress(0x4000_0000)), None);
+    assert_eq!(mapper.translate(VirtualAddress(0x401f_ffff)), None);
+    assert_eq!(mapper.translate(VirtualAddress(0x3fff_ffff)), Some(PhysicalAddress(0x7fff_ffff)));
+    assert_eq!(mapper.translate(VirtualAddress(0x4020_0000)), Some(PhysicalAddress(0x8020_0000)));
+
+    // Its place can take a table now.
+    mapper.map_to(inside, PhysicalAddress(0x5000), WRITABLE, &mut frames).unwrap();
+    assert_eq!(mapper.translate(VirtualAddress(0x4000_5000)), Some(PhysicalAddress(0x5000)));
+}
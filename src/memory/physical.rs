@@ -0,0 +1,256 @@
+use core::cmp;
+use core::slice;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin;
+
+use x86_64::PhysicalAddress;
+
+use console;
+use memory::buddy_alloc::BuddyAllocator;
+use memory::bump_alloc::BumpAllocator;
+use memory::frame::FrameAllocator;
+use memory::kernel_space::KernelTables;
+use memory::paging::{phys_to_virt, MapError};
+use platform;
+use uefi;
+
+pub const FRAME_SIZE: usize = 4096;
+
+// Size of the pool reserved from UEFI for allocations made before we own the memory.
+const EARLY_POOL_FRAMES: usize = 1024;
+
+/// The final UEFI memory map, as it was when boot services were terminated.
+pub struct MemoryMap {
+    raw: uefi::RawMemoryMap,
+}
+
+unsafe impl Send for MemoryMap {}
+unsafe impl Sync for MemoryMap {}
+
+impl MemoryMap {
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.raw.len()
+    }
+
+    #[inline]
+    pub fn get(&self, index: usize) -> uefi::MemoryDescriptor {
+        self.raw.get(index)
+    }
+
+    pub fn iter<'a>(&'a self) -> MemoryMapIter<'a> {
+        MemoryMapIter { map: self, index: 0 }
+    }
+}
+
+pub struct MemoryMapIter<'a> {
+    map: &'a MemoryMap,
+    index: usize,
+}
+
+impl<'a> Iterator for MemoryMapIter<'a> {
+    type Item = uefi::MemoryDescriptor;
+
+    fn next(&mut self) -> Option<uefi::MemoryDescriptor> {
+        if self.index >= self.map.len() {
+            return None;
+        }
+
+        self.index += 1;
+        Some(self.map.get(self.index - 1))
+    }
+}
+
+/// The frame allocator currently in charge.
+enum Frames {
+    // Boot services are still up, frames come from a pool reserved from UEFI.
+    Early(BumpAllocator),
+    // All of the memory is ours.
+    Buddy(BuddyAllocator<'static>),
+}
+
+impl FrameAllocator for Frames {
+    fn alloc_frames(&mut self, order: usize) -> Option<PhysicalAddress> {
+        match *self {
+            Frames::Early(ref mut bump) => bump.alloc_frames(order),
+            Frames::Buddy(ref mut buddy) => buddy.alloc_frames(order),
+        }
+    }
+
+    fn free_frames(&mut self, address: PhysicalAddress, order: usize) {
+        match *self {
+            Frames::Early(ref mut bump) => bump.free_frames(address, order),
+            Frames::Buddy(ref mut buddy) => buddy.free_frames(address, order),
+        }
+    }
+
+    fn alloc_frames_below(&mut self, order: usize, limit: PhysicalAddress) -> Option<PhysicalAddress> {
+        match *self {
+            Frames::Early(ref mut bump) => bump.alloc_frames_below(order, limit),
+            Frames::Buddy(ref mut buddy) => buddy.alloc_frames_below(order, limit),
+        }
+    }
+}
+
+static MEMORY_MAP: spin::Once<MemoryMap> = spin::Once::new();
+static FRAMES: spin::Mutex<Option<Frames>> = spin::Mutex::new(None);
+
+// Whether boot services memory was handed to the frame allocator yet.
+static BOOT_SERVICES_RELEASED: AtomicBool = AtomicBool::new(false);
+
+/// Handle to the system-wide frame allocator.
+/// Each call takes a global lock, so it must not be used from within another frame allocator.
+/// Allocations fail before `init_early()`.
+pub struct SystemFrames;
+
+impl FrameAllocator for SystemFrames {
+    fn alloc_frames(&mut self, order: usize) -> Option<PhysicalAddress> {
+        FRAMES.lock().as_mut().and_then(|frames| frames.alloc_frames(order))
+    }
+
+    fn free_frames(&mut self, address: PhysicalAddress, order: usize) {
+        FRAMES.lock().as_mut().expect("Frame allocator is not initialized.").free_frames(address, order)
+    }
+
+    fn alloc_frames_below(&mut self, order: usize, limit: PhysicalAddress) -> Option<PhysicalAddress> {
+        FRAMES.lock().as_mut().and_then(|frames| frames.alloc_frames_below(order, limit))
+    }
+}
+
+/// Returns the memory map, or `None` if we don't own the memory yet.
+pub fn memory_map() -> Option<&'static MemoryMap> {
+    MEMORY_MAP.try()
+}
+
+/// Whether the memory was already taken over from UEFI.
+pub fn is_initialized() -> bool {
+    match *FRAMES.lock() {
+        Some(Frames::Buddy(_)) => true,
+        _ => false,
+    }
+}
+
+/// Reserves a pool of memory from UEFI, and serves frame allocations from it
+/// until `take_over_memory()` is called.
+pub unsafe fn init_early(system_table: *const uefi::SystemTable) -> Result<(), uefi::Status> {
+    let start = try!(uefi::allocate_pages(system_table, EARLY_POOL_FRAMES));
+    *FRAMES.lock() = Some(Frames::Early(BumpAllocator::new(start, start + (EARLY_POOL_FRAMES * FRAME_SIZE) as u64)));
+    Ok(())
+}
+
+#[inline]
+unsafe fn flat_mapped_slice<'a>(address: u64, words: usize) -> &'a mut [u64] {
+    slice::from_raw_parts_mut(phys_to_virt(PhysicalAddress(address)) as *mut u64, words)
+}
+
+/// Terminates UEFI boot services, switches to the kernel's own page tables, and takes ownership of all memory.
+///
+/// Everything that UEFI reported as conventional memory is handed to the frame allocator,
+/// as is the unused part of the early pool. Boot services code/data still holds our stack
+/// and UEFI's GDT and IDT, it is handed over later, by `release_boot_services_memory()`.
+/// Everything else (runtime services, ACPI tables, MMIO, etc.) is left alone,
+/// as is memory allocated by us as the loader.
+///
+/// If this fails before boot services are terminated, UEFI's state (including its page tables) is unchanged.
+/// After this returns successfully, no UEFI boot services may be used anymore.
+pub unsafe fn take_over_memory(system_table: *const uefi::SystemTable, image_handle: uefi::Handle) -> Result<(), uefi::Status> {
+    let mut map = uefi::RawMemoryMap::empty();
+
+    // First look at the preliminary map, to find out how large the frame allocator needs to be,
+    // and what the flat mapping has to cover.
+    try!(uefi::get_memory_map(system_table, &mut map));
+
+    let stack_pointer = platform::stack_pointer() as u64;
+    let mut stack = (PhysicalAddress(0), 0);
+    let mut limit: usize = 0;
+
+    for i in 0..map.len() {
+        let desc = map.get(i);
+        if desc.is_usable() && desc.physical_end() as usize / FRAME_SIZE > limit {
+            limit = desc.physical_end() as usize / FRAME_SIZE;
+        }
+
+        if desc.physical_start <= stack_pointer && stack_pointer < desc.physical_end() {
+            stack = (PhysicalAddress(desc.physical_start), (desc.physical_end() - desc.physical_start) as usize);
+        }
+    }
+
+    assert!(stack.1 != 0, "The stack is not in the memory map.");
+
+    let bitmap_words = BuddyAllocator::bitmap_size(limit);
+    let tree_words = BuddyAllocator::tree_size(limit);
+    let bitmap_address = try!(uefi::allocate_pages(system_table, (bitmap_words * 8 + FRAME_SIZE - 1) / FRAME_SIZE));
+    let tree_address = try!(uefi::allocate_pages(system_table, (tree_words * 8 + FRAME_SIZE - 1) / FRAME_SIZE));
+
+    let tables = match KernelTables::build(&map, stack, &mut SystemFrames) {
+        Ok(tables) => tables,
+        Err(MapError::OutOfFrames) => return Err(uefi::OUT_OF_RESOURCES),
+        Err(err) => panic!("Failed to build the kernel page tables: {:?}.", err),
+    };
+
+    // The allocations above modified the map, so the final map is fetched by `exit_boot_services()`.
+    try!(uefi::exit_boot_services(system_table, image_handle, &mut map));
+    // The firmware's console went with boot services.
+    console::switch_to_serial();
+
+    // Nothing but the image, the stack and the upper half is mapped from here on.
+    tables.activate();
+    map.buffer = phys_to_virt(PhysicalAddress(map.buffer as u64)) as *mut u8;
+
+    let mut frames = BuddyAllocator::new(limit, flat_mapped_slice(bitmap_address, bitmap_words),
+                                         flat_mapped_slice(tree_address, tree_words), FRAME_SIZE);
+
+    for i in 0..map.len() {
+        let desc = map.get(i);
+        if desc.memory_type != uefi::CONVENTIONAL_MEMORY {
+            // Boot services memory is still in use, see `release_boot_services_memory()`.
+            continue;
+        }
+
+        if let Some((start, end)) = usable_frames(&desc, limit) {
+            frames.dealloc(start, end - start);
+        }
+    }
+
+    let mut current = FRAMES.lock();
+
+    if let Some(Frames::Early(ref bump)) = *current {
+        let (address, count) = bump.remaining();
+        let start = address.0 as usize / FRAME_SIZE;
+        let end = cmp::min(start + count, limit);
+        if start < end {
+            frames.dealloc(start, end - start);
+        }
+    }
+
+    *current = Some(Frames::Buddy(frames));
+    drop(current);
+    MEMORY_MAP.call_once(|| MemoryMap { raw: map });
+    Ok(())
+}
+
+// The frames of a usable descriptor that the frame allocator covers, if any.
+fn usable_frames(desc: &uefi::MemoryDescriptor, limit: usize) -> Option<(usize, usize)> {
+    // Never hand out the zero frame, so that a null physical address is always a bug.
+    let start = if desc.physical_start == 0 { 1 } else { desc.physical_start as usize / FRAME_SIZE };
+    let end = cmp::min(desc.physical_end() as usize / FRAME_SIZE, limit);
+    if start < end { Some((start, end)) } else { None }
+}
+
+/// Hands the boot services memory to the frame allocator. It holds the UEFI stack, and the GDT and IDT
+/// UEFI left loaded, so this must only be called once we run on a different stack, with our own tables loaded.
+pub unsafe fn release_boot_services_memory() {
+    if BOOT_SERVICES_RELEASED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    let map = memory_map().expect("The memory is not taken over yet.");
+    if let Some(Frames::Buddy(ref mut frames)) = *FRAMES.lock() {
+        let limit = frames.limit();
+        for desc in map.iter().filter(|desc| desc.is_usable() && desc.memory_type != uefi::CONVENTIONAL_MEMORY) {
+            if let Some((start, end)) = usable_frames(&desc, limit) {
+                frames.dealloc(start, end - start);
+            }
+        }
+    }
+}
@@ -0,0 +1,287 @@
+use core::mem;
+use core::ptr::null_mut;
+
+use alloc::allocator::{Alloc, Layout, AllocErr};
+
+// Power-of-two size classes from 16 B to 2 KiB.
+pub const MIN_CLASS_SIZE: usize = 16;
+pub const MAX_CLASS_SIZE: usize = 2048;
+pub const CLASS_COUNT: usize = 8;
+
+// Every slab is aligned to its size, so the header of the slab
+// an object belongs to is found by simply masking the object's address.
+pub const SLAB_SIZE: usize = 16 * 1024;
+
+#[repr(C)]
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+#[repr(C)]
+struct Slab {
+    // Objects that were freed back into this slab.
+    free: *mut FreeObject,
+    // Offset of the first object that was never handed out.
+    bump: usize,
+    in_use: usize,
+    class: usize,
+
+    // Links in the list of slabs with free objects.
+    prev: *mut Slab,
+    next: *mut Slab,
+}
+
+impl Slab {
+    #[inline]
+    fn address(&self) -> usize {
+        self as *const Slab as usize
+    }
+
+    #[inline]
+    fn is_full(&self) -> bool {
+        self.free == null_mut() && self.bump >= SLAB_SIZE
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SlabClassStats {
+    pub object_size: usize,
+    pub slabs: usize,
+    pub objects_in_use: usize,
+    pub objects_free: usize,
+}
+
+#[derive(Copy, Clone)]
+struct SizeClass {
+    partial: *mut Slab,
+    stats: SlabClassStats,
+}
+
+impl SizeClass {
+    const fn new(object_size: usize) -> Self {
+        SizeClass {
+            partial: 0 as *mut Slab,
+            stats: SlabClassStats { object_size: object_size, slabs: 0, objects_in_use: 0, objects_free: 0 },
+        }
+    }
+
+    #[inline]
+    fn object_size(&self) -> usize {
+        self.stats.object_size
+    }
+
+    // Offset of the first object in a slab. The header occupies the front.
+    #[inline]
+    fn first_offset(&self) -> usize {
+        let hdr = mem::size_of::<Slab>();
+        ((hdr + self.object_size() - 1) / self.object_size()) * self.object_size()
+    }
+
+    #[inline]
+    fn objects_per_slab(&self) -> usize {
+        (SLAB_SIZE - self.first_offset()) / self.object_size()
+    }
+
+    unsafe fn link(&mut self, slab: *mut Slab) {
+        (*slab).prev = null_mut();
+        (*slab).next = self.partial;
+        if self.partial != null_mut() {
+            (*self.partial).prev = slab;
+        }
+        self.partial = slab;
+    }
+
+    unsafe fn unlink(&mut self, slab: *mut Slab) {
+        if (*slab).prev != null_mut() {
+            (*(*slab).prev).next = (*slab).next;
+        } else {
+            debug_assert!(self.partial == slab);
+            self.partial = (*slab).next;
+        }
+
+        if (*slab).next != null_mut() {
+            (*(*slab).next).prev = (*slab).prev;
+        }
+
+        (*slab).prev = null_mut();
+        (*slab).next = null_mut();
+    }
+}
+
+/// Size-class allocator for small objects.
+///
+/// Objects of each class are carved out of `SLAB_SIZE` slabs obtained from a backing allocator.
+/// Slabs with free objects are kept in a per-class list, so both allocation and deallocation
+/// are O(1). A slab that becomes completely empty is returned to the backing allocator,
+/// unless it is the last one with free space in its class.
+pub struct SlabAllocator {
+    classes: [SizeClass; CLASS_COUNT],
+}
+
+unsafe impl Send for SlabAllocator {}
+
+impl SlabAllocator {
+    pub const fn new() -> Self {
+        SlabAllocator {
+            classes: [
+                SizeClass::new(16), SizeClass::new(32), SizeClass::new(64), SizeClass::new(128),
+                SizeClass::new(256), SizeClass::new(512), SizeClass::new(1024), SizeClass::new(2048),
+            ],
+        }
+    }
+
+    /// Returns the size class serving the layout, or `None` if it's too large for slabs.
+    #[inline]
+    pub fn class_of(layout: &Layout) -> Option<usize> {
+        let size = if layout.size() > layout.align() { layout.size() } else { layout.align() };
+
+        if size > MAX_CLASS_SIZE {
+            return None;
+        }
+
+        if size <= MIN_CLASS_SIZE {
+            return Some(0);
+        }
+
+        let bits = mem::size_of::<usize>() * 8 - (size - 1).leading_zeros() as usize;
+        Some(bits - 4)
+    }
+
+    #[inline]
+    fn slab_layout() -> Layout {
+        Layout::from_size_align(SLAB_SIZE, SLAB_SIZE).unwrap()
+    }
+
+    pub unsafe fn alloc<A: Alloc>(&mut self, class: usize, backing: &mut A) -> Result<*mut u8, AllocErr> {
+        let cls = &mut self.classes[class];
+
+        if cls.partial == null_mut() {
+            let slab = try!(backing.alloc(Self::slab_layout())) as *mut Slab;
+            *slab = Slab { free: null_mut(), bump: cls.first_offset(), in_use: 0, class: class, prev: null_mut(), next: null_mut() };
+            cls.link(slab);
+
+            cls.stats.slabs += 1;
+            cls.stats.objects_free += cls.objects_per_slab();
+        }
+
+        let slab = &mut *cls.partial;
+        debug_assert!(slab.class == class);
+
+        let object = if slab.free != null_mut() {
+            let object = slab.free;
+            slab.free = (*object).next;
+            object as *mut u8
+        } else {
+            let object = slab.address() + slab.bump;
+            slab.bump += cls.object_size();
+            object as *mut u8
+        };
+
+        slab.in_use += 1;
+        cls.stats.objects_in_use += 1;
+        cls.stats.objects_free -= 1;
+
+        if slab.is_full() {
+            cls.unlink(slab);
+        }
+
+        Ok(object)
+    }
+
+    pub unsafe fn dealloc<A: Alloc>(&mut self, ptr: *mut u8, class: usize, backing: &mut A) {
+        let slab = (ptr as usize & !(SLAB_SIZE - 1)) as *mut Slab;
+        assert!((*slab).class == class, "Object freed with a layout of a different size class.");
+
+        let cls = &mut self.classes[class];
+        let was_full = (*slab).is_full();
+
+        let object = ptr as *mut FreeObject;
+        (*object).next = (*slab).free;
+        (*slab).free = object;
+
+        (*slab).in_use -= 1;
+        cls.stats.objects_in_use -= 1;
+        cls.stats.objects_free += 1;
+
+        if was_full {
+            cls.link(slab);
+        }
+
+        if (*slab).in_use == 0 && !(cls.partial == slab && (*slab).next == null_mut()) {
+            cls.unlink(slab);
+            cls.stats.slabs -= 1;
+            cls.stats.objects_free -= cls.objects_per_slab();
+            backing.dealloc(slab as *mut u8, Self::slab_layout());
+        }
+    }
+
+    pub fn stats(&self) -> [SlabClassStats; CLASS_COUNT] {
+        let mut stats = [SlabClassStats::default(); CLASS_COUNT];
+        for i in 0..CLASS_COUNT {
+            stats[i] = self.classes[i].stats;
+        }
+        stats
+    }
+}
+
+#[test]
+fn test_slab_size_classes() {
+    let class = |size, align| SlabAllocator::class_of(&Layout::from_size_align(size, align).unwrap());
+
+    assert_eq!(class(1, 1), Some(0));
+    assert_eq!(class(16, 8), Some(0));
+    assert_eq!(class(17, 8), Some(1));
+    assert_eq!(class(8, 64), Some(2));
+    assert_eq!(class(2048, 16), Some(7));
+    assert_eq!(class(2049, 16), None);
+}
+
+#[cfg(test)]
+#[repr(C, align(16384))]
+struct TestArena([u8; 8 * SLAB_SIZE]);
+
+#[test]
+fn test_slab_alloc_and_release() {
+    use memory::list_alloc_simple::ListAlloc;
+
+    let mut arena = TestArena([0; 8 * SLAB_SIZE]);
+    let mut backing = ListAlloc::new();
+    let mut slab = SlabAllocator::new();
+
+    unsafe {
+        backing.provide(arena.0.as_mut_ptr(), 8 * SLAB_SIZE);
+
+        let per_slab = slab.classes[7].objects_per_slab();
+        assert_eq!(per_slab, 7);
+
+        let mut objects = [null_mut(); 14];
+        for i in 0..14 {
+            objects[i] = slab.alloc(7, &mut backing).unwrap();
+            assert_eq!(objects[i] as usize % 2048, 0);
+        }
+
+        assert_eq!(slab.stats()[7].slabs, 2);
+        assert_eq!(slab.stats()[7].objects_in_use, 14);
+        assert_eq!(slab.stats()[7].objects_free, 0);
+
+        // Emptying the first slab returns it, since the second one still has room.
+        slab.dealloc(objects[13], 7, &mut backing);
+        for i in 0..7 {
+            slab.dealloc(objects[i], 7, &mut backing);
+        }
+        assert_eq!(slab.stats()[7].slabs, 1);
+        assert_eq!(slab.stats()[7].objects_free, 1);
+
+        // The last slab with free space is kept around.
+        for i in 7..13 {
+            slab.dealloc(objects[i], 7, &mut backing);
+        }
+        assert_eq!(slab.stats()[7].slabs, 1);
+        assert_eq!(slab.stats()[7].objects_in_use, 0);
+        assert_eq!(slab.stats()[7].objects_free, 7);
+
+        // Freed objects are reused.
+        let a = slab.alloc(7, &mut backing).unwrap();
+        assert_eq!(a, objects[12]);
+    }
+}
@@ -0,0 +1,253 @@
+//! Kernel virtual address ranges that are neither in the flat map nor in the image,
+//! for stacks, MMIO windows, module images and the like.
+
+use spin;
+
+use alloc::btree_map::BTreeMap;
+
+use x86_64::{PhysicalAddress, VirtualAddress};
+
+use memory::kernel_space::KERNEL_BASE;
+use memory::paging::{self, Mapper, MapError, Page, EntryFlags, CacheMode, PAGE_SIZE, FLAT_MEMORY_END, WRITABLE, GLOBAL};
+use memory::physical::SystemFrames;
+
+/// Start of the window for kernel regions, right past the largest possible flat map.
+pub const REGIONS_START: usize = FLAT_MEMORY_END;
+/// End of the window, where the kernel image begins.
+pub const REGIONS_END: usize = KERNEL_BASE;
+
+/// Unmapped space kept on each side of every kernel region.
+pub const GUARD_SIZE: usize = PAGE_SIZE;
+
+#[inline]
+fn align_up(address: usize, align: usize) -> usize {
+    (address + align - 1) & !(align - 1)
+}
+
+/// Hands out page-aligned ranges of a virtual address window.
+/// Every region is surrounded by `guard` bytes that are never handed out,
+/// so that running off either end of a region faults instead of hitting the neighbour.
+///
+/// Free ranges live in a B-tree keyed by start address, and are merged with their neighbours on release.
+/// The allocator only deals in addresses, it doesn't map anything.
+pub struct VirtualRegionAllocator {
+    guard: usize,
+    // Start -> end of every free range.
+    free: BTreeMap<usize, usize>,
+    // Start -> size of every reserved region, not counting the guards.
+    reserved: BTreeMap<usize, usize>,
+}
+
+impl VirtualRegionAllocator {
+    pub fn new(start: usize, end: usize, guard: usize) -> Self {
+        assert!((start | end | guard) % PAGE_SIZE == 0 && start < end);
+
+        let mut free = BTreeMap::new();
+        free.insert(start, end);
+        VirtualRegionAllocator { guard: guard, free: free, reserved: BTreeMap::new() }
+    }
+
+    /// Reserves `size` bytes, rounded up to whole pages, with the start aligned to `align`.
+    /// Takes the lowest range that fits.
+    pub fn reserve(&mut self, size: usize, align: usize) -> Option<VirtualAddress> {
+        assert!(size > 0 && align.is_power_of_two());
+
+        let size = align_up(size, PAGE_SIZE);
+        let align = if align < PAGE_SIZE { PAGE_SIZE } else { align };
+        let guard = self.guard;
+
+        let found = self.free.iter()
+            .map(|(&start, &end)| (start, end, align_up(start + guard, align)))
+            .find(|&(_, end, region)| region < end && end - region >= size + guard);
+
+        let (start, end, region) = match found {
+            Some(found) => found,
+            None => return None,
+        };
+
+        self.free.remove(&start);
+        if start < region - guard {
+            self.free.insert(start, region - guard);
+        }
+        if region + size + guard < end {
+            self.free.insert(region + size + guard, end);
+        }
+
+        self.reserved.insert(region, size);
+        Some(VirtualAddress(region))
+    }
+
+    /// Releases a region returned by `reserve()`, together with its guards. Returns the size of the region.
+    pub fn release(&mut self, address: VirtualAddress) -> usize {
+        let size = match self.reserved.remove(&address.0) {
+            Some(size) => size,
+            None => panic!("Releasing {:#x}, which is not a reserved region.", address.0),
+        };
+
+        let mut start = address.0 - self.guard;
+        let mut end = address.0 + size + self.guard;
+
+        let previous = self.free.range(..start).next_back().map(|(&s, &e)| (s, e));
+        if let Some((previous_start, previous_end)) = previous {
+            if previous_end == start {
+                self.free.remove(&previous_start);
+                start = previous_start;
+            }
+        }
+        if let Some(next_end) = self.free.remove(&end) {
+            end = next_end;
+        }
+
+        self.free.insert(start, end);
+        size
+    }
+
+    /// The reserved region containing `address`, as start and size.
+    pub fn region_containing(&self, address: VirtualAddress) -> Option<(VirtualAddress, usize)> {
+        match self.reserved.range(..address.0 + 1).next_back() {
+            Some((&start, &size)) if address.0 < start + size => Some((VirtualAddress(start), size)),
+            _ => None,
+        }
+    }
+}
+
+static KERNEL_REGIONS: spin::Mutex<Option<VirtualRegionAllocator>> = spin::Mutex::new(None);
+
+// The allocator lives on the heap, so it's only created on first use.
+fn with_kernel_regions<T, F>(func: F) -> T
+    where F: FnOnce(&mut VirtualRegionAllocator) -> T
+{
+    let mut regions = KERNEL_REGIONS.lock();
+    if regions.is_none() {
+        *regions = Some(VirtualRegionAllocator::new(REGIONS_START, REGIONS_END, GUARD_SIZE));
+    }
+
+    match *regions {
+        Some(ref mut regions) => func(regions),
+        None => unreachable!(),
+    }
+}
+
+/// Reserves a kernel region, without mapping anything into it.
+pub fn reserve(size: usize, align: usize) -> Option<VirtualAddress> {
+    with_kernel_regions(|regions| regions.reserve(size, align))
+}
+
+/// Releases a kernel region. Whatever is mapped in it must have been unmapped already.
+pub fn release(address: VirtualAddress) {
+    with_kernel_regions(|regions| { regions.release(address); })
+}
+
+/// Maps `frames`, in order, into a fresh kernel region, and returns its start.
+/// Only valid on the kernel's own tables.
+pub unsafe fn vmap(frames: &[PhysicalAddress], flags: EntryFlags) -> Result<VirtualAddress, MapError> {
+    assert!(!frames.is_empty());
+
+    // The lock also serializes changes to the tables covering the window.
+    with_kernel_regions(|regions| {
+        let start = match regions.reserve(frames.len() * PAGE_SIZE, PAGE_SIZE) {
+            Some(start) => start,
+            None => return Err(MapError::OutOfAddressSpace),
+        };
+
+        let mut mapper = Mapper::current();
+        for (i, &frame) in frames.iter().enumerate() {
+            let page = Page::containing_address(VirtualAddress(start.0 + i * PAGE_SIZE));
+
+            if let Err(err) = mapper.map_to(page, frame, flags, &mut SystemFrames) {
+                for j in 0..i {
+                    mapper.unmap(Page::containing_address(VirtualAddress(start.0 + j * PAGE_SIZE)));
+                }
+                regions.release(start);
+                return Err(err);
+            }
+        }
+
+        Ok(start)
+    })
+}
+
+/// Maps `size` bytes of device memory at `address` into a fresh kernel region, and returns the virtual address
+/// corresponding to `address`. The flat map is write-back, so devices must always be accessed through here.
+pub unsafe fn map_mmio(address: PhysicalAddress, size: usize, cache: CacheMode) -> Result<VirtualAddress, MapError> {
+    let offset = address.0 as usize % PAGE_SIZE;
+    let frame = PhysicalAddress(address.0 - offset as u64);
+    let size = align_up(offset + size, PAGE_SIZE);
+
+    with_kernel_regions(|regions| {
+        let start = match regions.reserve(size, PAGE_SIZE) {
+            Some(start) => start,
+            None => return Err(MapError::OutOfAddressSpace),
+        };
+
+        let mut mapper = Mapper::current();
+        let flags = WRITABLE | GLOBAL | paging::no_execute();
+        if let Err(err) = mapper.map_range(start, frame, size, flags, cache, &mut SystemFrames) {
+            for i in 0..size / PAGE_SIZE {
+                mapper.unmap(Page::containing_address(VirtualAddress(start.0 + i * PAGE_SIZE)));
+            }
+            regions.release(start);
+            return Err(err);
+        }
+
+        Ok(VirtualAddress(start.0 + offset))
+    })
+}
+
+/// Unmaps a region created by `map_mmio()`, given the address it returned.
+pub unsafe fn unmap_mmio(address: VirtualAddress) {
+    vunmap(VirtualAddress(address.0 & !(PAGE_SIZE - 1)));
+}
+
+/// Unmaps a region created by `vmap()` and releases it. The frames still belong to the caller.
+pub unsafe fn vunmap(address: VirtualAddress) {
+    with_kernel_regions(|regions| {
+        let size = regions.release(address);
+
+        let mut mapper = Mapper::current();
+        for i in 0..size / PAGE_SIZE {
+            mapper.unmap(Page::containing_address(VirtualAddress(address.0 + i * PAGE_SIZE)));
+        }
+    })
+}
+
+/// Changes the flags of the `size` bytes at `address`, inside a region created by `vmap()`.
+pub unsafe fn protect(address: VirtualAddress, size: usize, flags: EntryFlags) {
+    with_kernel_regions(|_| {
+        let mut mapper = Mapper::current();
+        for i in 0..align_up(size, PAGE_SIZE) / PAGE_SIZE {
+            let page = Page::containing_address(VirtualAddress(address.0 + i * PAGE_SIZE));
+            assert!(mapper.protect(page, flags), "Protecting {:#x}, which is not mapped.", page.start_address().0);
+        }
+    })
+}
+
+#[test]
+fn test_virtual_regions_guards_and_merging() {
+    let start = 0x10_0000;
+    let mut regions = VirtualRegionAllocator::new(start, start + 64 * PAGE_SIZE, PAGE_SIZE);
+
+    let a = regions.reserve(1, 1).unwrap();
+    let b = regions.reserve(3 * PAGE_SIZE, 1).unwrap();
+    assert_eq!(a.0, start + PAGE_SIZE);
+    // One guard after `a`, one before `b`.
+    assert_eq!(b.0, a.0 + 3 * PAGE_SIZE);
+
+    assert_eq!(regions.region_containing(VirtualAddress(b.0 + 5)), Some((b, 3 * PAGE_SIZE)));
+    assert_eq!(regions.region_containing(VirtualAddress(b.0 - 1)), None);
+
+    let c = regions.reserve(PAGE_SIZE, 16 * PAGE_SIZE).unwrap();
+    assert_eq!(c.0 % (16 * PAGE_SIZE), 0);
+    assert!(c.0 - PAGE_SIZE >= b.0 + 4 * PAGE_SIZE);
+
+    // Too large for what's left.
+    assert_eq!(regions.reserve(60 * PAGE_SIZE, 1), None);
+
+    assert_eq!(regions.release(b), 3 * PAGE_SIZE);
+    assert_eq!(regions.release(a), PAGE_SIZE);
+    assert_eq!(regions.release(c), PAGE_SIZE);
+
+    // Everything merged back into one range.
+    assert_eq!(regions.free.len(), 1);
+    assert_eq!(regions.reserve(62 * PAGE_SIZE, 1).unwrap().0, start + PAGE_SIZE);
+}
@@ -0,0 +1,248 @@
+//! Parsing of ELF64 headers. Nothing is trusted: every offset and size is checked against the file
+//! before it's used, and structures are read unaligned, out of whatever buffer the file is in.
+
+use core::mem;
+use core::ptr;
+
+use memory::paging::PAGE_SIZE;
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_DYN: u16 = 3;
+const EM_X86_64: u16 = 62;
+
+pub const PT_LOAD: u32 = 1;
+pub const PT_DYNAMIC: u32 = 2;
+pub const PT_TLS: u32 = 7;
+pub const PT_GNU_RELRO: u32 = 0x6474_e552;
+
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct Elf64_Ehdr {
+    e_ident: [u8; 16],
+    e_type: u16,
+    e_machine: u16,
+    e_version: u32,
+    e_entry: u64,
+    e_phoff: u64,
+    e_shoff: u64,
+    e_flags: u32,
+    e_ehsize: u16,
+    e_phentsize: u16,
+    e_phnum: u16,
+    e_shentsize: u16,
+    e_shnum: u16,
+    e_shstrndx: u16,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct Elf64_Phdr {
+    pub p_type: u32,
+    pub p_flags: u32,
+    pub p_offset: u64,
+    pub p_vaddr: u64,
+    pub p_paddr: u64,
+    pub p_filesz: u64,
+    pub p_memsz: u64,
+    pub p_align: u64,
+}
+
+impl Elf64_Phdr {
+    /// End of the segment in memory. Only valid once the file is checked.
+    #[inline]
+    pub fn end(&self) -> u64 {
+        self.p_vaddr + self.p_memsz
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ElfError {
+    /// The file ends before a header or segment does.
+    Truncated,
+    /// Not an ELF file at all.
+    BadMagic,
+    /// Not a little endian, 64-bit file of the current version.
+    BadIdent,
+    /// Not a position independent x86-64 file.
+    WrongType,
+    /// Program headers of a size other than `Elf64_Phdr`.
+    BadPhentsize,
+    /// Program header `index` is inconsistent.
+    BadSegment { index: usize },
+}
+
+// The `T` at `offset` in `data`, if it's all there.
+fn read<T: Copy>(data: &[u8], offset: u64) -> Result<T, ElfError> {
+    let end = match offset.checked_add(mem::size_of::<T>() as u64) {
+        Some(end) => end,
+        None => return Err(ElfError::Truncated),
+    };
+    if end > data.len() as u64 {
+        return Err(ElfError::Truncated);
+    }
+    Ok(unsafe { ptr::read_unaligned(data.as_ptr().offset(offset as isize) as *const T) })
+}
+
+/// An ELF file whose headers have been checked.
+pub struct ElfFile<'a> {
+    data: &'a [u8],
+    header: Elf64_Ehdr,
+}
+
+impl<'a> ElfFile<'a> {
+    /// Checks the file header, and every program header that matters for loading.
+    /// Segments of the types we handle must be consistent and lie within the file,
+    /// loadable segments must be in order and may not share pages.
+    pub fn parse(data: &'a [u8]) -> Result<ElfFile<'a>, ElfError> {
+        let header: Elf64_Ehdr = try!(read(data, 0));
+
+        if header.e_ident[..4] != ELF_MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if header.e_ident[4] != ELFCLASS64 || header.e_ident[5] != ELFDATA2LSB || header.e_ident[6] != EV_CURRENT {
+            return Err(ElfError::BadIdent);
+        }
+        if header.e_type != ET_DYN || header.e_machine != EM_X86_64 {
+            return Err(ElfError::WrongType);
+        }
+        if header.e_phentsize as usize != mem::size_of::<Elf64_Phdr>() {
+            return Err(ElfError::BadPhentsize);
+        }
+
+        let file = ElfFile { data: data, header: header };
+
+        // The last header has to be there, the others are in between.
+        if header.e_phnum != 0 {
+            try!(file.segment(header.e_phnum as usize - 1));
+        }
+
+        let mut previous_end = 0;
+        for (index, segment) in file.segments().enumerate() {
+            match segment.p_type {
+                PT_LOAD | PT_DYNAMIC | PT_TLS | PT_GNU_RELRO => {},
+                _ => continue,
+            }
+
+            let bad = Err(ElfError::BadSegment { index: index });
+            let file_end = segment.p_offset.checked_add(segment.p_filesz);
+            let memory_end = segment.p_vaddr.checked_add(segment.p_memsz);
+            match (file_end, memory_end) {
+                (Some(file_end), Some(_)) if file_end <= data.len() as u64 => {},
+                (Some(_), Some(_)) => return Err(ElfError::Truncated),
+                _ => return bad,
+            }
+            if segment.p_filesz > segment.p_memsz || (segment.p_align != 0 && !segment.p_align.is_power_of_two()) {
+                return bad;
+            }
+
+            if segment.p_type == PT_LOAD && segment.p_memsz != 0 {
+                // Permissions are per page, so two segments sharing one can't both get theirs.
+                if segment.p_vaddr < previous_end {
+                    return bad;
+                }
+                previous_end = match segment.end().checked_add(PAGE_SIZE as u64 - 1) {
+                    Some(end) => end & !(PAGE_SIZE as u64 - 1),
+                    None => return bad,
+                };
+            }
+        }
+
+        Ok(file)
+    }
+
+    /// Program header `index`.
+    pub fn segment(&self, index: usize) -> Result<Elf64_Phdr, ElfError> {
+        let offset = (index as u64).checked_mul(mem::size_of::<Elf64_Phdr>() as u64)
+            .and_then(|offset| offset.checked_add(self.header.e_phoff));
+        match offset {
+            Some(offset) => read(self.data, offset),
+            None => Err(ElfError::Truncated),
+        }
+    }
+
+    pub fn segments<'b>(&'b self) -> Segments<'a, 'b> {
+        Segments { file: self, index: 0 }
+    }
+
+    /// The contents of a checked segment in the file.
+    pub fn segment_data(&self, segment: &Elf64_Phdr) -> &'a [u8] {
+        &self.data[segment.p_offset as usize..(segment.p_offset + segment.p_filesz) as usize]
+    }
+
+    #[inline]
+    pub fn entry(&self) -> u64 {
+        self.header.e_entry
+    }
+}
+
+pub struct Segments<'a: 'b, 'b> {
+    file: &'b ElfFile<'a>,
+    index: usize,
+}
+
+impl<'a, 'b> Iterator for Segments<'a, 'b> {
+    type Item = Elf64_Phdr;
+
+    fn next(&mut self) -> Option<Elf64_Phdr> {
+        if self.index >= self.file.header.e_phnum as usize {
+            return None;
+        }
+        self.index += 1;
+        self.file.segment(self.index - 1).ok()
+    }
+}
+
+#[cfg(test)]
+pub fn test_file(segments: &[(u32, u64, u64, u64)]) -> [u8; 512] {
+    let mut data = [0; 512];
+    let header = Elf64_Ehdr {
+        e_ident: [0x7f, b'E', b'L', b'F', ELFCLASS64, ELFDATA2LSB, EV_CURRENT, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        e_type: ET_DYN, e_machine: EM_X86_64, e_version: 1, e_entry: 0, e_phoff: 64, e_shoff: 0, e_flags: 0,
+        e_ehsize: 64, e_phentsize: 56, e_phnum: segments.len() as u16, e_shentsize: 0, e_shnum: 0, e_shstrndx: 0,
+    };
+    unsafe {
+        ptr::write_unaligned(data.as_mut_ptr() as *mut Elf64_Ehdr, header);
+        for (i, &(p_type, offset, vaddr, size)) in segments.iter().enumerate() {
+            let segment = Elf64_Phdr { p_type: p_type, p_flags: 0, p_offset: offset, p_vaddr: vaddr, p_paddr: 0,
+                                       p_filesz: size, p_memsz: size, p_align: 0x1000 };
+            ptr::write_unaligned(data.as_mut_ptr().offset(64 + i as isize * 56) as *mut Elf64_Phdr, segment);
+        }
+    }
+    data
+}
+
+#[test]
+fn test_elf_parse() {
+    let data = test_file(&[(PT_LOAD, 0, 0, 0x100), (PT_LOAD, 0x100, 0x1100, 0x100), (PT_DYNAMIC, 0x100, 0x1100, 0x40)]);
+    let file = ElfFile::parse(&data).unwrap();
+    assert_eq!(file.segments().count(), 3);
+    assert_eq!(file.segment_data(&file.segment(1).unwrap()).len(), 0x100);
+
+    assert_eq!(ElfFile::parse(&data[..100]).err(), Some(ElfError::Truncated));
+    assert_eq!(ElfFile::parse(&data[1..]).err(), Some(ElfError::BadMagic));
+
+    let mut data = data;
+    data[54] = 64;
+    assert_eq!(ElfFile::parse(&data).err(), Some(ElfError::BadPhentsize));
+}
+
+#[test]
+fn test_elf_rejects_bad_segments() {
+    // Past the end of the file.
+    let data = test_file(&[(PT_LOAD, 0x100, 0, 0x200)]);
+    assert_eq!(ElfFile::parse(&data).err(), Some(ElfError::Truncated));
+
+    // Wrapping around.
+    let data = test_file(&[(PT_LOAD, 0, !0 - 0x10, 0x100)]);
+    assert_eq!(ElfFile::parse(&data).err(), Some(ElfError::BadSegment { index: 0 }));
+
+    // Sharing a page with the previous segment.
+    let data = test_file(&[(PT_LOAD, 0, 0, 0x100), (PT_LOAD, 0x100, 0x800, 0x100)]);
+    assert_eq!(ElfFile::parse(&data).err(), Some(ElfError::BadSegment { index: 1 }));
+}
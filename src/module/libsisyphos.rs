@@ -0,0 +1,210 @@
+//! `libsisyphos`, the library modules link against to use the kernel. There is no file behind it:
+//! undefined symbols in a module are resolved against the table here, while it's being relocated.
+//!
+//! The ABI is versioned as a whole, `sis_abi_version()` returns the major version in the upper 16 bits,
+//! and the minor version in the lower ones. Symbols are only ever added, which bumps the minor version,
+//! and each one records the minor version it appeared in. Any other change bumps the major version.
+//!
+//! Calls that can fail return a negative `SIS_E*` code. There is no scheduler yet, every module runs
+//! on the CPU that started it, so the thread calls only cover what that allows.
+
+use core::ptr;
+use core::slice;
+use core::str;
+
+use alloc::allocator::{Alloc, Layout};
+
+use console;
+use ipc::{self, IpcError};
+use percpu;
+use relocate::SymbolResolver;
+
+pub const ABI_MAJOR: u32 = 1;
+pub const ABI_MINOR: u32 = 0;
+
+pub const SIS_ENOCHANNEL: i64 = -1;
+pub const SIS_EEMPTY: i64 = -2;
+pub const SIS_EFULL: i64 = -3;
+pub const SIS_ETOOSMALL: i64 = -4;
+pub const SIS_EINVAL: i64 = -5;
+
+// Offset of the TLS block size in the thread control block, see `module::create_tls()`.
+pub const TCB_TLS_OFFSET: usize = 8;
+
+pub struct Export {
+    pub name: &'static str,
+    /// Minor version the symbol appeared in.
+    pub since: u32,
+    pub address: u64,
+}
+
+// Function addresses can't go into a static, so the table is put together on every lookup.
+fn exports() -> [Export; 14] {
+    let export = |name, since, address: usize| Export { name: name, since: since, address: address as u64 };
+    [
+        export("sis_abi_version", 0, sis_abi_version as usize),
+        export("sis_abi_minor", 0, sis_abi_minor as usize),
+        // Memory
+        export("sis_alloc", 0, sis_alloc as usize),
+        export("sis_free", 0, sis_free as usize),
+        export("sis_realloc", 0, sis_realloc as usize),
+        // Threads
+        export("sis_thread_id", 0, sis_thread_id as usize),
+        export("sis_yield", 0, sis_yield as usize),
+        export("__tls_get_addr", 0, tls_get_addr as usize),
+        // IPC
+        export("sis_channel_create", 0, sis_channel_create as usize),
+        export("sis_channel_destroy", 0, sis_channel_destroy as usize),
+        export("sis_channel_send", 0, sis_channel_send as usize),
+        export("sis_channel_receive", 0, sis_channel_receive as usize),
+        // Console
+        export("sis_console_write", 0, sis_console_write as usize),
+        export("sis_console_write_line", 0, sis_console_write_line as usize),
+    ]
+}
+
+/// Resolves symbols against the library.
+pub struct KernelLibrary;
+
+impl SymbolResolver for KernelLibrary {
+    fn resolve(&self, name: &str) -> Option<u64> {
+        exports().iter().find(|export| export.name == name).map(|export| export.address)
+    }
+}
+
+// A buffer passed in by a module. Empty buffers may come with any pointer.
+unsafe fn buffer<'a>(data: *const u8, len: usize) -> Option<&'a [u8]> {
+    if len == 0 {
+        Some(&[])
+    } else if data.is_null() {
+        None
+    } else {
+        Some(slice::from_raw_parts(data, len))
+    }
+}
+
+fn ipc_error(err: IpcError) -> i64 {
+    match err {
+        IpcError::NoSuchChannel => SIS_ENOCHANNEL,
+        IpcError::Empty => SIS_EEMPTY,
+        IpcError::Full => SIS_EFULL,
+        IpcError::BufferTooSmall(_) => SIS_ETOOSMALL,
+    }
+}
+
+extern "C" fn sis_abi_version() -> u32 {
+    ABI_MAJOR << 16 | ABI_MINOR
+}
+
+/// The minor version a symbol appeared in, so that modules can check for optional parts of the ABI.
+extern "C" fn sis_abi_minor(symbol: *const u8, len: usize) -> i64 {
+    let name = match unsafe { buffer(symbol, len) }.and_then(|name| str::from_utf8(name).ok()) {
+        Some(name) => name,
+        None => return SIS_EINVAL,
+    };
+    match exports().iter().find(|export| export.name == name) {
+        Some(export) => export.since as i64,
+        None => SIS_EINVAL,
+    }
+}
+
+/// Returns null if out of memory, or if `size` is 0 or `align` isn't a power of two.
+unsafe extern "C" fn sis_alloc(size: usize, align: usize) -> *mut u8 {
+    match Layout::from_size_align(size, align) {
+        Some(layout) if size != 0 => (&::ALLOCATOR).alloc(layout).unwrap_or(ptr::null_mut()),
+        _ => ptr::null_mut(),
+    }
+}
+
+/// `size` and `align` must be what the block was allocated with.
+unsafe extern "C" fn sis_free(block: *mut u8, size: usize, align: usize) {
+    if block.is_null() {
+        return;
+    }
+    if let Some(layout) = Layout::from_size_align(size, align) {
+        (&::ALLOCATOR).dealloc(block, layout);
+    }
+}
+
+/// Returns null if the block can't be resized, in which case it stays allocated.
+unsafe extern "C" fn sis_realloc(block: *mut u8, size: usize, align: usize, new_size: usize) -> *mut u8 {
+    if block.is_null() {
+        return sis_alloc(new_size, align);
+    }
+    match (Layout::from_size_align(size, align), Layout::from_size_align(new_size, align)) {
+        (Some(layout), Some(new_layout)) if new_size != 0 =>
+            (&::ALLOCATOR).realloc(block, layout, new_layout).unwrap_or(ptr::null_mut()),
+        _ => ptr::null_mut(),
+    }
+}
+
+/// With one thread per CPU, the CPU number.
+extern "C" fn sis_thread_id() -> u64 {
+    percpu::current().map_or(0, |cpu| cpu.cpu_id as u64)
+}
+
+extern "C" fn sis_yield() {
+    unsafe { asm!("pause" :::: "volatile"); }
+}
+
+/// Address of a TLS variable, given its module ID and offset.
+/// A module only ever sees its own TLS block, the one right below the thread pointer.
+unsafe extern "C" fn tls_get_addr(index: *const [u64; 2]) -> *mut u8 {
+    let thread_pointer: u64;
+    asm!("mov %fs:0, $0" : "=r"(thread_pointer));
+    let block_size = *((thread_pointer as usize + TCB_TLS_OFFSET) as *const u64);
+    (thread_pointer - block_size + (*index)[1]) as usize as *mut u8
+}
+
+extern "C" fn sis_channel_create() -> u64 {
+    ipc::create()
+}
+
+extern "C" fn sis_channel_destroy(channel: u64) -> i64 {
+    match ipc::destroy(channel) {
+        Ok(()) => 0,
+        Err(err) => ipc_error(err),
+    }
+}
+
+unsafe extern "C" fn sis_channel_send(channel: u64, message: *const u8, len: usize) -> i64 {
+    let message = match buffer(message, len) {
+        Some(message) => message,
+        None => return SIS_EINVAL,
+    };
+    match ipc::send(channel, message) {
+        Ok(()) => 0,
+        Err(err) => ipc_error(err),
+    }
+}
+
+/// Returns the size of the message received.
+unsafe extern "C" fn sis_channel_receive(channel: u64, data: *mut u8, len: usize) -> i64 {
+    let target: &mut [u8] = match buffer(data, len) {
+        Some(_) if len == 0 => &mut [],
+        Some(_) => slice::from_raw_parts_mut(data, len),
+        None => return SIS_EINVAL,
+    };
+    match ipc::receive(channel, target) {
+        Ok(size) => size as i64,
+        Err(err) => ipc_error(err),
+    }
+}
+
+/// Writes UTF-8 text to the kernel console, which is the serial port by the time modules run.
+unsafe extern "C" fn sis_console_write(text: *const u8, len: usize) -> i64 {
+    let text = match buffer(text, len).and_then(|text| str::from_utf8(text).ok()) {
+        Some(text) => text,
+        None => return SIS_EINVAL,
+    };
+    let _ = console::out().write_str(text);
+    0
+}
+
+unsafe extern "C" fn sis_console_write_line(text: *const u8, len: usize) -> i64 {
+    let result = sis_console_write(text, len);
+    if result == 0 {
+        let _ = console::out().write_str("\n");
+    }
+    result
+}
@@ -0,0 +1,431 @@
+//! Kernel modules: position independent ELF64 programs, loaded into a kernel region and run in kernel space.
+//!
+//! A module's entry point is `extern "C" fn(cmdline: *const u8, cmdline_len: usize) -> i64`.
+
+pub mod elf;
+pub mod libsisyphos;
+
+use core::cmp;
+use core::fmt;
+use core::ptr;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use x86_64::{PhysicalAddress, VirtualAddress};
+use x86_64::registers::msr;
+
+use boot_modules;
+use console;
+use memory::frame::FrameAllocator;
+use memory::kernel_stack::KernelStack;
+use memory::paging::{self, MapError, EntryFlags, PAGE_SIZE, WRITABLE, GLOBAL};
+use memory::physical::SystemFrames;
+use memory::virtual_region;
+use relocate::{self, RelocError, TlsModule};
+use self::libsisyphos::{KernelLibrary, TCB_TLS_OFFSET};
+use self::elf::{ElfFile, ElfError, Elf64_Phdr, PT_LOAD, PT_DYNAMIC, PT_TLS, PT_GNU_RELRO, PF_X, PF_W};
+
+const IA32_FS_BASE: u32 = 0xc000_0100;
+
+// Largest image we map, so that a bogus header can't eat up the kernel window.
+const MAX_IMAGE_SIZE: u64 = 256 * 1024 * 1024;
+
+const MODULE_STACK_PAGES: usize = 16;
+
+// Every module is linked on its own, so its TLS block is the only one it knows about.
+const TLS_MODULE_ID: u64 = 1;
+
+#[derive(Clone, Debug)]
+pub enum LoadError {
+    /// The headers are malformed.
+    Elf(ElfError),
+    /// There is nothing to load.
+    NoSegments,
+    /// The image would take more than `MAX_IMAGE_SIZE` bytes.
+    TooLarge(u64),
+    /// There is no dynamic section, so the module can't be relocated.
+    NoDynamic,
+    /// The entry point isn't in an executable segment.
+    BadEntry(u64),
+    /// The TLS segment lies outside of the image, or needs more than page alignment.
+    BadTls,
+    /// The module needs a symbol that `libsisyphos` doesn't export.
+    MissingSymbol { module: String, symbol: String },
+    Map(MapError),
+    Reloc(RelocError),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LoadError::Elf(err) => write!(f, "malformed ELF file ({:?})", err),
+            LoadError::NoSegments => write!(f, "no loadable segments"),
+            LoadError::TooLarge(size) => write!(f, "image of {} bytes is too large", size),
+            LoadError::NoDynamic => write!(f, "no dynamic section"),
+            LoadError::BadEntry(entry) => write!(f, "entry point {:#x} is not in an executable segment", entry),
+            LoadError::BadTls => write!(f, "bad TLS segment"),
+            LoadError::MissingSymbol { ref module, ref symbol } => write!(f, "{} needs undefined symbol {}", module, symbol),
+            LoadError::Map(err) => write!(f, "mapping failed ({:?})", err),
+            LoadError::Reloc(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<ElfError> for LoadError {
+    fn from(err: ElfError) -> LoadError {
+        LoadError::Elf(err)
+    }
+}
+
+impl From<MapError> for LoadError {
+    fn from(err: MapError) -> LoadError {
+        LoadError::Map(err)
+    }
+}
+
+#[inline]
+fn align_up(address: u64, align: u64) -> u64 {
+    (address + align - 1) & !(align - 1)
+}
+
+#[inline]
+fn align_down(address: u64, align: u64) -> u64 {
+    address & !(align - 1)
+}
+
+/// Fresh, zeroed frames mapped into a kernel region. Both go away with it.
+struct Mapping {
+    start: VirtualAddress,
+    frames: Vec<PhysicalAddress>,
+}
+
+impl Mapping {
+    fn new(pages: usize, flags: EntryFlags) -> Result<Mapping, MapError> {
+        let mut frames = Vec::with_capacity(pages);
+        for _ in 0..pages {
+            match SystemFrames.alloc_frames(0) {
+                Some(frame) => frames.push(frame),
+                None => {
+                    Mapping::free_frames(&frames);
+                    return Err(MapError::OutOfFrames);
+                }
+            }
+        }
+
+        let start = match unsafe { virtual_region::vmap(&frames, flags) } {
+            Ok(start) => start,
+            Err(err) => {
+                Mapping::free_frames(&frames);
+                return Err(err);
+            }
+        };
+
+        unsafe { ptr::write_bytes(start.0 as *mut u8, 0, pages * PAGE_SIZE); }
+        Ok(Mapping { start: start, frames: frames })
+    }
+
+    fn free_frames(frames: &[PhysicalAddress]) {
+        for &frame in frames {
+            SystemFrames.free_frames(frame, 0);
+        }
+    }
+
+    #[inline]
+    fn address(&self, offset: u64) -> u64 {
+        self.start.0 as u64 + offset
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe { virtual_region::vunmap(self.start); }
+        Mapping::free_frames(&self.frames);
+    }
+}
+
+/// A loaded and relocated module, ready to run.
+pub struct Module {
+    name: String,
+    image: Mapping,
+    // The TLS block and the thread pointer pointing right past it, if the module has one.
+    tls: Option<(Mapping, u64)>,
+    entry: u64,
+}
+
+// Page table flags for a segment.
+fn segment_flags(segment: &Elf64_Phdr) -> EntryFlags {
+    let mut flags = GLOBAL;
+    if segment.p_flags & PF_W != 0 {
+        flags |= WRITABLE;
+    }
+    if segment.p_flags & PF_X == 0 {
+        flags |= paging::no_execute();
+    }
+    flags
+}
+
+// The segments loading needs, checked before anything is mapped.
+struct ImageLayout {
+    size: u64,
+    dynamic: Elf64_Phdr,
+    tls: Option<Elf64_Phdr>,
+    relro: Option<Elf64_Phdr>,
+}
+
+fn check_layout(file: &ElfFile) -> Result<ImageLayout, LoadError> {
+    // The image starts at address 0 of the file, and ends with the last loadable segment.
+    let mut size = 0;
+    let (mut dynamic, mut tls, mut relro) = (None, None, None);
+    for segment in file.segments() {
+        match segment.p_type {
+            PT_LOAD if segment.p_memsz != 0 => size = cmp::max(size, align_up(segment.end(), PAGE_SIZE as u64)),
+            PT_DYNAMIC => dynamic = Some(segment),
+            PT_TLS => tls = Some(segment),
+            PT_GNU_RELRO => relro = Some(segment),
+            _ => {},
+        }
+    }
+
+    if size == 0 {
+        return Err(LoadError::NoSegments);
+    }
+    if size > MAX_IMAGE_SIZE {
+        return Err(LoadError::TooLarge(size));
+    }
+    let dynamic = match dynamic {
+        Some(dynamic) => dynamic,
+        None => return Err(LoadError::NoDynamic),
+    };
+
+    let entry = file.entry();
+    let executable = file.segments()
+        .any(|s| s.p_type == PT_LOAD && s.p_flags & PF_X != 0 && s.p_vaddr <= entry && entry < s.end());
+    if !executable {
+        return Err(LoadError::BadEntry(entry));
+    }
+
+    if let Some(ref tls) = tls {
+        if tls.p_align > PAGE_SIZE as u64 || tls.p_memsz > MAX_IMAGE_SIZE || tls.p_vaddr.checked_add(tls.p_filesz).map_or(true, |end| end > size) {
+            return Err(LoadError::BadTls);
+        }
+    }
+
+    Ok(ImageLayout { size: size, dynamic: dynamic, tls: tls, relro: relro })
+}
+
+// Sets up the TLS block of the initial thread, in the x86-64 layout: the block lies right below
+// the thread pointer, which points to the thread control block, whose first word points to itself.
+// `__tls_get_addr()` finds the block through its size, which the second word holds.
+// The initialization image is copied in later, once it's relocated.
+unsafe fn create_tls(tls: &Elf64_Phdr) -> Result<(Mapping, TlsModule), LoadError> {
+    let align = if tls.p_align == 0 { 1 } else { tls.p_align };
+    let offset = align_up(tls.p_memsz, align);
+    let pages = align_up(offset + TCB_TLS_OFFSET as u64 + 8, PAGE_SIZE as u64) as usize / PAGE_SIZE;
+    let block = try!(Mapping::new(pages, WRITABLE | GLOBAL | paging::no_execute()));
+
+    let thread_pointer = block.address(offset);
+    *(thread_pointer as *mut u64) = thread_pointer;
+    *((thread_pointer + TCB_TLS_OFFSET as u64) as *mut u64) = offset;
+
+    Ok((block, TlsModule { id: TLS_MODULE_ID, offset: offset }))
+}
+
+/// Loads the module in `data`, and relocates it against its own base,
+/// resolving the symbols it doesn't define against `libsisyphos`.
+/// Nothing in the file is trusted, malformed files are rejected without side effects.
+pub fn load(name: &str, data: &[u8]) -> Result<Module, LoadError> {
+    let file = try!(ElfFile::parse(data));
+    let ImageLayout { size: image_size, dynamic, tls: tls_segment, relro } = try!(check_layout(&file));
+    let entry = file.entry();
+
+    // Writable and executable while it's being put together, nobody else knows about it yet.
+    // IRELATIVE resolvers run during relocation, so the code has to be executable by then.
+    let image = try!(Mapping::new(image_size as usize / PAGE_SIZE, WRITABLE | GLOBAL));
+
+    unsafe {
+        for segment in file.segments().filter(|s| s.p_type == PT_LOAD) {
+            let contents = file.segment_data(&segment);
+            ptr::copy_nonoverlapping(contents.as_ptr(), image.address(segment.p_vaddr) as *mut u8, contents.len());
+        }
+
+        let tls = match tls_segment {
+            Some(ref segment) => Some(try!(create_tls(segment))),
+            None => None,
+        };
+
+        let dynamic_address = image.address(dynamic.p_vaddr) as *const u8;
+        let tls_module = tls.as_ref().map(|&(_, module)| module);
+        try!(relocate_image(name, image.start.0 as u64, image_size, dynamic_address, tls_module));
+
+        if let (Some(segment), Some(&(ref block, _))) = (tls_segment, tls.as_ref()) {
+            ptr::copy_nonoverlapping(image.address(segment.p_vaddr) as *const u8, block.address(0) as *mut u8, segment.p_filesz as usize);
+        }
+
+        // Whatever isn't covered by a segment stays mapped, but read-only.
+        virtual_region::protect(image.start, image_size as usize, GLOBAL | paging::no_execute());
+        for segment in file.segments().filter(|s| s.p_type == PT_LOAD && s.p_memsz != 0) {
+            let start = align_down(segment.p_vaddr, PAGE_SIZE as u64);
+            let end = align_up(segment.end(), PAGE_SIZE as u64);
+            virtual_region::protect(VirtualAddress(image.address(start) as usize), (end - start) as usize, segment_flags(&segment));
+        }
+
+        // Only whole pages can be made read-only, the rest of the last one stays writable.
+        if let Some(relro) = relro {
+            let start = align_down(relro.p_vaddr, PAGE_SIZE as u64);
+            let end = align_down(relro.end(), PAGE_SIZE as u64);
+            if start < end && end <= image_size {
+                virtual_region::protect(VirtualAddress(image.address(start) as usize), (end - start) as usize, GLOBAL | paging::no_execute());
+            }
+        }
+
+        Ok(Module {
+            name: String::from(name),
+            entry: image.address(entry),
+            image: image,
+            tls: tls.map(|(block, module)| { let thread_pointer = block.address(module.offset); (block, thread_pointer) }),
+        })
+    }
+}
+
+// Relocates the image of module `name` at `base` against `libsisyphos`.
+unsafe fn relocate_image(name: &str, base: u64, size: u64, dynamic: *const u8, tls: Option<TlsModule>) -> Result<(), LoadError> {
+    match relocate::relocate_with(base, size, dynamic, &KernelLibrary, tls) {
+        Ok(()) => Ok(()),
+        // The name points into the image, which goes away with the error.
+        Err(RelocError::UndefinedSymbol { name: symbol, .. }) =>
+            Err(LoadError::MissingSymbol { module: String::from(name), symbol: String::from(symbol) }),
+        Err(err) => Err(LoadError::Reloc(err)),
+    }
+}
+
+// Calls `entry` with two arguments, on the stack ending at `stack_top`, and switches back when it returns.
+unsafe fn call_on_stack(entry: u64, stack_top: usize, mut arg0: u64, mut arg1: u64) -> i64 {
+    let result: i64;
+    asm!("mov %rsp, %rbx
+          mov $3, %rsp
+          call *$4
+          mov %rbx, %rsp"
+         : "={rax}"(result), "+{rdi}"(arg0), "+{rsi}"(arg1)
+         : "r"(stack_top), "r"(entry)
+         : "rbx", "rcx", "rdx", "r8", "r9", "r10", "r11", "memory"
+         : "volatile");
+    result
+}
+
+impl Module {
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Where the image is mapped.
+    #[inline]
+    pub fn base(&self) -> VirtualAddress {
+        self.image.start
+    }
+
+    /// Runs the entry point with `cmdline`, on a fresh stack and with the module's TLS block installed,
+    /// and returns what it returns. There are no threads yet, so it runs to completion on the calling CPU.
+    pub fn start(&self, cmdline: &str) -> Result<i64, MapError> {
+        let stack = try!(KernelStack::new(MODULE_STACK_PAGES, "module"));
+
+        unsafe {
+            let fs_base = msr::rdmsr(IA32_FS_BASE);
+            if let Some((_, thread_pointer)) = self.tls {
+                msr::wrmsr(IA32_FS_BASE, thread_pointer);
+            }
+
+            let result = call_on_stack(self.entry, stack.top(), cmdline.as_ptr() as u64, cmdline.len() as u64);
+
+            msr::wrmsr(IA32_FS_BASE, fs_base);
+            Ok(result)
+        }
+    }
+}
+
+/// Loads and starts the modules read from the boot volume, one after the other, in the order they were listed.
+/// Failures are reported on the console and don't keep the others from starting.
+pub fn start_boot_modules() {
+    for index in 0..boot_modules::count() {
+        let boot_module = match boot_modules::get(index) {
+            Some(boot_module) => boot_module,
+            None => break,
+        };
+
+        let result = load(boot_module.name, boot_module.data)
+            .and_then(|module| module.start(boot_module.cmdline).map_err(LoadError::Map));
+        // Fetched only now, the module may have used the console itself.
+        let out = unsafe { console::out() };
+        let _ = match result {
+            Ok(status) => write!(out, "Boot module {} exited with {}.\n", boot_module.name, status),
+            Err(err) => write!(out, "Failed to start boot module {}: {}.\n", boot_module.name, err),
+        };
+    }
+}
+
+// `elf::test_file()`, with segment `executable` executable and the entry point at `entry`.
+#[cfg(test)]
+fn test_module(segments: &[(u32, u64, u64, u64)], executable: usize, entry: u64) -> [u8; 512] {
+    let mut data = elf::test_file(segments);
+    unsafe {
+        ptr::write_unaligned(data.as_mut_ptr().offset(24) as *mut u64, entry);
+        ptr::write_unaligned(data.as_mut_ptr().offset(64 + executable as isize * 56 + 4) as *mut u32, PF_X);
+    }
+    data
+}
+
+#[cfg(test)]
+fn test_check_layout(data: &[u8]) -> Result<ImageLayout, LoadError> {
+    check_layout(&ElfFile::parse(data).unwrap())
+}
+
+#[test]
+fn test_load_rejects_bad_layouts() {
+    let data = test_module(&[(PT_LOAD, 0, 0, 0x100)], 0, 0x10);
+    match test_check_layout(&data) {
+        Err(LoadError::NoDynamic) => {},
+        other => panic!("Unexpected result {:?}", other.err()),
+    }
+
+    // Outside of any segment, and in one that isn't executable.
+    let data = test_module(&[(PT_LOAD, 0, 0, 0x100), (PT_DYNAMIC, 0x100, 0x100, 0x40)], 0, 0x200);
+    match test_check_layout(&data) {
+        Err(LoadError::BadEntry(0x200)) => {},
+        other => panic!("Unexpected result {:?}", other.err()),
+    }
+    let data = test_module(&[(PT_LOAD, 0, 0, 0x100), (PT_LOAD, 0x100, 0x1000, 0x100), (PT_DYNAMIC, 0x100, 0x1000, 0x40)], 0, 0x1010);
+    match test_check_layout(&data) {
+        Err(LoadError::BadEntry(0x1010)) => {},
+        other => panic!("Unexpected result {:?}", other.err()),
+    }
+
+    let segments = [(PT_LOAD, 0, 0, 0x100), (PT_DYNAMIC, 0x100, 0x100, 0x40), (PT_TLS, 0x100, 0x100, 0x40)];
+    let mut data = test_module(&segments, 0, 0x10);
+    assert!(test_check_layout(&data).is_ok());
+
+    // Aligned to more than a page.
+    unsafe { ptr::write_unaligned(data.as_mut_ptr().offset(64 + 2 * 56 + 48) as *mut u64, 2 * PAGE_SIZE as u64); }
+    match test_check_layout(&data) {
+        Err(LoadError::BadTls) => {},
+        other => panic!("Unexpected result {:?}", other.err()),
+    }
+
+    // Past the end of the image.
+    let data = test_module(&[(PT_LOAD, 0, 0, 0x100), (PT_DYNAMIC, 0x100, 0x100, 0x40), (PT_TLS, 0x100, 0xff0, 0x100)], 0, 0x10);
+    match test_check_layout(&data) {
+        Err(LoadError::BadTls) => {},
+        other => panic!("Unexpected result {:?}", other.err()),
+    }
+}
+
+#[test]
+fn test_load_rejects_missing_symbol() {
+    // An R_X86_64_64 relocation against "ext", which `libsisyphos` doesn't export.
+    let mut image = relocate::test_image(&[(0x1c0, 1 | 1 << 32, 0)]);
+    let base = image.0.as_mut_ptr() as u64;
+    match unsafe { relocate_image("test.so", base, 0x200, base as *const u8, None) } {
+        Err(LoadError::MissingSymbol { ref module, ref symbol }) if module == "test.so" && symbol == "ext" => {},
+        other => panic!("Unexpected result {:?}", other),
+    }
+}
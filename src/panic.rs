@@ -1,13 +1,31 @@
 
-use efi_app;
 use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
 
-#[lang = "panic_fmt"]
-#[no_mangle]
+use console;
+use memory::page_dump;
+use memory::paging::L4Table;
+
+static DUMPED_PAGE_TABLES: AtomicBool = AtomicBool::new(false);
+
+#[cfg_attr(not(test), lang = "panic_fmt")]
+#[cfg_attr(not(test), no_mangle)]
 pub extern "C" fn panic_fmt(args: fmt::Arguments, s: &'static str, line: u32) -> ! {
-    let out: &mut fmt::Write = unsafe { efi_app::__fixme_temporary_out() };
-    out.write_fmt(format_args!("Panic in \'{}\' (line {}):\n", s, line));
-    out.write_fmt(args);
+    let out = unsafe { console::out() };
+    let _ = out.write_fmt(format_args!("Panic in \'{}\' (line {}):\n", s, line));
+    let _ = out.write_fmt(args);
+
+    // The heap may be what's broken, so only look at it if nobody holds the lock.
+    if let Some(stats) = ::ALLOCATOR.try_stats() {
+        let _ = out.write_fmt(format_args!("\n{}", stats));
+    }
+
+    // Only once, in case walking the tables is what panics.
+    if !DUMPED_PAGE_TABLES.swap(true, Ordering::SeqCst) {
+        let _ = out.write_fmt(format_args!("\nPage tables:\n"));
+        let _ = page_dump::dump(out, unsafe { L4Table::current() });
+    }
+
     loop{}
 }
 
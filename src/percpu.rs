@@ -0,0 +1,64 @@
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use x86_64::registers::msr;
+
+use memory::magazine::MagazineCache;
+
+const IA32_GS_BASE: u32 = 0xc000_0101;
+
+/// Data private to a single CPU. The GS base of each CPU points to its own instance.
+#[repr(C)]
+pub struct PerCpu {
+    // Must stay first, it's read through `%gs:0`.
+    self_ptr: *const PerCpu,
+    pub cpu_id: usize,
+
+    // Only accessed by the owning CPU, with interrupts disabled.
+    heap_cache: UnsafeCell<MagazineCache>,
+}
+
+unsafe impl Sync for PerCpu {}
+
+impl PerCpu {
+    pub const fn new(cpu_id: usize) -> Self {
+        PerCpu { self_ptr: 0 as *const PerCpu, cpu_id: cpu_id, heap_cache: UnsafeCell::new(MagazineCache::new()) }
+    }
+
+    /// The caller must have interrupts disabled, and must not hold another reference to the cache.
+    #[inline]
+    pub unsafe fn heap_cache(&self) -> &mut MagazineCache {
+        &mut *self.heap_cache.get()
+    }
+}
+
+static mut BOOT_CPU: PerCpu = PerCpu::new(0);
+
+// Set once the boot CPU has a valid GS base. Other CPUs must install theirs
+// before running any code that could allocate.
+static READY: AtomicBool = AtomicBool::new(false);
+
+/// Points GS base of the current CPU at `area`.
+pub unsafe fn install(area: &'static mut PerCpu) {
+    area.self_ptr = area;
+    msr::wrmsr(IA32_GS_BASE, area as *const PerCpu as u64);
+}
+
+pub unsafe fn init_boot_cpu() {
+    install(&mut BOOT_CPU);
+    READY.store(true, Ordering::Release);
+}
+
+/// Returns the data area of the current CPU, or `None` during early boot.
+#[inline]
+pub fn current() -> Option<&'static PerCpu> {
+    if !READY.load(Ordering::Acquire) {
+        return None;
+    }
+
+    unsafe {
+        let ptr: *const PerCpu;
+        asm!("mov %gs:0, $0" : "=r"(ptr) ::: "volatile");
+        Some(&*ptr)
+    }
+}
@@ -1,21 +1,27 @@
 
 use x86_64::registers::flags;
+use x86_64::registers::msr;
 use x86_64::instructions::interrupts;
 
+const IA32_EFER: u32 = 0xc000_0080;
+const EFER_NXE: u64 = 1 << 11;
+const CR0_WP: usize = 1 << 16;
+const CR4_PCIDE: usize = 1 << 17;
+
 pub fn uninterruptible<T, F> (func: F) -> T
     where F: FnOnce() -> T
 {
     let if_enabled = flags::flags().contains(flags::Flags::IF);
 
     unsafe {
-        x86_64::instructions::interrupts::disable();
+        interrupts::disable();
     }
 
     let result = func();
 
     if if_enabled {
         unsafe {
-            x86_64::instructions::interrupts::enable();
+            interrupts::enable();
         }
     }
 
@@ -23,3 +29,83 @@ pub fn uninterruptible<T, F> (func: F) -> T
 }
 
 
+
+/// Executes CPUID, returns `(eax, ebx, ecx, edx)`.
+pub fn cpuid(leaf: u32, subleaf: u32) -> (u32, u32, u32, u32) {
+    let (a, b, c, d): (u32, u32, u32, u32);
+    unsafe {
+        asm!("cpuid" : "={eax}"(a), "={ebx}"(b), "={ecx}"(c), "={edx}"(d) : "{eax}"(leaf), "{ecx}"(subleaf) :: "volatile");
+    }
+    (a, b, c, d)
+}
+
+/// Whether the processor supports 1 GiB pages.
+pub fn has_1g_pages() -> bool {
+    let (max_extended, _, _, _) = cpuid(0x8000_0000, 0);
+    max_extended >= 0x8000_0001 && cpuid(0x8000_0001, 0).3 & (1 << 26) != 0
+}
+
+/// Whether the processor supports the page attribute table.
+pub fn has_pat() -> bool {
+    cpuid(1, 0).3 & (1 << 16) != 0
+}
+
+/// Whether the processor supports the no-execute bit in page table entries.
+pub fn has_nx() -> bool {
+    let (max_extended, _, _, _) = cpuid(0x8000_0000, 0);
+    max_extended >= 0x8000_0001 && cpuid(0x8000_0001, 0).3 & (1 << 20) != 0
+}
+
+/// Sets EFER.NXE. Until then, the no-execute bit is reserved, and entries using it fault.
+pub unsafe fn enable_nx() {
+    msr::wrmsr(IA32_EFER, msr::rdmsr(IA32_EFER) | EFER_NXE);
+}
+
+/// Sets CR0.WP, so that read-only pages are enforced in supervisor mode too.
+pub unsafe fn enable_write_protect() {
+    let cr0: usize;
+    asm!("mov %cr0, $0" : "=r"(cr0) ::: "volatile");
+    asm!("mov $0, %cr0" :: "r"(cr0 | CR0_WP) : "memory" : "volatile");
+}
+
+/// Whether the processor supports process-context identifiers.
+pub fn has_pcid() -> bool {
+    cpuid(1, 0).2 & (1 << 17) != 0
+}
+
+/// Sets CR4.PCIDE. The low 12 bits of CR3 must be zero.
+pub unsafe fn enable_pcid() {
+    let cr4: usize;
+    asm!("mov %cr4, $0" : "=r"(cr4) ::: "volatile");
+    asm!("mov $0, %cr4" :: "r"(cr4 | CR4_PCIDE) : "memory" : "volatile");
+}
+
+/// Whether CR4.PCIDE is set.
+pub fn pcid_enabled() -> bool {
+    let cr4: usize;
+    unsafe {
+        asm!("mov %cr4, $0" : "=r"(cr4) ::: "volatile");
+    }
+    cr4 & CR4_PCIDE != 0
+}
+
+#[inline]
+pub unsafe fn outb(port: u16, value: u8) {
+    asm!("outb %al, %dx" :: "{dx}"(port), "{al}"(value) :: "volatile");
+}
+
+#[inline]
+pub unsafe fn inb(port: u16) -> u8 {
+    let value: u8;
+    asm!("inb %dx, %al" : "={al}"(value) : "{dx}"(port) :: "volatile");
+    value
+}
+
+#[inline(always)]
+pub fn stack_pointer() -> usize {
+    let rsp: usize;
+    unsafe {
+        asm!("mov %rsp, $0" : "=r"(rsp) ::: "volatile");
+    }
+    rsp
+}
@@ -1,67 +1,721 @@
+//! Dynamic relocation of ELF64 images, the kernel itself as well as loaded modules.
 
-use efi_app;
-use core::ptr;
+use core::fmt;
+use core::mem;
+use core::slice;
+use core::str;
 
 #[repr(C)]
 pub struct Elf64_Dyn {
-	d_tag: u64,
-	d_val: u64,
+    d_tag: u64,
+    d_val: u64,
 }
 
 #[repr(C)]
 pub struct Elf64_Rela {
-	r_offset: u64,
-	r_info: u64,
-	r_addend: u64,
+    r_offset: u64,
+    r_info: u64,
+    r_addend: u64,
 }
 
-const R_X86_64_RELATIVE: u64 = 8;
+#[repr(C)]
+pub struct Elf64_Sym {
+    st_name: u32,
+    st_info: u8,
+    st_other: u8,
+    st_shndx: u16,
+    pub st_value: u64,
+    pub st_size: u64,
+}
+
+impl Elf64_Sym {
+    #[inline]
+    pub fn is_defined(&self) -> bool {
+        self.st_shndx != SHN_UNDEF
+    }
+
+    #[inline]
+    fn is_weak(&self) -> bool {
+        self.st_info >> 4 == STB_WEAK
+    }
+}
+
+const R_X86_64_NONE: u32 = 0;
+const R_X86_64_64: u32 = 1;
+const R_X86_64_GLOB_DAT: u32 = 6;
+const R_X86_64_JUMP_SLOT: u32 = 7;
+const R_X86_64_RELATIVE: u32 = 8;
+const R_X86_64_DTPMOD64: u32 = 16;
+const R_X86_64_DTPOFF64: u32 = 17;
+const R_X86_64_TPOFF64: u32 = 18;
+const R_X86_64_IRELATIVE: u32 = 37;
+
 const DT_NULL: u64 = 0;
+const DT_PLTRELSZ: u64 = 2;
+const DT_HASH: u64 = 4;
+const DT_STRTAB: u64 = 5;
+const DT_SYMTAB: u64 = 6;
 const DT_RELA: u64 = 7;
 const DT_RELASZ: u64 = 8;
 const DT_RELAENT: u64 = 9;
+const DT_STRSZ: u64 = 10;
+const DT_JMPREL: u64 = 23;
+const DT_GNU_HASH: u64 = 0x6fff_fef5;
+
+const SHN_UNDEF: u16 = 0;
+const STB_WEAK: u8 = 2;
+
+#[derive(Copy, Clone, Debug)]
+pub enum RelocError {
+    /// The image has relocations, but no DT_RELA.
+    MissingRela,
+    /// DT_RELAENT doesn't match the size of `Elf64_Rela`.
+    BadRelaEnt(u64),
+    /// The dynamic section, or a table it points to, doesn't lie within the image.
+    TableOutOfImage { table: &'static str, offset: u64, size: u64 },
+    /// Relocation `index` refers to memory outside of the image.
+    OffsetOutOfImage { index: usize, offset: u64 },
+    /// Relocation `index` refers to a symbol past the end of the symbol table.
+    BadSymbol { index: usize, symbol: usize },
+    /// Relocation `index` has a type we don't know how to apply.
+    UnsupportedType { index: usize, kind: u32 },
+    /// Relocation `index` refers to a symbol that neither the image nor the resolver defines.
+    /// The name points into the image.
+    UndefinedSymbol { index: usize, name: &'static str },
+    /// Relocation `index` is a TLS relocation, but the image has no TLS block.
+    NoTlsBlock { index: usize },
+    /// An offset and size in `what` wrap around the address space.
+    Overflow { what: &'static str, offset: u64 },
+}
+
+impl fmt::Display for RelocError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RelocError::MissingRela => write!(f, "relocations without DT_RELA"),
+            RelocError::BadRelaEnt(size) => write!(f, "DT_RELAENT is {}, expected {}", size, mem::size_of::<Elf64_Rela>()),
+            RelocError::TableOutOfImage { table, offset, size } =>
+                write!(f, "{} at {:#x} ({} bytes) is outside of the image", table, offset, size),
+            RelocError::OffsetOutOfImage { index, offset } =>
+                write!(f, "relocation {} refers to {:#x}, outside of the image", index, offset),
+            RelocError::BadSymbol { index, symbol } =>
+                write!(f, "relocation {} refers to symbol {}, past the symbol table", index, symbol),
+            RelocError::UnsupportedType { index, kind } => write!(f, "relocation {} has unsupported type {}", index, kind),
+            RelocError::UndefinedSymbol { index, name } => write!(f, "relocation {} refers to undefined symbol {}", index, name),
+            RelocError::NoTlsBlock { index } => write!(f, "relocation {} needs a TLS block, but there is none", index),
+            RelocError::Overflow { what, offset } => write!(f, "{} at {:#x} overflows", what, offset),
+        }
+    }
+}
+
+/// Where symbols that an image uses, but doesn't define, come from.
+pub trait SymbolResolver {
+    /// The address of the symbol, or `None` if it's unknown.
+    fn resolve(&self, name: &str) -> Option<u64>;
+}
+
+/// Resolves nothing, for self-contained images like the kernel.
+pub struct NoSymbols;
+
+impl SymbolResolver for NoSymbols {
+    fn resolve(&self, _name: &str) -> Option<u64> {
+        None
+    }
+}
+
+/// Identifies the TLS block of the image being relocated.
+#[derive(Copy, Clone, Debug)]
+pub struct TlsModule {
+    /// Module ID, as passed to `__tls_get_addr()`.
+    pub id: u64,
+    /// Distance from the thread pointer down to the block, in the static TLS area.
+    pub offset: u64,
+}
+
+// How a range fails to lie within the image.
+enum Outside {
+    Overflow,
+    Beyond,
+}
+
+/// The parts of the dynamic section we care about. Tables are offsets into the image,
+/// those that are used for relocation are checked to lie within it.
+pub struct Dynamic {
+    base: u64,
+    size: u64,
+    rela: Option<u64>,
+    rela_size: u64,
+    rela_ent: u64,
+    jmprel: Option<u64>,
+    jmprel_size: u64,
+    symtab: Option<u64>,
+    strtab: Option<u64>,
+    strtab_size: u64,
+    hash: Option<u64>,
+    gnu_hash: Option<u64>,
+}
+
+fn sysv_hash(name: &[u8]) -> u32 {
+    let mut h: u32 = 0;
+    for &c in name {
+        h = (h << 4).wrapping_add(c as u32);
+        let g = h & 0xf000_0000;
+        if g != 0 {
+            h ^= g >> 24;
+        }
+        h &= !g;
+    }
+    h
+}
+
+fn gnu_hash(name: &[u8]) -> u32 {
+    name.iter().fold(5381u32, |h, &c| h.wrapping_mul(33).wrapping_add(c as u32))
+}
+
+// Where `len` bytes at `offset` end, if they lie within `size` bytes.
+fn check_range(offset: u64, len: u64, size: u64) -> Result<u64, Outside> {
+    match offset.checked_add(len) {
+        None => Err(Outside::Overflow),
+        Some(end) if end > size => Err(Outside::Beyond),
+        Some(end) => Ok(end),
+    }
+}
+
+fn check_table(table: &'static str, offset: u64, len: u64, size: u64) -> Result<(), RelocError> {
+    match check_range(offset, len, size) {
+        Ok(_) => Ok(()),
+        Err(Outside::Overflow) => Err(RelocError::Overflow { what: table, offset: offset }),
+        Err(Outside::Beyond) => Err(RelocError::TableOutOfImage { table: table, offset: offset, size: len }),
+    }
+}
+
+impl Dynamic {
+    /// Reads the dynamic section at `dyn`, of an image of `size` bytes loaded at `base`.
+    pub unsafe fn parse(base: u64, size: u64, dyn: *const u8) -> Result<Dynamic, RelocError> {
+        let mut info = Dynamic {
+            base: base, size: size, rela: None, rela_size: 0, rela_ent: 0, jmprel: None, jmprel_size: 0,
+            symtab: None, strtab: None, strtab_size: 0, hash: None, gnu_hash: None,
+        };
+
+        let entry_size = mem::size_of::<Elf64_Dyn>() as u64;
+        let mut offset = (dyn as u64).wrapping_sub(base);
+        loop {
+            try!(check_table("dynamic section", offset, entry_size, size));
+            let entry = &*((base + offset) as usize as *const Elf64_Dyn);
+            let value = entry.d_val;
+            match entry.d_tag {
+                DT_NULL => break,
+                DT_RELA => info.rela = Some(value),
+                DT_RELASZ => info.rela_size = value,
+                DT_RELAENT => info.rela_ent = value,
+                DT_JMPREL => info.jmprel = Some(value),
+                DT_PLTRELSZ => info.jmprel_size = value,
+                DT_SYMTAB => info.symtab = Some(value),
+                DT_STRTAB => info.strtab = Some(value),
+                DT_STRSZ => info.strtab_size = value,
+                DT_HASH => info.hash = Some(value),
+                DT_GNU_HASH => info.gnu_hash = Some(value),
+                _ => {},
+            }
+            offset += entry_size;
+        }
+
+        if let Some(rela) = info.rela {
+            try!(check_table("DT_RELA", rela, info.rela_size, size));
+        }
+        if let Some(jmprel) = info.jmprel {
+            try!(check_table("DT_JMPREL", jmprel, info.jmprel_size, size));
+        }
+        if let Some(strtab) = info.strtab {
+            try!(check_table("DT_STRTAB", strtab, info.strtab_size, size));
+        }
+
+        Ok(info)
+    }
+
+    // `count` words at `offset` into the image, if they lie within it.
+    unsafe fn words(&self, offset: u64, count: usize) -> Option<&'static [u32]> {
+        match check_range(offset, count as u64 * 4, self.size) {
+            Ok(_) => Some(slice::from_raw_parts((self.base + offset) as usize as *const u32, count)),
+            Err(_) => None,
+        }
+    }
+
+    // Offsets of the buckets and chains of the GNU hash table, with its header.
+    unsafe fn gnu_layout(&self) -> Option<(&'static [u32], &'static [u32], u64)> {
+        let table = match self.gnu_hash {
+            Some(table) => table,
+            None => return None,
+        };
+        let header = match self.words(table, 4) {
+            Some(header) => header,
+            None => return None,
+        };
+        let (nbuckets, bloom_size) = (header[0] as u64, header[2] as u64);
+        let buckets = match self.words(table + 16 + bloom_size * 8, nbuckets as usize) {
+            Some(buckets) if nbuckets != 0 => buckets,
+            _ => return None,
+        };
+        Some((header, buckets, table + 16 + bloom_size * 8 + nbuckets * 4))
+    }
+
+    /// Number of entries in the symbol table, which the dynamic section only gives through the hash tables.
+    /// `None` if there is no usable hash table.
+    pub unsafe fn symbol_count(&self) -> Option<usize> {
+        if let Some(table) = self.hash {
+            // nbucket, nchain, where nchain is the number of symbols.
+            return self.words(table, 2).map(|header| header[1] as usize);
+        }
+
+        // Past the highest symbol in any bucket, walk its chain to the end.
+        let (header, buckets, chains) = match self.gnu_layout() {
+            Some(layout) => layout,
+            None => return None,
+        };
+        let symoffset = header[1] as usize;
+        let mut last = match buckets.iter().max() {
+            Some(&last) if last as usize >= symoffset => last as usize,
+            _ => return Some(symoffset),
+        };
+        loop {
+            match self.words(chains + (last - symoffset) as u64 * 4, 1) {
+                Some(chain) if chain[0] & 1 != 0 => return Some(last + 1),
+                Some(_) => last += 1,
+                None => return None,
+            }
+        }
+    }
+
+    /// Entry `index` of the symbol table, if it lies within the image.
+    pub unsafe fn symbol(&self, index: usize) -> Option<&'static Elf64_Sym> {
+        let symtab = match self.symtab {
+            Some(symtab) => symtab,
+            None => return None,
+        };
+        let entry_size = mem::size_of::<Elf64_Sym>() as u64;
+        let offset = match (index as u64).checked_mul(entry_size).and_then(|offset| offset.checked_add(symtab)) {
+            Some(offset) => offset,
+            None => return None,
+        };
+        match check_range(offset, entry_size, self.size) {
+            Ok(_) => Some(&*((self.base + offset) as usize as *const Elf64_Sym)),
+            Err(_) => None,
+        }
+    }
+
+    unsafe fn name_bytes(&self, symbol: &Elf64_Sym) -> &'static [u8] {
+        let strtab = match self.strtab {
+            Some(strtab) if (symbol.st_name as u64) < self.strtab_size => strtab,
+            _ => return &[],
+        };
+        let start = self.base + strtab + symbol.st_name as u64;
+        let bytes = slice::from_raw_parts(start as usize as *const u8, (self.strtab_size - symbol.st_name as u64) as usize);
+        match bytes.iter().position(|&c| c == 0) {
+            Some(len) => &bytes[..len],
+            None => bytes,
+        }
+    }
+
+    /// The name of the symbol. Points into the image.
+    pub unsafe fn symbol_name(&self, symbol: &Elf64_Sym) -> &'static str {
+        match str::from_utf8(self.name_bytes(symbol)) {
+            Ok(name) => name,
+            Err(_) => "<invalid name>",
+        }
+    }
+
+    /// Finds a symbol the image defines, through whichever hash table it has.
+    pub unsafe fn lookup(&self, name: &str) -> Option<&'static Elf64_Sym> {
+        let name = name.as_bytes();
+
+        let found = if self.gnu_hash.is_some() {
+            self.lookup_gnu(name)
+        } else {
+            self.lookup_sysv(name)
+        };
+
+        match found.and_then(|index| self.symbol(index)) {
+            Some(symbol) if symbol.is_defined() => Some(symbol),
+            _ => None,
+        }
+    }
+
+    unsafe fn matches(&self, index: usize, name: &[u8]) -> bool {
+        match self.symbol(index) {
+            Some(symbol) => self.name_bytes(symbol) == name,
+            None => false,
+        }
+    }
 
-pub unsafe fn relocate(ldbase: u64, dyn: *const u8) -> efi_app::Status {
+    unsafe fn lookup_sysv(&self, name: &[u8]) -> Option<usize> {
+        let table = match self.hash {
+            Some(table) => table,
+            None => return None,
+        };
+        let (nbucket, nchain) = match self.words(table, 2) {
+            Some(header) if header[0] != 0 => (header[0] as usize, header[1] as usize),
+            _ => return None,
+        };
+        let (buckets, chains) = match (self.words(table + 8, nbucket), self.words(table + 8 + nbucket as u64 * 4, nchain)) {
+            (Some(buckets), Some(chains)) => (buckets, chains),
+            _ => return None,
+        };
 
-    let dyn = dyn as *const Elf64_Dyn;
+        // A corrupt chain could loop forever, but never needs more steps than there are symbols.
+        let mut index = buckets[sysv_hash(name) as usize % nbucket] as usize;
+        for _ in 0..nchain {
+            if index == 0 || index >= nchain {
+                return None;
+            }
+            if self.matches(index, name) {
+                return Some(index);
+            }
+            index = chains[index] as usize;
+        }
+        None
+    }
+
+    unsafe fn lookup_gnu(&self, name: &[u8]) -> Option<usize> {
+        let (header, buckets, chains) = match self.gnu_layout() {
+            Some(layout) => layout,
+            None => return None,
+        };
+        let symoffset = header[1] as usize;
+
+        let hash = gnu_hash(name);
+        let mut index = buckets[hash as usize % buckets.len()] as usize;
+        if index < symoffset {
+            return None;
+        }
+
+        loop {
+            let chain_hash = match self.words(chains + (index - symoffset) as u64 * 4, 1) {
+                Some(chain) => chain[0],
+                None => return None,
+            };
+            if hash | 1 == chain_hash | 1 && self.matches(index, name) {
+                return Some(index);
+            }
+            if chain_hash & 1 != 0 {
+                return None;
+            }
+            index += 1;
+        }
+    }
+}
+
+struct Relocator<'a> {
+    dynamic: Dynamic,
+    resolver: &'a SymbolResolver,
+    tls: Option<TlsModule>,
+}
+
+impl<'a> Relocator<'a> {
+    unsafe fn symbol(&self, index: usize, symbol_index: usize) -> Result<&'static Elf64_Sym, RelocError> {
+        let in_table = match self.dynamic.symbol_count() {
+            Some(count) => symbol_index < count,
+            None => true,
+        };
+        match self.dynamic.symbol(symbol_index) {
+            Some(symbol) if in_table => Ok(symbol),
+            _ => Err(RelocError::BadSymbol { index: index, symbol: symbol_index }),
+        }
+    }
+
+    // Symbols the image defines bind to the image itself, like with `-Bsymbolic`.
+    unsafe fn symbol_value(&self, index: usize, symbol_index: usize) -> Result<u64, RelocError> {
+        if symbol_index == 0 {
+            return Ok(0);
+        }
+
+        let symbol = try!(self.symbol(index, symbol_index));
+        if symbol.is_defined() {
+            return Ok(self.dynamic.base.wrapping_add(symbol.st_value));
+        }
+
+        let name = self.dynamic.symbol_name(symbol);
+        match self.resolver.resolve(name) {
+            Some(value) => Ok(value),
+            None if symbol.is_weak() => Ok(0),
+            None => Err(RelocError::UndefinedSymbol { index: index, name: name }),
+        }
+    }
+
+    // Offset of the symbol in our own TLS block. TLS symbols of other modules are not supported.
+    unsafe fn tls_offset(&self, index: usize, symbol_index: usize) -> Result<(TlsModule, u64), RelocError> {
+        let tls = match self.tls {
+            Some(tls) => tls,
+            None => return Err(RelocError::NoTlsBlock { index: index }),
+        };
+
+        if symbol_index == 0 {
+            return Ok((tls, 0));
+        }
+
+        let symbol = try!(self.symbol(index, symbol_index));
+        if !symbol.is_defined() {
+            return Err(RelocError::UndefinedSymbol { index: index, name: self.dynamic.symbol_name(symbol) });
+        }
+        Ok((tls, symbol.st_value))
+    }
+
+    // Address of `len` bytes at `offset` into the image, for relocation `index`.
+    fn address(&self, index: usize, offset: u64, len: u64) -> Result<u64, RelocError> {
+        match check_range(offset, len, self.dynamic.size) {
+            Ok(_) => Ok(self.dynamic.base + offset),
+            Err(Outside::Overflow) => Err(RelocError::Overflow { what: "relocation", offset: offset }),
+            Err(Outside::Beyond) => Err(RelocError::OffsetOutOfImage { index: index, offset: offset }),
+        }
+    }
+
+    unsafe fn apply(&self, index: usize, rela: &Elf64_Rela) -> Result<(), RelocError> {
+        let kind = rela.r_info as u32;
+        let symbol_index = (rela.r_info >> 32) as usize;
+        let base = self.dynamic.base;
+        let addend = rela.r_addend;
+
+        if kind == R_X86_64_NONE {
+            return Ok(());
+        }
+        let target = try!(self.address(index, rela.r_offset, mem::size_of::<u64>() as u64));
+
+        let value = match kind {
+            R_X86_64_RELATIVE => base.wrapping_add(addend),
+            R_X86_64_64 => try!(self.symbol_value(index, symbol_index)).wrapping_add(addend),
+            R_X86_64_GLOB_DAT | R_X86_64_JUMP_SLOT => try!(self.symbol_value(index, symbol_index)),
+            R_X86_64_DTPMOD64 => try!(self.tls_offset(index, symbol_index)).0.id,
+            R_X86_64_DTPOFF64 => try!(self.tls_offset(index, symbol_index)).1.wrapping_add(addend),
+            R_X86_64_TPOFF64 => {
+                // The static TLS block lies below the thread pointer.
+                let (tls, offset) = try!(self.tls_offset(index, symbol_index));
+                offset.wrapping_add(addend).wrapping_sub(tls.offset)
+            },
+            R_X86_64_IRELATIVE => {
+                let function = try!(self.address(index, addend, 1));
+                let resolver: extern "C" fn() -> u64 = mem::transmute(function as usize);
+                resolver()
+            },
+            _ => return Err(RelocError::UnsupportedType { index: index, kind: kind }),
+        };
+
+        *(target as usize as *mut u64) = value;
+        Ok(())
+    }
+
+    // Applies a table of relocations. `first` is the index of the first one, counting across tables.
+    unsafe fn apply_table(&self, table: u64, size: u64, first: usize) -> Result<usize, RelocError> {
+        let count = (size / mem::size_of::<Elf64_Rela>() as u64) as usize;
+        let relas = slice::from_raw_parts((self.dynamic.base + table) as usize as *const Elf64_Rela, count);
+
+        for (i, rela) in relas.iter().enumerate() {
+            try!(self.apply(first + i, rela));
+        }
+        Ok(first + count)
+    }
+}
+
+/// Applies the relocations of a self-contained image of `size` bytes, loaded at `ldbase`,
+/// with its dynamic section at `dyn`.
+pub unsafe fn relocate(ldbase: u64, size: u64, dyn: *const u8) -> Result<(), RelocError> {
+    relocate_with(ldbase, size, dyn, &NoSymbols, None)
+}
 
-    let mut relsz: isize = 0;
-    let mut relent: isize = 0;
-    let mut rel: *const u8 = ptr::null();
-    let mut dyn = dyn;
+/// Applies all relocations in DT_RELA and DT_JMPREL.
+/// Symbols the image doesn't define are looked up through `resolver`.
+/// Nothing outside of the `size` bytes at `ldbase` is read or written.
+pub unsafe fn relocate_with(ldbase: u64, size: u64, dyn: *const u8, resolver: &SymbolResolver, tls: Option<TlsModule>)
+                            -> Result<(), RelocError> {
+    let dynamic = try!(Dynamic::parse(ldbase, size, dyn));
+
+    let rela = match dynamic.rela {
+        Some(_) if dynamic.rela_ent != mem::size_of::<Elf64_Rela>() as u64 => return Err(RelocError::BadRelaEnt(dynamic.rela_ent)),
+        Some(rela) => Some((rela, dynamic.rela_size)),
+        None if dynamic.rela_size != 0 || dynamic.rela_ent != 0 => return Err(RelocError::MissingRela),
+        None => None,
+    };
+    let jmprel = dynamic.jmprel.map(|jmprel| (jmprel, dynamic.jmprel_size));
+
+    let relocator = Relocator { dynamic: dynamic, resolver: resolver, tls: tls };
+    let next = match rela {
+        Some((table, size)) => try!(relocator.apply_table(table, size, 0)),
+        None => 0,
+    };
+    if let Some((table, size)) = jmprel {
+        try!(relocator.apply_table(table, size, next));
+    }
+
+    Ok(())
+}
 
-    while (*dyn).d_tag != DT_NULL {
-        match (*dyn).d_tag {
-            DT_RELA => { rel = ((*dyn).d_val + ldbase) as usize as *const u8; },
-            DT_RELASZ => { relsz = (*dyn).d_val as isize; },
-            DT_RELAENT => { relent = (*dyn).d_val as isize; },
-            _ => {},
+#[cfg(test)]
+#[repr(C, align(16))]
+pub struct TestImage(pub [u64; 64]);
+
+// Symbols of the test images, after the null symbol: name, st_info, st_shndx and st_value.
+#[cfg(test)]
+const TEST_SYMBOLS: [(&'static str, u8, u16, u64); 4] = [
+    ("ext", 0x12, SHN_UNDEF, 0),
+    ("weak", 0x22, SHN_UNDEF, 0),
+    ("local", 0x12, 1, 0x1f0),
+    ("tls", 0x16, 1, 0x10),
+];
+
+// A dynamic section at the start, the hash table of kind `hash` at 0x80, the symbol table at 0xa8,
+// up to five `relas` at 0x120 and the string table at 0x1a0, with room for targets behind it.
+#[cfg(test)]
+fn test_image_hashed(relas: &[(u64, u64, u64)], hash: u64) -> TestImage {
+    assert!(relas.len() <= 5);
+    let mut image = TestImage([0; 64]);
+    let dynamic = [
+        DT_RELA, 0x120, DT_RELASZ, relas.len() as u64 * 24, DT_RELAENT, 24,
+        DT_SYMTAB, 0xa8, DT_STRTAB, 0x1a0, DT_STRSZ, 0x20, hash, 0x80, DT_NULL, 0,
+    ];
+    image.0[..dynamic.len()].copy_from_slice(&dynamic);
+    for (i, &(offset, info, addend)) in relas.iter().enumerate() {
+        image.0[0x120 / 8 + i * 3..0x120 / 8 + i * 3 + 3].copy_from_slice(&[offset, info, addend]);
+    }
+
+    // All symbols in one bucket. The GNU table only hashes the defined ones, from `symoffset` 3 on.
+    let table = if hash == DT_GNU_HASH {
+        [1, 3, 1, 0, !0, !0, 3, gnu_hash(b"local") & !1, gnu_hash(b"tls") | 1]
+    } else {
+        [1, 5, 4, 0, 0, 1, 2, 3, 0]
+    };
+
+    unsafe {
+        let bytes = image.0.as_mut_ptr() as *mut u8;
+        ::core::ptr::copy_nonoverlapping(table.as_ptr(), bytes.offset(0x80) as *mut u32, table.len());
+
+        let symbols = bytes.offset(0xa8) as *mut Elf64_Sym;
+        let mut name = 1;
+        for (i, &(text, info, shndx, value)) in TEST_SYMBOLS.iter().enumerate() {
+            *symbols.offset(i as isize + 1) = Elf64_Sym {
+                st_name: name as u32, st_info: info, st_other: 0, st_shndx: shndx, st_value: value, st_size: 0,
+            };
+            ::core::ptr::copy_nonoverlapping(text.as_ptr(), bytes.offset(0x1a0 + name), text.len());
+            name += text.len() as isize + 1;
         }
-        dyn = dyn.offset(1);
     }
+    image
+}
+
+#[cfg(test)]
+pub fn test_image(relas: &[(u64, u64, u64)]) -> TestImage {
+    test_image_hashed(relas, DT_HASH)
+}
 
-    if rel.is_null() && relent == 0 {
-        return efi_app::Status::success();
+// Knows only "ext".
+#[cfg(test)]
+struct TestResolver;
+
+#[cfg(test)]
+impl SymbolResolver for TestResolver {
+    fn resolve(&self, name: &str) -> Option<u64> {
+        if name == "ext" { Some(0x1000) } else { None }
     }
+}
 
-    if rel.is_null() || relent == 0 {
-        return efi_app::Status::load_error();
+#[test]
+fn test_relocate_relative() {
+    let mut image = test_image(&[(0x1f0, R_X86_64_RELATIVE as u64, 0x40), (0x1f8, R_X86_64_NONE as u64, 0)]);
+    let base = image.0.as_mut_ptr() as u64;
+    unsafe { relocate(base, 0x200, base as *const u8).unwrap(); }
+    assert_eq!(image.0[0x1f0 / 8], base + 0x40);
+}
+
+#[test]
+fn test_relocate_checks_bounds() {
+    let mut image = test_image(&[(0x1f0, R_X86_64_RELATIVE as u64, 0), (0x1fc, R_X86_64_RELATIVE as u64, 0)]);
+    let base = image.0.as_mut_ptr() as u64;
+    match unsafe { relocate(base, 0x200, base as *const u8) } {
+        Err(RelocError::OffsetOutOfImage { index: 1, offset: 0x1fc }) => {},
+        other => panic!("Unexpected result {:?}", other),
     }
 
-    // Apply the relocs.
+    let mut image = test_image(&[(!0, R_X86_64_RELATIVE as u64, 0)]);
+    let base = image.0.as_mut_ptr() as u64;
+    match unsafe { relocate(base, 0x200, base as *const u8) } {
+        Err(RelocError::Overflow { .. }) => {},
+        other => panic!("Unexpected result {:?}", other),
+    }
 
-	while relsz > 0 {
-	    let rela = &*(rel as *const Elf64_Rela);
+    // The table itself doesn't fit.
+    match unsafe { relocate(base, 0x110, base as *const u8) } {
+        Err(RelocError::TableOutOfImage { table: "DT_RELA", .. }) => {},
+        other => panic!("Unexpected result {:?}", other),
+    }
+}
 
-		if rela.r_info == R_X86_64_RELATIVE {
-			let addr = rela.r_offset.wrapping_add(ldbase) as usize as *mut u64;
-			*addr = rela.r_addend.wrapping_add(ldbase);
-		}
+#[test]
+fn test_relocate_unsupported_type() {
+    let mut image = test_image(&[(0x1f0, R_X86_64_RELATIVE as u64, 0), (0x1f8, 2, 0)]);
+    let base = image.0.as_mut_ptr() as u64;
+    match unsafe { relocate(base, 0x200, base as *const u8) } {
+        Err(RelocError::UnsupportedType { index: 1, kind: 2 }) => {},
+        other => panic!("Unexpected result {:?}", other),
+    }
+}
 
-		rel = rel.offset(relent);
-		relsz -= relent;
-	}
+#[test]
+fn test_symbol_lookup() {
+    for &hash in &[DT_HASH, DT_GNU_HASH] {
+        let image = test_image_hashed(&[], hash);
+        let base = image.0.as_ptr() as u64;
+        unsafe {
+            let dynamic = Dynamic::parse(base, 0x200, base as *const u8).unwrap();
+            assert_eq!(dynamic.symbol_count(), Some(5));
+            assert_eq!(dynamic.lookup("local").map(|symbol| symbol.st_value), Some(0x1f0));
+            assert_eq!(dynamic.lookup("tls").map(|symbol| symbol.st_value), Some(0x10));
+            // Only defined symbols are found.
+            assert!(dynamic.lookup("ext").is_none());
+            assert!(dynamic.lookup("missing").is_none());
+        }
+    }
+}
 
-    efi_app::Status::success()
+#[test]
+fn test_relocate_symbols() {
+    let tls = TlsModule { id: 7, offset: 0x40 };
+    for &hash in &[DT_HASH, DT_GNU_HASH] {
+        let mut image = test_image_hashed(&[
+            (0x1c0, R_X86_64_64 as u64 | 1 << 32, 8),
+            (0x1c8, R_X86_64_GLOB_DAT as u64 | 2 << 32, 0),
+            (0x1d0, R_X86_64_JUMP_SLOT as u64 | 3 << 32, 0),
+            (0x1d8, R_X86_64_TPOFF64 as u64 | 4 << 32, 4),
+            (0x1e0, R_X86_64_DTPOFF64 as u64 | 4 << 32, 4),
+        ], hash);
+        let base = image.0.as_mut_ptr() as u64;
+        unsafe { relocate_with(base, 0x200, base as *const u8, &TestResolver, Some(tls)).unwrap(); }
+        assert_eq!(image.0[0x1c0 / 8], 0x1008);
+        // Weak and undefined.
+        assert_eq!(image.0[0x1c8 / 8], 0);
+        assert_eq!(image.0[0x1d0 / 8], base + 0x1f0);
+        assert_eq!(image.0[0x1d8 / 8], 0x14u64.wrapping_sub(0x40));
+        assert_eq!(image.0[0x1e0 / 8], 0x14);
+    }
 }
 
+#[test]
+fn test_relocate_symbol_errors() {
+    let mut image = test_image(&[(0x1c0, R_X86_64_DTPMOD64 as u64 | 4 << 32, 0)]);
+    let base = image.0.as_mut_ptr() as u64;
+    unsafe { relocate_with(base, 0x200, base as *const u8, &NoSymbols, Some(TlsModule { id: 7, offset: 0x40 })).unwrap(); }
+    assert_eq!(image.0[0x1c0 / 8], 7);
+    match unsafe { relocate_with(base, 0x200, base as *const u8, &NoSymbols, None) } {
+        Err(RelocError::NoTlsBlock { index: 0 }) => {},
+        other => panic!("Unexpected result {:?}", other),
+    }
+
+    let mut image = test_image(&[(0x1c0, R_X86_64_GLOB_DAT as u64 | 2 << 32, 0), (0x1c8, R_X86_64_64 as u64 | 1 << 32, 0)]);
+    let base = image.0.as_mut_ptr() as u64;
+    match unsafe { relocate(base, 0x200, base as *const u8) } {
+        Err(RelocError::UndefinedSymbol { index: 1, name: "ext" }) => {},
+        other => panic!("Unexpected result {:?}", other),
+    }
+
+    let mut image = test_image(&[(0x1c0, R_X86_64_64 as u64 | 5 << 32, 0)]);
+    let base = image.0.as_mut_ptr() as u64;
+    match unsafe { relocate(base, 0x200, base as *const u8) } {
+        Err(RelocError::BadSymbol { index: 0, symbol: 5 }) => {},
+        other => panic!("Unexpected result {:?}", other),
+    }
+}
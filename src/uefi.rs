@@ -0,0 +1,388 @@
+//! Raw UEFI boot services definitions that are not covered by `efi_app`.
+//! Layouts follow the UEFI 2.6 specification.
+
+use core::cmp;
+use core::mem;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+pub type Status = usize;
+pub type Handle = *mut u8;
+
+pub const SUCCESS: Status = 0;
+const ERROR_BIT: Status = 1 << 63;
+pub const INVALID_PARAMETER: Status = ERROR_BIT | 2;
+pub const LOAD_ERROR: Status = ERROR_BIT | 1;
+pub const BUFFER_TOO_SMALL: Status = ERROR_BIT | 5;
+pub const OUT_OF_RESOURCES: Status = ERROR_BIT | 9;
+pub const NOT_FOUND: Status = ERROR_BIT | 14;
+
+pub const PAGE_SIZE: usize = 4096;
+
+#[repr(C)]
+pub struct TableHeader {
+    pub signature: u64,
+    pub revision: u32,
+    pub header_size: u32,
+    pub crc32: u32,
+    pub reserved: u32,
+}
+
+#[repr(C)]
+pub struct SystemTable {
+    pub hdr: TableHeader,
+    pub firmware_vendor: *const u16,
+    pub firmware_revision: u32,
+    pub console_in_handle: Handle,
+    pub con_in: *mut u8,
+    pub console_out_handle: Handle,
+    pub con_out: *mut u8,
+    pub standard_error_handle: Handle,
+    pub std_err: *mut u8,
+    pub runtime_services: *mut u8,
+    pub boot_services: *mut BootServices,
+    pub number_of_table_entries: usize,
+    pub configuration_table: *mut u8,
+}
+
+#[repr(C)]
+pub struct BootServices {
+    pub hdr: TableHeader,
+
+    raise_tpl: usize,
+    restore_tpl: usize,
+
+    pub allocate_pages: extern "win64" fn(alloc_type: u32, memory_type: u32, pages: usize, memory: *mut u64) -> Status,
+    pub free_pages: extern "win64" fn(memory: u64, pages: usize) -> Status,
+    pub get_memory_map: extern "win64" fn(memory_map_size: *mut usize, memory_map: *mut u8, map_key: *mut usize,
+                                          descriptor_size: *mut usize, descriptor_version: *mut u32) -> Status,
+    pub allocate_pool: extern "win64" fn(pool_type: u32, size: usize, buffer: *mut *mut u8) -> Status,
+    pub free_pool: extern "win64" fn(buffer: *mut u8) -> Status,
+
+    // Event & timer services, the first protocol handler services.
+    _unused_1: [usize; 9],
+
+    pub handle_protocol: extern "win64" fn(handle: Handle, protocol: *const Guid, interface: *mut *mut u8) -> Status,
+
+    // The other protocol handler services, image services.
+    _unused_2: [usize; 9],
+
+    pub exit_boot_services: extern "win64" fn(image_handle: Handle, map_key: usize) -> Status,
+}
+
+#[repr(C)]
+pub struct Guid {
+    pub data1: u32,
+    pub data2: u16,
+    pub data3: u16,
+    pub data4: [u8; 8],
+}
+
+pub const LOADED_IMAGE_PROTOCOL: Guid =
+    Guid { data1: 0x5b1b_31a1, data2: 0x9562, data3: 0x11d2, data4: [0x8e, 0x3f, 0x00, 0xa0, 0xc9, 0x69, 0x72, 0x3b] };
+pub const SIMPLE_FILE_SYSTEM_PROTOCOL: Guid =
+    Guid { data1: 0x964e_5b22, data2: 0x6459, data3: 0x11d2, data4: [0x8e, 0x39, 0x00, 0xa0, 0xc9, 0x69, 0x72, 0x3b] };
+
+/// The start of `EFI_LOADED_IMAGE_PROTOCOL`, up to what we use.
+#[repr(C)]
+pub struct LoadedImageProtocol {
+    pub revision: u32,
+    pub parent_handle: Handle,
+    pub system_table: *const SystemTable,
+    pub device_handle: Handle,
+}
+
+#[repr(C)]
+pub struct SimpleFileSystemProtocol {
+    pub revision: u64,
+    pub open_volume: extern "win64" fn(this: *mut SimpleFileSystemProtocol, root: *mut *mut FileProtocol) -> Status,
+}
+
+/// The start of `EFI_FILE_PROTOCOL`, up to what we use.
+#[repr(C)]
+pub struct FileProtocol {
+    pub revision: u64,
+    pub open: extern "win64" fn(this: *mut FileProtocol, new_handle: *mut *mut FileProtocol, file_name: *const u16,
+                                open_mode: u64, attributes: u64) -> Status,
+    pub close: extern "win64" fn(this: *mut FileProtocol) -> Status,
+    delete: usize,
+    pub read: extern "win64" fn(this: *mut FileProtocol, buffer_size: *mut usize, buffer: *mut u8) -> Status,
+    write: usize,
+    pub get_position: extern "win64" fn(this: *mut FileProtocol, position: *mut u64) -> Status,
+    pub set_position: extern "win64" fn(this: *mut FileProtocol, position: u64) -> Status,
+}
+
+pub const FILE_MODE_READ: u64 = 1;
+
+// Passed to `SetPosition()`, moves to the end of the file.
+const POSITION_END_OF_FILE: u64 = !0;
+
+// Longest path `read_file()` takes, in UTF-16 code units.
+const MAX_PATH: usize = 256;
+
+// EFI_ALLOCATE_TYPE
+pub const ALLOCATE_ANY_PAGES: u32 = 0;
+pub const ALLOCATE_MAX_ADDRESS: u32 = 1;
+
+// EFI_MEMORY_TYPE
+pub const RESERVED_MEMORY_TYPE: u32 = 0;
+pub const LOADER_CODE: u32 = 1;
+pub const LOADER_DATA: u32 = 2;
+pub const BOOT_SERVICES_CODE: u32 = 3;
+pub const BOOT_SERVICES_DATA: u32 = 4;
+pub const RUNTIME_SERVICES_CODE: u32 = 5;
+pub const RUNTIME_SERVICES_DATA: u32 = 6;
+pub const CONVENTIONAL_MEMORY: u32 = 7;
+pub const UNUSABLE_MEMORY: u32 = 8;
+pub const ACPI_RECLAIM_MEMORY: u32 = 9;
+pub const ACPI_MEMORY_NVS: u32 = 10;
+pub const MEMORY_MAPPED_IO: u32 = 11;
+pub const MEMORY_MAPPED_IO_PORT_SPACE: u32 = 12;
+pub const PAL_CODE: u32 = 13;
+pub const PERSISTENT_MEMORY: u32 = 14;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct MemoryDescriptor {
+    pub memory_type: u32,
+    pub physical_start: u64,
+    pub virtual_start: u64,
+    pub number_of_pages: u64,
+    pub attribute: u64,
+}
+
+impl MemoryDescriptor {
+    #[inline]
+    pub fn physical_end(&self) -> u64 {
+        self.physical_start + self.number_of_pages * PAGE_SIZE as u64
+    }
+
+    /// Memory that becomes ours to use once boot services are gone.
+    #[inline]
+    pub fn is_usable(&self) -> bool {
+        match self.memory_type {
+            CONVENTIONAL_MEMORY | BOOT_SERVICES_CODE | BOOT_SERVICES_DATA => true,
+            _ => false,
+        }
+    }
+
+    /// RAM, as opposed to MMIO, reserved or unusable ranges. Only RAM may be mapped write-back.
+    #[inline]
+    pub fn is_ram(&self) -> bool {
+        match self.memory_type {
+            LOADER_CODE | LOADER_DATA | BOOT_SERVICES_CODE | BOOT_SERVICES_DATA | RUNTIME_SERVICES_CODE
+                | RUNTIME_SERVICES_DATA | CONVENTIONAL_MEMORY | ACPI_RECLAIM_MEMORY | ACPI_MEMORY_NVS => true,
+            _ => false,
+        }
+    }
+}
+
+// The system table, for as long as boot services are available.
+static BOOT_SYSTEM_TABLE: AtomicUsize = AtomicUsize::new(0);
+
+pub fn set_boot_system_table(system_table: *const SystemTable) {
+    BOOT_SYSTEM_TABLE.store(system_table as usize, Ordering::SeqCst);
+}
+
+/// Returns the system table, or `None` once boot services were terminated.
+pub fn boot_system_table() -> Option<*const SystemTable> {
+    match BOOT_SYSTEM_TABLE.load(Ordering::SeqCst) {
+        0 => None,
+        st => Some(st as *const SystemTable),
+    }
+}
+
+#[inline]
+pub unsafe fn boot_services<'a>(system_table: *const SystemTable) -> &'a BootServices {
+    &*(*system_table).boot_services
+}
+
+/// Allocates `pages` pages of `LOADER_DATA` memory. Returns the physical address.
+pub unsafe fn allocate_pages(system_table: *const SystemTable, pages: usize) -> Result<u64, Status> {
+    let mut address: u64 = 0;
+    let status = (boot_services(system_table).allocate_pages)(ALLOCATE_ANY_PAGES, LOADER_DATA, pages, &mut address);
+    if status == SUCCESS { Ok(address) } else { Err(status) }
+}
+
+/// The raw memory map, as returned by `GetMemoryMap()`.
+/// Descriptors are `descriptor_size` bytes apart, which needn't match `size_of::<MemoryDescriptor>()`.
+pub struct RawMemoryMap {
+    pub buffer: *mut u8,
+    pub capacity: usize,
+    pub size: usize,
+    pub key: usize,
+    pub descriptor_size: usize,
+}
+
+impl RawMemoryMap {
+    pub const fn empty() -> Self {
+        RawMemoryMap { buffer: 0 as *mut u8, capacity: 0, size: 0, key: 0, descriptor_size: 0 }
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        if self.descriptor_size == 0 { 0 } else { self.size / self.descriptor_size }
+    }
+
+    #[inline]
+    pub fn get(&self, index: usize) -> MemoryDescriptor {
+        assert!(index < self.len());
+        unsafe { ptr::read_unaligned(self.buffer.offset((index * self.descriptor_size) as isize) as *const MemoryDescriptor) }
+    }
+}
+
+/// Fetches the current memory map into `map`, allocating (or growing) its buffer when needed.
+/// Allocating the buffer itself changes the memory map, so the buffer is always made
+/// a few descriptors larger than strictly required.
+pub unsafe fn get_memory_map(system_table: *const SystemTable, map: &mut RawMemoryMap) -> Result<(), Status> {
+    let bs = boot_services(system_table);
+
+    loop {
+        let mut size = map.capacity;
+        let mut version: u32 = 0;
+        let status = (bs.get_memory_map)(&mut size, map.buffer, &mut map.key, &mut map.descriptor_size, &mut version);
+
+        match status {
+            SUCCESS => {
+                map.size = size;
+                return Ok(());
+            },
+            BUFFER_TOO_SMALL => {
+                if !map.buffer.is_null() {
+                    let status = (bs.free_pages)(map.buffer as u64, map.capacity / PAGE_SIZE);
+                    if status != SUCCESS {
+                        return Err(status);
+                    }
+                }
+
+                let slack = 8 * cmp::max(map.descriptor_size, mem::size_of::<MemoryDescriptor>());
+                let pages = (size + slack + PAGE_SIZE - 1) / PAGE_SIZE;
+                map.buffer = try!(allocate_pages(system_table, pages)) as usize as *mut u8;
+                map.capacity = pages * PAGE_SIZE;
+            },
+            _ => return Err(status),
+        }
+    }
+}
+
+/// Terminates boot services. On success, the map in `map` is the final memory map.
+/// The firmware is allowed to change the memory map on the first attempt,
+/// in which case the map is fetched again and the call repeated.
+pub unsafe fn exit_boot_services(system_table: *const SystemTable, image_handle: Handle, map: &mut RawMemoryMap) -> Result<(), Status> {
+    try!(get_memory_map(system_table, map));
+
+    let status = (boot_services(system_table).exit_boot_services)(image_handle, map.key);
+    if status == SUCCESS {
+        set_boot_system_table(ptr::null());
+        return Ok(());
+    }
+
+    if status != INVALID_PARAMETER {
+        return Err(status);
+    }
+
+    // Even if the retry fails, boot services are in an unknown state.
+    set_boot_system_table(ptr::null());
+
+    // After a failed ExitBootServices(), only GetMemoryMap() may be used, so the buffer must already be large enough.
+    let mut size = map.capacity;
+    let mut version: u32 = 0;
+    let status = (boot_services(system_table).get_memory_map)(&mut size, map.buffer, &mut map.key, &mut map.descriptor_size, &mut version);
+    if status != SUCCESS {
+        return Err(status);
+    }
+    map.size = size;
+
+    let status = (boot_services(system_table).exit_boot_services)(image_handle, map.key);
+    if status == SUCCESS { Ok(()) } else { Err(status) }
+}
+
+/// Opens the root directory of the volume the image was loaded from.
+pub unsafe fn open_boot_volume(system_table: *const SystemTable, image_handle: Handle) -> Result<*mut FileProtocol, Status> {
+    let bs = boot_services(system_table);
+
+    let mut image: *mut u8 = ptr::null_mut();
+    let status = (bs.handle_protocol)(image_handle, &LOADED_IMAGE_PROTOCOL, &mut image);
+    if status != SUCCESS {
+        return Err(status);
+    }
+
+    let device = (*(image as *const LoadedImageProtocol)).device_handle;
+    let mut file_system: *mut u8 = ptr::null_mut();
+    let status = (bs.handle_protocol)(device, &SIMPLE_FILE_SYSTEM_PROTOCOL, &mut file_system);
+    if status != SUCCESS {
+        return Err(status);
+    }
+
+    let file_system = file_system as *mut SimpleFileSystemProtocol;
+    let mut root: *mut FileProtocol = ptr::null_mut();
+    let status = ((*file_system).open_volume)(file_system, &mut root);
+    if status == SUCCESS { Ok(root) } else { Err(status) }
+}
+
+/// Opens the file or directory at `path`, relative to `directory` unless it starts with a backslash.
+/// Forward slashes are taken as backslashes.
+pub unsafe fn open_file(directory: *mut FileProtocol, path: &str) -> Result<*mut FileProtocol, Status> {
+    // UCS-2, with a terminating zero.
+    let mut name = [0u16; MAX_PATH];
+    let mut length = 0;
+    for c in path.chars() {
+        if length == MAX_PATH - 1 || c as u32 > 0xffff {
+            return Err(INVALID_PARAMETER);
+        }
+        name[length] = if c == '/' { '\\' as u16 } else { c as u16 };
+        length += 1;
+    }
+
+    let mut file: *mut FileProtocol = ptr::null_mut();
+    let status = ((*directory).open)(directory, &mut file, name.as_ptr(), FILE_MODE_READ, 0);
+    if status == SUCCESS { Ok(file) } else { Err(status) }
+}
+
+#[inline]
+pub unsafe fn close_file(file: *mut FileProtocol) {
+    ((*file).close)(file);
+}
+
+/// Reads the file at `path`, relative to `directory`, into freshly allocated `LOADER_DATA` pages,
+/// which stay allocated after boot services are gone. Returns their physical address and the size of the file.
+pub unsafe fn read_file(system_table: *const SystemTable, directory: *mut FileProtocol, path: &str) -> Result<(u64, usize), Status> {
+    let file = try!(open_file(directory, path));
+    let result = read_whole(system_table, file);
+    close_file(file);
+    result
+}
+
+unsafe fn read_whole(system_table: *const SystemTable, file: *mut FileProtocol) -> Result<(u64, usize), Status> {
+    let mut size: u64 = 0;
+    let status = ((*file).set_position)(file, POSITION_END_OF_FILE);
+    if status != SUCCESS {
+        return Err(status);
+    }
+    let status = ((*file).get_position)(file, &mut size);
+    if status != SUCCESS {
+        return Err(status);
+    }
+    let status = ((*file).set_position)(file, 0);
+    if status != SUCCESS {
+        return Err(status);
+    }
+
+    let size = size as usize;
+    let pages = cmp::max(1, (size + PAGE_SIZE - 1) / PAGE_SIZE);
+    let buffer = try!(allocate_pages(system_table, pages));
+
+    // Boot services memory is identity mapped. A read may return less than asked for.
+    let mut done = 0;
+    while done < size {
+        let mut chunk = size - done;
+        let status = ((*file).read)(file, &mut chunk, (buffer as usize + done) as *mut u8);
+        if status != SUCCESS || chunk == 0 {
+            (boot_services(system_table).free_pages)(buffer, pages);
+            return Err(if status != SUCCESS { status } else { LOAD_ERROR });
+        }
+        done += chunk;
+    }
+
+    Ok((buffer, size))
+}